@@ -0,0 +1,195 @@
+use std::{cmp::Ordering, collections::{BTreeMap, HashMap}};
+
+use mini_json::{Path, Value};
+use sockets::response::Response;
+
+use crate::{index::IndexKey, parse_target, storage::Entry};
+
+#[derive(Debug, PartialEq)]
+enum Operation {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Distinct
+}
+
+impl Operation {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "count" => Ok(Operation::Count),
+            "sum" => Ok(Operation::Sum),
+            "avg" => Ok(Operation::Avg),
+            "min" => Ok(Operation::Min),
+            "max" => Ok(Operation::Max),
+            "distinct" => Ok(Operation::Distinct),
+            _ => Err(format!("Unknown aggregation {name}"))
+        }
+    }
+}
+
+/// `op [field] [GROUP BY field]` part of the `AGG` command.
+#[derive(Debug, PartialEq)]
+struct Aggregation {
+    operation: Operation,
+    field: Option<Path>,
+    group_by: Option<Path>
+}
+
+impl Aggregation {
+    fn parse(args: &str) -> Result<Self, String> {
+        let (operation, mut rest) = args.trim().split_once(char::is_whitespace).unwrap_or((args.trim(), ""));
+        let operation = Operation::parse(operation)?;
+        let mut field = None;
+        if !rest.trim().is_empty() && group_by_clause(rest).is_none() {
+            let (path, remaining) = Path::parse_prefix(rest.trim_start())?;
+            field = Some(path);
+            rest = remaining;
+        }
+        let group_by = match rest.trim() {
+            "" => None,
+            clause => match group_by_clause(clause) {
+                Some(path) => Some(Path::parse(path.trim_start())?),
+                None => return Err("Invalid arguments".to_string())
+            }
+        };
+        Ok(Self { operation, field, group_by })
+    }
+
+    fn run(&self, items: &[Value]) -> Value {
+        let Some(group_by) = &self.group_by else {
+            return self.aggregate(items.iter())
+        };
+        // buckets are ordered by the grouping value, same as index entries, and keep its type, so `1` and `"1"` differ
+        let mut buckets: BTreeMap<IndexKey, (&Value, Vec<&Value>)> = BTreeMap::new();
+        for item in items {
+            let Some((bucket, group)) = item.get_element(group_by).ok().and_then(|group| Some((IndexKey::from_value(group)?, group))) else {
+                continue
+            };
+            buckets.entry(bucket).or_insert_with(|| (group, Vec::new())).1.push(item);
+        }
+        Value::Array(buckets.into_values().map(|(group, items)| {
+            Value::Array(vec![group.clone(), self.aggregate(items.into_iter())])
+        }).collect())
+    }
+
+    /// Aggregates `field` of every item, items without it are left out.
+    fn aggregate<'a>(&self, items: impl Iterator<Item = &'a Value>) -> Value {
+        let values: Vec<&Value> = match &self.field {
+            Some(field) => items.filter_map(|item| item.get_element(field).ok()).collect(),
+            None => items.collect()
+        };
+        let numbers: Vec<f64> = values.iter().filter_map(|value| number(value)).collect();
+        let extreme = |ordering: Ordering| values.iter()
+            .filter_map(|value| Some((number(value)?, *value)))
+            .reduce(|a, b| if b.0.total_cmp(&a.0) == ordering { b } else { a })
+            .map_or(Value::Null, |(_, value)| value.clone());
+        match self.operation {
+            Operation::Count => Value::Integer(values.len() as isize),
+            Operation::Sum => sum(&values),
+            Operation::Avg => match numbers.len() {
+                0 => Value::Null,
+                count => Value::Float(numbers.iter().sum::<f64>() / count as f64)
+            },
+            Operation::Min => extreme(Ordering::Less),
+            Operation::Max => extreme(Ordering::Greater),
+            Operation::Distinct => {
+                let mut distinct = BTreeMap::new();
+                for value in &values {
+                    if let Some(key) = IndexKey::from_value(value) {
+                        distinct.entry(key).or_insert_with(|| (*value).clone());
+                    }
+                }
+                Value::Array(distinct.into_values().collect())
+            }
+        }
+    }
+}
+
+/// Path following `GROUP BY`, if `args` start with it.
+fn group_by_clause(args: &str) -> Option<&str> {
+    let (group, rest) = args.trim_start().split_once(char::is_whitespace)?;
+    let (by, path) = rest.trim_start().split_once(char::is_whitespace)?;
+    (group.eq_ignore_ascii_case("group") && by.eq_ignore_ascii_case("by")).then_some(path)
+}
+
+fn number(value: &Value) -> Option<f64> {
+    value.integer().map(|i| i as f64).or_else(|_| value.float()).ok()
+}
+
+/// Sum of numeric values stays an integer unless some of them is a float or the sum doesn't fit.
+fn sum(values: &[&Value]) -> Value {
+    let mut integers = values.iter().filter(|value| number(value).is_some());
+    match integers.try_fold(0isize, |sum, value| sum.checked_add(value.integer().ok()?)) {
+        Some(sum) => Value::Integer(sum),
+        None => Value::Float(values.iter().filter_map(|value| number(value)).sum())
+    }
+}
+
+/// `AGG key.path op [field] [GROUP BY field]`, aggregates array at the path, or `field` of objects inside it.
+/// Operations are `COUNT`, `SUM`, `AVG`, `MIN`, `MAX` and `DISTINCT`, numeric ones skip values which aren't numbers.
+/// `GROUP BY` returns `[group, aggregate]` pair of every group, ordered by the group value.
+pub fn agg_cmd(args: &str, storage: &HashMap<String, Entry>) -> Response {
+    let (key, path, rest) = match parse_target(args) {
+        Ok(target) => target,
+        Err(response) => return response
+    };
+    let aggregation = match Aggregation::parse(rest) {
+        Ok(aggregation) => aggregation,
+        Err(e) => return Response::builder().set_body(e)
+    };
+    let document = match storage.get(&key) {
+        Some(Entry::Value(value)) => value,
+        Some(entry) => return Response::builder().set_body(format!("Invalid type: expected Value, got {}", entry.typename())),
+        None => return Response::builder().set_body("Key not found")
+    };
+    match document.get_element(&path) {
+        Ok(Value::Array(items)) => Response::builder().set_body(aggregation.run(items).serialize()),
+        Ok(value) => Response::builder().set_body(format!("Invalid type: expected Array, got {}", value.typename())),
+        Err(e) => Response::builder().set_body(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(args: &str, items: &str) -> String {
+        let Value::Array(items) = Value::deserialize(items).unwrap() else {
+            panic!("not an array")
+        };
+        Aggregation::parse(args).unwrap().run(&items).serialize()
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let orders = r#"[{"total": 10, "status": "paid"}, {"total": 2.5, "status": "new"}, {"total": 5, "status": "paid"}, {"status": "new"}]"#;
+        assert_eq!(run("COUNT", orders), "4");
+        assert_eq!(run("COUNT total", orders), "3");
+        assert_eq!(run("SUM total", orders), "17.5");
+        assert_eq!(run("SUM", "[1, 2, \"x\"]"), "3");
+        assert_eq!(run("AVG", "[1, 2]"), "1.5");
+        assert_eq!(run("AVG", "[]"), "null");
+        assert_eq!(run("MIN total", orders), "2.5");
+        assert_eq!(run("MAX total", orders), "10");
+        assert_eq!(run("DISTINCT status", orders), r#"["new", "paid"]"#);
+        assert_eq!(run("DISTINCT", "[1, 1.0, 2, true]"), "[true, 1, 2]");
+        assert_eq!(run("SUM total GROUP BY status", orders), r#"[["new", 2.5], ["paid", 15]]"#);
+        assert_eq!(run("COUNT GROUP BY paid", r#"[{"paid": true}, {"paid": false}, {"paid": true}, {}]"#), "[[false, 1], [true, 2]]");
+        // groups of different types don't collide, numbers are ordered by value
+        assert_eq!(run("COUNT GROUP BY n", r#"[{"n": 10}, {"n": "1"}, {"n": 1}, {"n": 2.0}, {"n": 2}]"#), r#"[[1, 1], [2, 2], [10, 1], ["1", 1]]"#);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Aggregation::parse("sum a.b group by c").unwrap(), Aggregation {
+            operation: Operation::Sum,
+            field: Some(Path::parse("a.b").unwrap()),
+            group_by: Some(Path::parse("c").unwrap())
+        });
+        assert!(Aggregation::parse("median").is_err());
+        assert!(Aggregation::parse("sum a b").is_err());
+        assert!(Aggregation::parse("count group by").is_err());
+    }
+}
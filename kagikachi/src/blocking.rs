@@ -0,0 +1,198 @@
+use std::{collections::{HashMap, VecDeque}, sync::Arc, thread, time::{Duration, Instant}};
+
+use mini_json::{Path, Segment, Value};
+use sockets::{response::Response, Client};
+
+use crate::{parse_target, storage::{Entry, Shard}, Database};
+
+/// Connection blocked in `BPOP`, waiting for an element of the array at `path`.
+struct Waiter {
+    client: Arc<Client>,
+    path: Path,
+    deadline: Option<Instant>
+}
+
+/// Element handed to a waiting client, replied once the shard it was taken from is unlocked.
+pub struct Popped {
+    client: Arc<Client>,
+    value: Value,
+    /// `DEL` of the element, for replicas
+    pub message: String
+}
+
+impl Popped {
+    pub fn reply(self) {
+        let _ = self.client.reply(Response::builder().set_body(self.value.serialize()));
+    }
+}
+
+/// Clients blocked in `BPOP` by the key they wait on, each queue in the order they came.
+#[derive(Default)]
+pub struct Waiters {
+    waiting: HashMap<String, VecDeque<Waiter>>
+}
+
+impl Waiters {
+    pub fn is_waiting(&self, key: &str) -> bool {
+        self.waiting.contains_key(key)
+    }
+
+    /// Hands front elements of arrays under `key` to its waiters, the longest waiting first.
+    /// Waiters on an empty or missing array don't hold back the ones waiting on other paths.
+    pub fn serve(&mut self, key: &str, shard: &mut Shard) -> Vec<Popped> {
+        let mut popped = Vec::new();
+        let (Some(queue), Some(Entry::Value(document))) = (self.waiting.get_mut(key), shard.get_mut(key)) else {
+            return popped
+        };
+        let mut i = 0;
+        while i < queue.len() {
+            let value = match document.get_mut_element(&queue[i].path) {
+                Ok(Value::Array(items)) if !items.is_empty() => items.remove(0),
+                _ => {
+                    i += 1;
+                    continue
+                }
+            };
+            let waiter = queue.remove(i).unwrap();
+            let path = Path::from([Segment::Key(key.to_string())].into_iter().chain(waiter.path.segments().iter().cloned()).collect::<Vec<Segment>>());
+            popped.push(Popped { client: waiter.client, value, message: format!("DEL {}", path.child(Segment::Index(0))) });
+        }
+        if queue.is_empty() {
+            self.waiting.remove(key);
+        }
+        popped
+    }
+
+    /// Forgets waiters of the closed connection, so nothing is popped for it.
+    pub fn remove_client(&mut self, id: u64) {
+        for queue in self.waiting.values_mut() {
+            queue.retain(|waiter| waiter.client.id != id);
+        }
+        self.waiting.retain(|_, queue| !queue.is_empty());
+    }
+
+    /// Removes waiters whose timeout elapsed, returning their clients.
+    fn expire(&mut self, now: Instant) -> Vec<Arc<Client>> {
+        let mut expired = Vec::new();
+        for queue in self.waiting.values_mut() {
+            queue.retain(|waiter| match waiter.deadline {
+                Some(deadline) if deadline <= now => {
+                    expired.push(waiter.client.clone());
+                    false
+                },
+                _ => true
+            });
+        }
+        self.waiting.retain(|_, queue| !queue.is_empty());
+        expired
+    }
+}
+
+/// `BPOP key.path timeout`, removes and returns the first element of the array, waiting up to `timeout`
+/// milliseconds for one to be pushed if it's empty or doesn't exist yet. Zero waits forever, `null` is returned on timeout.
+///
+/// Nothing is locked while waiting, the reply is sent by whichever write hands the element over,
+/// so clients blocked on the same array are served in the order they came. The connection itself
+/// is blocked, its next command is only read once the reply is sent.
+pub fn bpop_cmd(args: &str, client: &Arc<Client>, db: &Database) -> Option<Response> {
+    let (key, path, timeout) = match parse_target(args) {
+        Ok(target) => target,
+        Err(response) => return Some(response)
+    };
+    let Ok(timeout) = timeout.parse::<u64>() else {
+        return Some(Response::builder().set_body(format!("Invalid timeout {timeout}")))
+    };
+    let deadline = match timeout {
+        0 => None,
+        timeout => Some(Instant::now() + Duration::from_millis(timeout))
+    };
+    // waiter is queued under the shard lock, the write then serves it right away if the array has elements
+    let queued = db.write_local(&key, |shard| {
+        match shard.get(&key) {
+            Some(Entry::Value(document)) => match document.get_element(&path) {
+                Ok(Value::Array(_)) | Err(_) => (),
+                Ok(value) => return Err(format!("Invalid type: expected Array, got {}", value.typename()))
+            },
+            Some(entry) => return Err(format!("Invalid type: expected Value, got {}", entry.typename())),
+            None => ()
+        }
+        // deferred before the waiter can be served, so the reply can't come ahead of it
+        client.defer_reply();
+        let waiter = Waiter { client: client.clone(), path, deadline };
+        db.waiters.write().unwrap().waiting.entry(key.clone()).or_default().push_back(waiter);
+        Ok(())
+    });
+    match queued {
+        Ok(()) => None,
+        Err(e) => Some(Response::builder().set_body(e))
+    }
+}
+
+/// Replies `null` to clients whose `BPOP` timed out, runs for the lifetime of the server.
+pub fn expire_waiters(db: &Database) {
+    loop {
+        thread::sleep(Duration::from_millis(10));
+        let expired = db.waiters.write().unwrap().expire(Instant::now());
+        for client in expired {
+            let _ = client.reply(Response::builder().set_body(Value::Null.serialize()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, testing::{start, wait_until, Connection}};
+
+    fn waiting(db: &Database, key: &str) -> usize {
+        db.waiters.read().unwrap().waiting.get(key).map_or(0, VecDeque::len)
+    }
+
+    #[test]
+    fn test_wake_in_order() {
+        let (db, address) = start(Config::default());
+        let (mut first, mut second, mut writer) = (Connection::open(&address), Connection::open(&address), Connection::open(&address));
+        assert_eq!(writer.command(r#"SET queue {"jobs": []}"#), "OK");
+        first.send("BPOP queue.jobs 0");
+        // sent right away, but only read once the pop is answered
+        first.send("PING");
+        wait_until(|| waiting(db, "queue") == 1);
+        second.send("BPOP queue.jobs 0");
+        wait_until(|| waiting(db, "queue") == 2);
+
+        assert_eq!(writer.command("SET queue.jobs.- 1"), "OK");
+        assert_eq!(first.read(), "1");
+        assert_eq!(first.read(), "PONG");
+        assert_eq!(writer.command("SET queue.jobs [2, 3]"), "OK");
+        assert_eq!(second.read(), "2");
+        assert_eq!(writer.command("GET queue.jobs"), "[3]");
+        // elements already there are popped without waiting
+        assert_eq!(first.command("BPOP queue.jobs 0"), "3");
+        assert_eq!(waiting(db, "queue"), 0);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let (db, address) = start(Config::default());
+        let mut client = Connection::open(&address);
+        assert_eq!(client.command("BPOP missing.items 50"), "null");
+        assert_eq!(client.command("PING"), "PONG");
+        assert_eq!(waiting(db, "missing"), 0);
+        assert_eq!(client.command(r#"SET text "a""#), "OK");
+        assert_eq!(client.command("BPOP text 50"), "Invalid type: expected Array, got String");
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let (db, address) = start(Config::default());
+        let mut client = Connection::open(&address);
+        client.send("BPOP queue.jobs 0");
+        wait_until(|| waiting(db, "queue") == 1);
+        drop(client);
+        // waiter of the closed connection doesn't take elements pushed later
+        wait_until(|| waiting(db, "queue") == 0);
+        let mut writer = Connection::open(&address);
+        assert_eq!(writer.command(r#"SET queue {"jobs": [1]}"#), "OK");
+        assert_eq!(writer.command("GET queue.jobs"), "[1]");
+    }
+}
@@ -0,0 +1,392 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Write as _},
+    path::{Component, Path as FsPath, PathBuf}
+};
+
+use mini_json::{unescape, ParseLimits, Path, Value};
+use sockets::response::Response;
+
+use crate::{load_entries, storage::{self, Entry, Storage}, Database};
+
+/// Keys written at once while importing, each batch is replicated as a single `LOAD`.
+const BATCH: usize = 1000;
+
+/// Resolves file name given by a client inside the data directory, names leading anywhere else are refused.
+fn resolve(dir: &str, name: &str) -> Result<PathBuf, String> {
+    let path = FsPath::new(name);
+    if name.is_empty() || !path.components().all(|component| matches!(component, Component::Normal(_))) {
+        return Err(format!("Invalid path {name}"))
+    }
+    Ok(FsPath::new(dir).join(path))
+}
+
+/// Key pattern is either exact key or prefix ending with `*`.
+fn matches(pattern: Option<&str>, key: &str) -> bool {
+    match pattern {
+        None => true,
+        Some(pattern) => match pattern.strip_suffix('*') {
+            Some(prefix) => key.starts_with(prefix),
+            None => key == pattern
+        }
+    }
+}
+
+/// Record of the export, `{"key": .., "value": ..}` on a single line.
+/// Entries other than documents name the snapshot section of their type, eg. `{"key": .., "section": "sets", "value": ..}`.
+fn line(key: &str, entry: &Entry) -> String {
+    let key = Value::from_text(key).serialize();
+    let line = match entry {
+        Entry::Value(value) => format!("{{\"key\": {key}, \"value\": {}}}", value.serialize()),
+        entry => format!("{{\"key\": {key}, \"section\": \"{}\", \"value\": {}}}", entry.section(), entry.to_value().serialize())
+    };
+    // strings may hold raw line breaks, which would split the record
+    line.replace('\n', "\\n").replace('\r', "\\r")
+}
+
+fn export(storage: &Storage, path: &FsPath, pattern: Option<&str>) -> io::Result<usize> {
+    // written aside first, so failed export doesn't leave truncated file in place of the previous one
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let mut file = BufWriter::new(File::create(&partial)?);
+    let mut count = 0;
+    storage.scan(|key, entry| {
+        if !matches(pattern, key) {
+            return Ok(())
+        }
+        count += 1;
+        writeln!(file, "{}", line(key, entry))
+    })?;
+    file.flush()?;
+    fs::rename(&partial, path)?;
+    Ok(count)
+}
+
+/// `EXPORT path [MATCH key|prefix*]`, writes every matching key into the file in the data directory,
+/// one `{"key": .., "value": ..}` line per key, and returns their count.
+///
+/// Storage is locked for reading while the file is written, so the export is consistent, same as `DUMP`.
+pub fn export_cmd(args: &str, db: &Database) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let (name, pattern) = match args.as_slice() {
+        [name] => (*name, None),
+        [name, option, pattern] if option.eq_ignore_ascii_case("match") => (*name, Some(*pattern)),
+        _ => return Response::builder().set_body("Invalid arguments")
+    };
+    let path = match resolve(&db.dir, name) {
+        Ok(path) => path,
+        Err(e) => return Response::builder().set_body(e)
+    };
+    match export(&db.storage, &path, pattern) {
+        Ok(count) => Response::builder().set_body(count.to_string()),
+        Err(e) => Response::builder().set_body(format!("Cannot write {name}: {e}"))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Format {
+    /// Lines written by `EXPORT`
+    Lines,
+    /// Header naming fields, then one record per key
+    Csv { key: Option<String>, prefix: String }
+}
+
+/// `path [CSV [KEY column] [PREFIX prefix]]`
+fn parse_import(args: &str) -> Result<(&str, Format), String> {
+    let invalid = || "Invalid arguments".to_string();
+    let mut args = args.split_whitespace();
+    let name = args.next().ok_or_else(invalid)?;
+    match args.next() {
+        None => return Ok((name, Format::Lines)),
+        Some(format) if format.eq_ignore_ascii_case("csv") => (),
+        Some(_) => return Err(invalid())
+    }
+    let (mut key, mut prefix) = (None, String::new());
+    while let Some(option) = args.next() {
+        let value = args.next().ok_or_else(invalid)?.to_string();
+        match option.to_ascii_lowercase().as_str() {
+            "key" => key = Some(value),
+            "prefix" => prefix = value,
+            _ => return Err(invalid())
+        }
+    }
+    Ok((name, Format::Csv { key, prefix }))
+}
+
+/// Next CSV record, which spans several lines when quoted field holds a line break.
+fn next_record(lines: &mut impl Iterator<Item = io::Result<String>>, line_number: &mut usize) -> io::Result<Option<String>> {
+    let mut record: Option<String> = None;
+    for line in lines.by_ref() {
+        let line = line?;
+        *line_number += 1;
+        let line = line.strip_suffix('\r').unwrap_or(&line);
+        let record = match &mut record {
+            Some(record) => {
+                record.push('\n');
+                record.push_str(line);
+                record
+            },
+            None => record.insert(line.to_string())
+        };
+        // quotes come in pairs, even `""` escaping one inside quoted field
+        if record.matches('"').count() % 2 == 0 {
+            break
+        }
+    }
+    Ok(record)
+}
+
+/// Fields of the next CSV record, errors come with number of the line they're on.
+fn read_record(lines: &mut impl Iterator<Item = io::Result<String>>, line_number: &mut usize) -> Result<Option<Vec<String>>, (usize, String)> {
+    match next_record(lines, line_number) {
+        Ok(Some(record)) => split_record(&record).map(Some).map_err(|e| (*line_number, e)),
+        Ok(None) => Ok(None),
+        Err(e) => Err((*line_number, e.to_string()))
+    }
+}
+
+/// Splits CSV record into its fields, quoted fields may hold commas, line breaks and `""` for a quote.
+fn split_record(record: &str) -> Result<Vec<String>, String> {
+    let mut fields = vec![String::new()];
+    let mut chars = record.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        let field = fields.last_mut().unwrap();
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            },
+            '"' if quoted => {
+                quoted = false;
+                if !matches!(chars.peek(), None | Some(',')) {
+                    return Err("Unexpected character after closing quote".to_string())
+                }
+            },
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => fields.push(String::new()),
+            c => field.push(c)
+        }
+    }
+    match quoted {
+        true => Err("Unterminated quote".to_string()),
+        false => Ok(fields)
+    }
+}
+
+/// Typed value of a CSV field. Numbers are only recognized when nothing is lost, so `007` stays a string.
+fn field_value(field: &str) -> Value {
+    match field {
+        "true" => return Value::Boolean(true),
+        "false" => return Value::Boolean(false),
+        _ => ()
+    }
+    if let Ok(integer) = field.parse::<isize>() {
+        if integer.to_string() == field {
+            return Value::Integer(integer)
+        }
+    }
+    match field.parse::<f64>() {
+        Ok(float) if float.is_finite() && field.contains('.') && float.to_string() == field => Value::Float(float),
+        _ => Value::from_text(field)
+    }
+}
+
+/// Turns lines of the file into keys and values, handing them over in batches.
+struct Importer<'a> {
+    db: &'a Database,
+    batch: Vec<(String, Entry)>,
+    count: usize
+}
+
+impl Importer<'_> {
+    fn add(&mut self, key: String, entry: Entry) -> Result<(), Response> {
+        self.batch.push((key, entry));
+        self.count += 1;
+        match self.batch.len() >= BATCH {
+            true => self.flush(),
+            false => Ok(())
+        }
+    }
+
+    /// Writes the batch under locks of its keys, replicas receive it as `LOAD`.
+    fn flush(&mut self) -> Result<(), Response> {
+        if self.batch.is_empty() {
+            return Ok(())
+        }
+        let keys: Vec<String> = self.batch.iter().map(|(key, _)| key.clone()).collect();
+        let message = format!("LOAD {}", Value::Object(storage::snapshot(self.batch.iter().map(|(key, entry)| (key, entry)))).serialize());
+        let entries = self.batch.drain(..).collect();
+        self.db.write_keys(&keys, &message, |shards| load_entries(entries, shards, &self.db.schemas.read().unwrap())).map_err(Into::into)
+    }
+
+    fn import_lines(&mut self, file: impl BufRead) -> Result<(), Response> {
+        // values sit one level down in the record
+        let limits = ParseLimits { max_depth: self.db.parse_limits.max_depth + 1, ..self.db.parse_limits };
+        for (i, line) in file.lines().enumerate() {
+            let invalid = |e: &str| Response::builder().set_body(format!("Invalid line {}: {e}", i + 1));
+            let line = line.map_err(|e| invalid(&e.to_string()))?;
+            if line.trim().is_empty() {
+                continue
+            }
+            let (key, section, value) = match Value::deserialize_with(&line, &limits).map_err(invalid)? {
+                Value::Object(mut record) => match (record.remove("key"), record.remove("section"), record.remove("value")) {
+                    (Some(Value::String(key)), None, Some(value)) => (key, "values".to_string(), value),
+                    (Some(Value::String(key)), Some(Value::String(section)), Some(value)) => (key, section, value),
+                    _ => return Err(invalid("expected key and value"))
+                },
+                _ => return Err(invalid("expected key and value"))
+            };
+            let key = unescape(&key).map_err(invalid)?;
+            let entry = Entry::from_value(&section, value).map_err(|e| invalid(&e))?;
+            self.add(key, entry)?;
+        }
+        Ok(())
+    }
+
+    fn import_csv(&mut self, file: impl BufRead, key: Option<&str>, prefix: &str) -> Result<(), Response> {
+        let mut lines = file.lines();
+        let mut line_number = 0;
+        let invalid = |(line, e): (usize, String)| Response::builder().set_body(format!("Invalid line {line}: {e}"));
+        let Some(header) = read_record(&mut lines, &mut line_number).map_err(invalid)? else {
+            return Ok(())
+        };
+        let fields = match header.iter().map(|column| Path::parse(column)).collect::<Result<Vec<Path>, String>>() {
+            Ok(fields) => fields,
+            Err(e) => return Err(invalid((1, e)))
+        };
+        let key_column = match key {
+            None => 0,
+            Some(key) => match header.iter().position(|column| column == key) {
+                Some(column) => column,
+                None => return Err(Response::builder().set_body(format!("Unknown column {key}")))
+            }
+        };
+        while let Some(record) = read_record(&mut lines, &mut line_number).map_err(invalid)? {
+            let line = line_number;
+            if record.len() != fields.len() {
+                return Err(invalid((line, format!("expected {} fields, got {}", fields.len(), record.len()))))
+            }
+            if record[key_column].is_empty() {
+                return Err(invalid((line, "missing key".to_string())))
+            }
+            let mut document = Value::Object(HashMap::new());
+            // empty fields are left out, rather than stored as empty strings
+            for (field, value) in fields.iter().zip(&record).filter(|(_, value)| !value.is_empty()) {
+                document.set_element(field, field_value(value)).map_err(|e| invalid((line, e)))?;
+            }
+            self.add(format!("{prefix}{}", record[key_column]), Entry::Value(document))?;
+        }
+        Ok(())
+    }
+}
+
+/// `IMPORT path`, reads keys from a file in the data directory written by `EXPORT`, and returns their count.
+/// `IMPORT path CSV [KEY column] [PREFIX prefix]` reads CSV file instead, header names fields of the documents,
+/// which may be paths like `address.city`. Key is taken from the first column, or the given one, prepended with the prefix.
+///
+/// Keys are written in batches, checked against schemas like `LOAD`. Import stops at the first invalid line
+/// or rejected batch, batches before it stay written.
+pub fn import_cmd(args: &str, db: &Database) -> Response {
+    let (name, format) = match parse_import(args) {
+        Ok(parsed) => parsed,
+        Err(e) => return Response::builder().set_body(e)
+    };
+    let file = match resolve(&db.dir, name).and_then(|path| File::open(path).map_err(|e| e.to_string())) {
+        Ok(file) => BufReader::new(file),
+        Err(e) => return Response::builder().set_body(format!("Cannot read {name}: {e}"))
+    };
+    let mut importer = Importer { db, batch: Vec::new(), count: 0 };
+    let result = match &format {
+        Format::Lines => importer.import_lines(file),
+        Format::Csv { key, prefix } => importer.import_csv(file, key.as_deref(), prefix)
+    };
+    match result.and_then(|()| importer.flush()) {
+        Ok(()) => Response::builder().set_body(importer.count.to_string()),
+        Err(response) => response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{execute, Config};
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(resolve("data", "a/b.ndjson"), Ok(PathBuf::from("data/a/b.ndjson")));
+        for invalid in ["", "/etc/passwd", "../a", "a/../../b", "./a"] {
+            assert!(resolve("data", invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_line() {
+        let line = line("user:1", &Entry::Value(Value::String("a\nb".to_string())));
+        assert_eq!(line, r#"{"key": "user:1", "value": "a\nb"}"#);
+        let set = Entry::Set(["b".to_string(), "a".to_string()].into());
+        assert_eq!(super::line("tags", &set), r#"{"key": "tags", "section": "sets", "value": ["a", "b"]}"#);
+        assert!(matches(Some("user:*"), "user:1") && !matches(Some("user:"), "user:1") && matches(None, "a"));
+        assert_eq!(super::line(r#"a"b\c"#, &Entry::Value(Value::Null)), r#"{"key": "a\"b\\c", "value": null}"#);
+    }
+
+    #[test]
+    fn test_export_import() {
+        let dir = std::env::temp_dir().join(format!("kagikachi-bulk-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = Config { dir: dir.to_string_lossy().to_string(), ..Config::default() };
+        let body = |command: &str, db: &Database| execute(command, db).payload.string().unwrap();
+
+        let source = Database::new(&config);
+        let replies: Vec<String> = [r#"SET a"b\c {"n": "x\"y"}"#, "SADD tags a b", "SET other 1"].iter().map(|command| body(command, &source)).collect();
+        assert_eq!(replies, vec!["OK", "2", "OK"]);
+        assert_eq!(export_cmd("dump.ndjson MATCH a*", &source).payload.string().unwrap(), "1");
+        assert_eq!(export_cmd("all.ndjson", &source).payload.string().unwrap(), "3");
+
+        let target = Database::new(&config);
+        assert_eq!(import_cmd("dump.ndjson", &target).payload.string().unwrap(), "1");
+        assert_eq!(body(r#"GET a"b\c.n"#, &target), r#""x\"y""#);
+        assert_eq!(import_cmd("all.ndjson", &target).payload.string().unwrap(), "3");
+        assert_eq!(body("SMEMBERS tags", &target), r#"["a", "b"]"#);
+        assert_eq!(body("GET other", &target), "1");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_import() {
+        assert_eq!(parse_import("dump.ndjson"), Ok(("dump.ndjson", Format::Lines)));
+        assert_eq!(parse_import("a.csv csv KEY id PREFIX user:"), Ok(("a.csv", Format::Csv { key: Some("id".to_string()), prefix: "user:".to_string() })));
+        assert!(parse_import("a.csv json").is_err());
+        assert!(parse_import("a.csv CSV KEY").is_err());
+    }
+
+    #[test]
+    fn test_csv() {
+        let file = "id,name,address.city\r\n1,\"Doe, \"\"J\"\"\",Brno\n2,\"multi\nline\",\n";
+        let mut lines = file.as_bytes().lines();
+        let mut line = 0;
+        let mut records = Vec::new();
+        while let Some(record) = next_record(&mut lines, &mut line).unwrap() {
+            records.push(split_record(&record).unwrap());
+        }
+        assert_eq!(records, vec![
+            vec!["id", "name", "address.city"],
+            vec!["1", "Doe, \"J\"", "Brno"],
+            vec!["2", "multi\nline", ""]
+        ]);
+        assert_eq!(line, 4);
+        assert!(split_record("\"a\"b").is_err());
+        assert!(split_record("\"a").is_err());
+    }
+
+    #[test]
+    fn test_field_value() {
+        assert_eq!(field_value("42"), Value::Integer(42));
+        assert_eq!(field_value("-1.5"), Value::Float(-1.5));
+        assert_eq!(field_value("true"), Value::Boolean(true));
+        assert_eq!(field_value("007"), Value::String("007".to_string()));
+        assert_eq!(field_value("1e3"), Value::String("1e3".to_string()));
+        assert_eq!(field_value("say \"hi\""), Value::String(r#"say \"hi\""#.to_string()));
+    }
+}
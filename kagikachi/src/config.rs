@@ -0,0 +1,69 @@
+use std::env;
+
+use mini_json::ParseLimits;
+
+use crate::ratelimit::Limits;
+
+pub struct Config {
+    pub port: u16,
+    pub replicaof: Option<String>,
+    pub shards: usize,
+    /// Commands and bytes per second allowed to a single connection
+    pub client_limits: Limits,
+    /// Commands and bytes per second allowed to all connections from the same host
+    pub host_limits: Limits,
+    /// Consecutive throttled commands after which the client is disconnected
+    pub max_throttled: u32,
+    /// Largest frame a client may send, in bytes
+    pub max_frame_size: usize,
+    /// Bounds on size, nesting depth and object keys of values sent by clients
+    pub parse_limits: ParseLimits,
+    /// Directory `EXPORT` and `IMPORT` files are kept in
+    pub dir: String
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: 7878,
+            replicaof: None,
+            shards: 16,
+            client_limits: Limits::default(),
+            host_limits: Limits::default(),
+            max_throttled: 100,
+            max_frame_size: 64 * 1024 * 1024,
+            parse_limits: ParseLimits { max_size: 16 * 1024 * 1024, ..ParseLimits::default() },
+            dir: ".".to_string()
+        }
+    }
+}
+
+impl Config {
+    /// Builds config from command line arguments, eg. `--port 7879 --replicaof 127.0.0.1:7878`.
+    /// Rate limits are set with `--client-rate`, `--client-bandwidth`, `--host-rate` and `--host-bandwidth`, zero meaning unlimited.
+    /// Sizes given with `--max-frame-size` and `--max-value-size` are in bytes, `--max-keys` limits members of a single object
+    pub fn from_args() -> Result<Self, String> {
+        let mut config = Config::default();
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("Missing value for {arg}"));
+            match arg.as_str() {
+                "--port" => config.port = value()?.parse().map_err(|_| "Invalid port".to_string())?,
+                "--replicaof" => config.replicaof = Some(value()?),
+                "--shards" => config.shards = value()?.parse().map_err(|_| "Invalid shard count".to_string())?,
+                "--client-rate" => config.client_limits.commands = value()?.parse().map_err(|_| "Invalid rate".to_string())?,
+                "--client-bandwidth" => config.client_limits.bytes = value()?.parse().map_err(|_| "Invalid bandwidth".to_string())?,
+                "--host-rate" => config.host_limits.commands = value()?.parse().map_err(|_| "Invalid rate".to_string())?,
+                "--host-bandwidth" => config.host_limits.bytes = value()?.parse().map_err(|_| "Invalid bandwidth".to_string())?,
+                "--max-throttled" => config.max_throttled = value()?.parse().map_err(|_| "Invalid throttled command count".to_string())?,
+                "--max-frame-size" => config.max_frame_size = value()?.parse().map_err(|_| "Invalid frame size".to_string())?,
+                "--max-value-size" => config.parse_limits.max_size = value()?.parse().map_err(|_| "Invalid value size".to_string())?,
+                "--max-depth" => config.parse_limits.max_depth = value()?.parse().map_err(|_| "Invalid depth".to_string())?,
+                "--dir" => config.dir = value()?,
+                "--max-keys" => config.parse_limits.max_keys = value()?.parse().map_err(|_| "Invalid key count".to_string())?,
+                _ => return Err(format!("Unknown argument {arg}"))
+            }
+        }
+        Ok(config)
+    }
+}
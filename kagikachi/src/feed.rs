@@ -0,0 +1,43 @@
+use std::{sync::{mpsc::{self, SyncSender, TrySendError}, Arc}, thread};
+
+use sockets::{response::Response, Client};
+
+/// Messages a feed holds before its connection is considered too slow to keep up and is closed.
+const CAPACITY: usize = 64 * 1024;
+
+/// Messages queued for a connection, sent by a thread of its own,
+/// so the one queueing them doesn't wait on the network nor on a slow peer.
+pub struct Feed {
+    pub client: Arc<Client>,
+    sender: SyncSender<String>
+}
+
+impl Feed {
+    pub fn new(client: Arc<Client>) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<String>(CAPACITY);
+        let connection = client.clone();
+        thread::spawn(move || {
+            for message in receiver {
+                if connection.send(Response::builder().set_body(message)).is_err() {
+                    break
+                }
+            }
+        });
+        Self { client, sender }
+    }
+
+    /// Queues the message, false once the connection is gone or fell too far behind, the feed is to be dropped then.
+    pub fn send(&self, message: &str) -> bool {
+        match self.sender.try_send(message.to_string()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                // closing waits for the write in progress, which is what's slow
+                let client = self.client.clone();
+                // 1008 is policy violation
+                thread::spawn(move || client.close(1008, "Too far behind"));
+                false
+            },
+            Err(TrySendError::Disconnected(_)) => false
+        }
+    }
+}
@@ -0,0 +1,379 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use mini_json::{escape, Path, Value};
+use sockets::response::Response;
+
+use crate::Database;
+
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+/// Positions of consecutive fields are this far apart, so phrases never match across fields.
+const FIELD_GAP: usize = 100;
+
+/// Splits text into lowercase alphanumeric terms.
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(|term| term.to_lowercase())
+        .collect()
+}
+
+enum Clause {
+    Term(String),
+    Prefix(String),
+    Phrase(Vec<String>)
+}
+
+/// Parses query like `rust borr* 'memory safety'`. Whole query may be wrapped in double quotes,
+/// phrases are wrapped in single quotes and terms ending with `*` match by prefix.
+fn parse_query(query: &str) -> Vec<Clause> {
+    let query = query.trim();
+    let query = match query.strip_prefix('"').and_then(|q| q.strip_suffix('"')) {
+        Some(query) => query,
+        None => query
+    };
+    let mut clauses = Vec::new();
+    for (i, part) in query.split('\'').enumerate() {
+        // odd parts are inside of quotes
+        if i % 2 == 1 {
+            let terms = tokenize(part);
+            match terms.len() {
+                0 => {},
+                1 => clauses.push(Clause::Term(terms[0].clone())),
+                _ => clauses.push(Clause::Phrase(terms))
+            }
+            continue
+        }
+        for word in part.split_whitespace() {
+            match word.strip_suffix('*') {
+                Some(prefix) => clauses.extend(tokenize(prefix).into_iter().map(Clause::Prefix)),
+                None => clauses.extend(tokenize(word).into_iter().map(Clause::Term))
+            }
+        }
+    }
+    clauses
+}
+
+pub struct FtIndex {
+    prefix: String,
+    fields: Vec<Path>,
+    /// term -> key -> positions of the term in the document
+    postings: BTreeMap<String, HashMap<String, Vec<usize>>>,
+    /// key -> (document length, distinct terms)
+    documents: HashMap<String, (usize, Vec<String>)>,
+    total_length: usize
+}
+
+impl FtIndex {
+    pub fn new(pattern: &str, fields: &str) -> Result<Self, String> {
+        let prefix = match pattern.strip_suffix('*') {
+            Some(prefix) => prefix,
+            None => return Err("Key pattern must end with *".to_string())
+        };
+        let fields: Vec<Path> = fields.split(',').filter(|f| !f.is_empty()).map(Path::parse).collect::<Result<_, _>>()?;
+        if fields.is_empty() {
+            return Err("At least one field is required".to_string())
+        }
+        Ok(Self {
+            prefix: prefix.to_string(),
+            fields,
+            postings: BTreeMap::new(),
+            documents: HashMap::new(),
+            total_length: 0
+        })
+    }
+
+    fn remove(&mut self, key: &str) {
+        let Some((length, terms)) = self.documents.remove(key) else {
+            return
+        };
+        self.total_length -= length;
+        for term in terms {
+            if let Some(keys) = self.postings.get_mut(&term) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
+
+    /// Reindexes `key` after its value changed, `None` meaning the key was deleted.
+    pub fn update(&mut self, key: &str, value: Option<&Value>) {
+        if !key.starts_with(&self.prefix) {
+            return
+        }
+        self.remove(key);
+        let Some(value) = value else {
+            return
+        };
+
+        let mut positions: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut length = 0;
+        let mut offset = 0;
+        for field in &self.fields {
+            // tokenized as plain text, so escapes don't end up in the terms
+            let Ok(Ok(text)) = value.get_element(field).map(Value::text) else {
+                continue
+            };
+            let terms = tokenize(&text);
+            length += terms.len();
+            for (i, term) in terms.iter().enumerate() {
+                positions.entry(term.clone()).or_default().push(offset + i);
+            }
+            offset += terms.len() + FIELD_GAP;
+        }
+        if length == 0 {
+            return
+        }
+
+        let terms = positions.keys().cloned().collect();
+        for (term, positions) in positions {
+            self.postings.entry(term).or_default().insert(key.to_string(), positions);
+        }
+        self.documents.insert(key.to_string(), (length, terms));
+        self.total_length += length;
+    }
+
+    pub fn clear(&mut self) {
+        self.postings.clear();
+        self.documents.clear();
+        self.total_length = 0;
+    }
+
+    /// Term frequency of the clause in every document that matches it.
+    fn frequencies(&self, clause: &Clause) -> HashMap<&String, usize> {
+        let mut frequencies = HashMap::new();
+        match clause {
+            Clause::Term(term) => {
+                if let Some(keys) = self.postings.get(term) {
+                    frequencies.extend(keys.iter().map(|(key, positions)| (key, positions.len())));
+                }
+            },
+            Clause::Prefix(prefix) => {
+                let terms = self.postings.range(prefix.clone()..).take_while(|(term, _)| term.starts_with(prefix.as_str()));
+                for (_, keys) in terms {
+                    for (key, positions) in keys {
+                        *frequencies.entry(key).or_default() += positions.len();
+                    }
+                }
+            },
+            Clause::Phrase(terms) => {
+                let Some(first) = self.postings.get(&terms[0]) else {
+                    return frequencies
+                };
+                for (key, starts) in first {
+                    let count = starts.iter().filter(|&&start| {
+                        terms.iter().enumerate().skip(1).all(|(i, term)| {
+                            self.postings.get(term)
+                                .and_then(|keys| keys.get(key))
+                                .is_some_and(|positions| positions.contains(&(start + i)))
+                        })
+                    }).count();
+                    if count > 0 {
+                        frequencies.insert(key, count);
+                    }
+                }
+            }
+        }
+        frequencies
+    }
+
+    /// Returns keys matching every clause of the query, best BM25 score first.
+    pub fn search(&self, query: &str) -> Vec<String> {
+        let clauses = parse_query(query);
+        if clauses.is_empty() || self.documents.is_empty() {
+            return Vec::new()
+        }
+        let documents = self.documents.len() as f64;
+        let average_length = self.total_length as f64 / documents;
+
+        let mut scores: HashMap<&String, f64> = HashMap::new();
+        let mut matched: Option<HashSet<&String>> = None;
+        for clause in &clauses {
+            let frequencies = self.frequencies(clause);
+            let containing = frequencies.len() as f64;
+            let idf = ((documents - containing + 0.5) / (containing + 0.5) + 1.0).ln();
+            for (key, frequency) in &frequencies {
+                let length = self.documents[*key].0 as f64;
+                let tf = *frequency as f64;
+                *scores.entry(key).or_default() += idf * tf * (K1 + 1.0) / (tf + K1 * (1.0 - B + B * length / average_length));
+            }
+            let keys: HashSet<&String> = frequencies.into_keys().collect();
+            matched = Some(match matched {
+                Some(matched) => matched.intersection(&keys).copied().collect(),
+                None => keys
+            });
+        }
+
+        let mut results: Vec<(&String, f64)> = matched.unwrap_or_default().into_iter().map(|key| (key, scores[key])).collect();
+        results.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        results.into_iter().map(|(key, _)| key.clone()).collect()
+    }
+
+    pub fn describe(&self) -> Value {
+        Value::Object(HashMap::from([
+            ("prefix".to_string(), Value::from_text(&format!("{}*", self.prefix))),
+            ("fields".to_string(), Value::Array(self.fields.iter().map(|field| Value::from_text(&field.to_string())).collect())),
+            ("documents".to_string(), Value::Integer(self.documents.len() as isize)),
+            ("terms".to_string(), Value::Integer(self.postings.len() as isize))
+        ]))
+    }
+}
+
+#[derive(Default)]
+pub struct FtIndexes {
+    indexes: HashMap<String, FtIndex>
+}
+
+impl FtIndexes {
+    pub fn create<'a>(&mut self, name: &str, mut index: FtIndex, storage: impl Iterator<Item = (&'a String, &'a Value)>) -> Result<(), String> {
+        if self.indexes.contains_key(name) {
+            return Err(format!("Index {name} already exists"))
+        }
+        for (key, value) in storage {
+            index.update(key, Some(value));
+        }
+        self.indexes.insert(name.to_string(), index);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.indexes.remove(name).is_some()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&FtIndex> {
+        self.indexes.get(name)
+    }
+
+    /// Whether changes of `key` concern some index.
+    pub fn covers(&self, key: &str) -> bool {
+        self.indexes.values().any(|index| key.starts_with(&index.prefix))
+    }
+
+    pub fn update(&mut self, key: &str, value: Option<&Value>) {
+        for index in self.indexes.values_mut() {
+            index.update(key, value);
+        }
+    }
+
+    /// Rebuilds every index from scratch, used when the whole storage is replaced.
+    pub fn rebuild<'a>(&mut self, storage: impl Iterator<Item = (&'a String, &'a Value)>) {
+        for index in self.indexes.values_mut() {
+            index.clear();
+        }
+        for (key, value) in storage {
+            self.update(key, Some(value));
+        }
+    }
+
+    pub fn describe(&self) -> Value {
+        Value::Object(self.indexes.iter().map(|(name, index)| (escape(name), index.describe())).collect())
+    }
+}
+
+/// `FTINDEX CREATE name ON prefix* FIELDS title,body`, `FTINDEX DROP name` or `FTINDEX LIST`
+pub fn ftindex_cmd(args: &str, db: &Database) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    match args.as_slice() {
+        [subcommand, name, on, pattern, fields, paths] if subcommand.eq_ignore_ascii_case("create")
+            && on.eq_ignore_ascii_case("on") && fields.eq_ignore_ascii_case("fields") => {
+            let index = match FtIndex::new(pattern, paths) {
+                Ok(index) => index,
+                Err(e) => return Response::builder().set_body(e)
+            };
+            // writers are blocked while the index is built, so no update can be missed
+            let shards = db.storage.read_all();
+            // writes already applied may still be updating indexes, the new one is built from their result
+            let _after_write = shards.after_write();
+            match db.fulltext.write().unwrap().create(name, index, shards.documents()) {
+                Ok(()) => Response::builder().set_body("OK"),
+                Err(e) => Response::builder().set_body(e)
+            }
+        },
+        [subcommand, name] if subcommand.eq_ignore_ascii_case("drop") => {
+            match db.fulltext.write().unwrap().remove(name) {
+                true => Response::builder().set_body("OK"),
+                false => Response::builder().set_body("Index not found")
+            }
+        },
+        [subcommand] if subcommand.eq_ignore_ascii_case("list") => {
+            Response::builder().set_body(db.fulltext.read().unwrap().describe().serialize())
+        },
+        _ => Response::builder().set_body("Invalid arguments")
+    }
+}
+
+/// `FTSEARCH name "query terms"`, returns array of matching keys ranked by BM25
+pub fn ftsearch_cmd(args: &str, db: &Database) -> Response {
+    let (name, query) = match args.split_once(" ") {
+        Some((name, query)) => (name, query),
+        None => return Response::builder().set_body("Invalid arguments")
+    };
+    let fulltext = db.fulltext.read().unwrap();
+    match fulltext.get(name) {
+        Some(index) => {
+            let keys = index.search(query).iter().map(|key| Value::from_text(key)).collect();
+            Response::builder().set_body(Value::Array(keys).serialize())
+        },
+        None => Response::builder().set_body("Index not found")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{execute, Config};
+
+    fn article(title: &str, body: &str) -> Value {
+        Value::Object(HashMap::from([
+            ("title".to_string(), Value::String(title.to_string())),
+            ("body".to_string(), Value::String(body.to_string()))
+        ]))
+    }
+
+    fn index() -> FtIndex {
+        let mut index = FtIndex::new("doc:*", "title,body").unwrap();
+        index.update("doc:1", Some(&article("Rust ownership", "Ownership and borrowing keep memory safe.")));
+        index.update("doc:2", Some(&article("Garbage collection", "Memory is reclaimed by the collector, not by ownership rules.")));
+        index.update("doc:3", Some(&article("Rust async", "Futures in Rust are lazy. Rust rust rust.")));
+        index.update("other", Some(&article("Rust", "Rust")));
+        index
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(tokenize("Hello, World! it's 2024"), vec!["hello", "world", "it", "s", "2024"]);
+    }
+
+    #[test]
+    fn test_search_ranking() {
+        let index = index();
+        assert_eq!(index.search("\"rust\""), vec!["doc:3", "doc:1"]);
+        assert_eq!(index.search("ownership memory"), vec!["doc:1", "doc:2"]);
+        assert!(index.search("python").is_empty());
+    }
+
+    #[test]
+    fn test_search_prefix_and_phrase() {
+        let mut index = index();
+        assert_eq!(index.search("collect*"), vec!["doc:2"]);
+        assert_eq!(index.search("'memory safe'"), vec!["doc:1"]);
+        // phrases don't match across fields
+        assert!(index.search("'ownership ownership'").is_empty());
+        index.update("doc:1", None);
+        assert!(index.search("'memory safe'").is_empty());
+    }
+
+    #[test]
+    fn test_escaping() {
+        let db = Database::new(&Config::default());
+        execute(r#"SET doc:"4" {"title": "Quoted \"Zig\"", "body": "first\nsecond"}"#, &db);
+        let body = |response: Response| response.payload.string().unwrap();
+        assert_eq!(body(ftindex_cmd("CREATE docs ON doc:* FIELDS title,body", &db)), "OK");
+        // escapes are not part of the terms
+        assert_eq!(body(ftsearch_cmd("docs second", &db)), r#"["doc:\"4\""]"#);
+        assert_eq!(body(ftsearch_cmd("docs 'quoted zig'", &db)), r#"["doc:\"4\""]"#);
+        assert_eq!(body(ftsearch_cmd("docs nsecond", &db)), "[]");
+    }
+}
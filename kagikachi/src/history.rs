@@ -0,0 +1,262 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    time::{SystemTime, UNIX_EPOCH}
+};
+
+use mini_json::Value;
+use sockets::response::Response;
+
+use crate::{execute, parse_target, projection::Projection, storage::Entry, Database};
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// State of the key between two writes, `None` value meaning the key didn't exist.
+#[derive(Clone, Debug, PartialEq)]
+struct Version {
+    version: u64,
+    timestamp: Option<u64>,
+    value: Option<Value>
+}
+
+impl Version {
+    fn to_value(&self) -> Value {
+        let mut fields = HashMap::from([
+            ("version".to_string(), Value::Integer(self.version as isize)),
+            ("timestamp".to_string(), self.timestamp.map_or(Value::Null, |t| Value::Integer(t as isize)))
+        ]);
+        match &self.value {
+            Some(value) => fields.insert("value".to_string(), value.clone()),
+            None => fields.insert("deleted".to_string(), Value::Boolean(true))
+        };
+        Value::Object(fields)
+    }
+}
+
+#[derive(Debug, Default)]
+struct KeyHistory {
+    /// Version of the current value, `versions` hold older ones
+    version: u64,
+    timestamp: Option<u64>,
+    versions: VecDeque<Version>
+}
+
+/// Previous values of keys configured to keep their history.
+/// Keys are configured one by one or by prefix, exact key configuration wins over prefixes.
+#[derive(Debug, Default)]
+pub struct History {
+    limits: BTreeMap<String, usize>,
+    keys: HashMap<String, KeyHistory>
+}
+
+impl History {
+    /// Number of versions kept for `key`, `None` if it's not versioned.
+    fn limit(&self, key: &str) -> Option<usize> {
+        if let Some(limit) = self.limits.get(key) {
+            return Some(*limit)
+        }
+        self.limits.iter()
+            .filter_map(|(pattern, limit)| Some((pattern.strip_suffix('*')?, limit)))
+            .filter(|(prefix, _)| key.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, limit)| *limit)
+    }
+
+    pub fn is_versioned(&self, key: &str) -> bool {
+        self.limit(key).is_some()
+    }
+
+    /// Sets number of versions kept for the key or `prefix*`, zero turns versioning off.
+    pub fn configure(&mut self, pattern: &str, limit: usize) {
+        match limit {
+            0 => self.limits.remove(pattern),
+            limit => self.limits.insert(pattern.to_string(), limit)
+        };
+        let limits: Vec<(String, Option<usize>)> = self.keys.keys().map(|key| (key.clone(), self.limit(key))).collect();
+        for (key, limit) in limits {
+            match limit {
+                Some(limit) => self.trim(&key, limit),
+                None => {
+                    self.keys.remove(&key);
+                }
+            }
+        }
+    }
+
+    fn trim(&mut self, key: &str, limit: usize) {
+        if let Some(history) = self.keys.get_mut(key) {
+            while history.versions.len() > limit {
+                history.versions.pop_front();
+            }
+        }
+    }
+
+    /// Records write of versioned `key` which replaced `previous` with `current`. Writes which didn't change anything are skipped.
+    pub fn record(&mut self, key: &str, previous: Option<Value>, current: Option<&Value>) {
+        let Some(limit) = self.limit(key) else {
+            return
+        };
+        if previous.as_ref() == current {
+            return
+        }
+        let history = self.keys.entry(key.to_string()).or_default();
+        history.versions.push_back(Version { version: history.version, timestamp: history.timestamp, value: previous });
+        history.version += 1;
+        history.timestamp = Some(now());
+        self.trim(key, limit);
+    }
+
+    /// Value `key` had at `version`, `current` being the value it has now.
+    /// Outer `None` means the version is unknown, inner one that the key didn't exist at the time.
+    fn get(&self, key: &str, version: u64, current: Option<&Value>) -> Option<Option<Value>> {
+        let history = self.keys.get(key);
+        if version == history.map_or(0, |history| history.version) {
+            return Some(current.cloned())
+        }
+        history?.versions.iter().find(|v| v.version == version).map(|v| v.value.clone())
+    }
+
+    /// Versions of `key` from the newest one, including the current value.
+    fn list(&self, key: &str, current: Option<&Value>) -> Vec<Version> {
+        let Some(history) = self.keys.get(key) else {
+            return Vec::new()
+        };
+        let current = Version { version: history.version, timestamp: history.timestamp, value: current.cloned() };
+        std::iter::once(current).chain(history.versions.iter().rev().cloned()).collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.limits.clear();
+        self.keys.clear();
+    }
+
+    /// `VERSIONING` commands recreating the configuration, sent to replicas after the snapshot.
+    pub fn commands(&self) -> Vec<String> {
+        self.limits.iter().map(|(pattern, limit)| format!("VERSIONING {pattern} {limit}")).collect()
+    }
+}
+
+/// Splits `key@v12` into the key and version, only for keys which are versioned.
+fn parse_versioned<'a>(key: &'a str, history: &History) -> Option<(&'a str, u64)> {
+    let (key, version) = key.rsplit_once("@v")?;
+    let version = version.parse().ok()?;
+    history.is_versioned(key).then_some((key, version))
+}
+
+/// `VERSIONING key|prefix* N` keeps last N versions of matching keys, zero turns it off. `VERSIONING LIST` shows the configuration.
+pub fn versioning_cmd(args: &str, message: &str, db: &Database) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    match args.as_slice() {
+        [subcommand] if subcommand.eq_ignore_ascii_case("list") => {
+            let history = db.history.read().unwrap();
+            let limits = history.limits.iter().map(|(pattern, limit)| (pattern.clone(), Value::Integer(*limit as isize)));
+            Response::builder().set_body(Value::Object(limits.collect()).serialize())
+        },
+        [pattern, limit] => match limit.parse::<usize>() {
+            // writers are blocked, so no write misses the change
+            Ok(limit) => db.write_all(message, |_| {
+                db.history.write().unwrap().configure(pattern, limit);
+                Response::builder().set_body("OK")
+            }),
+            Err(_) => Response::builder().set_body(format!("Invalid count {limit}"))
+        },
+        _ => Response::builder().set_body("Invalid arguments")
+    }
+}
+
+/// `HISTORY key`, returns versions from the newest one, each with version number, timestamp and value
+pub fn history_cmd(args: &str, db: &Database) -> Response {
+    let key = args.trim();
+    let shard = db.storage.read(key);
+    let versions = db.history.read().unwrap().list(key, shard.get(key).and_then(Entry::value));
+    Response::builder().set_body(Value::Array(versions.iter().map(Version::to_value).collect()).serialize())
+}
+
+/// `GET key@v12` or `GET key@v12.path`, with the same projection options as `GET`. `None` if the key is not asking for a version.
+pub fn get_version_cmd(args: &str, db: &Database) -> Option<Response> {
+    let (versioned, path, rest) = parse_target(args).ok()?;
+    let (key, version) = parse_versioned(&versioned, &db.history.read().unwrap())?;
+    let projection = match Projection::parse(rest) {
+        Ok(projection) => projection,
+        Err(e) => return Some(Response::builder().set_body(e))
+    };
+    let shard = db.storage.read(key);
+    let value = db.history.read().unwrap().get(key, version, shard.get(key).and_then(Entry::value));
+    let response = match value {
+        None => Response::builder().set_body(format!("Version {version} not found")),
+        Some(None) => Response::builder().set_body("Key not found"),
+        Some(Some(value)) => match value.get_element(&path) {
+            Ok(value) => Response::builder().set_body(projection.apply(value).serialize()),
+            Err(e) => Response::builder().set_body(e)
+        }
+    };
+    Some(response)
+}
+
+/// `ROLLBACK key v12`, writes the value the key had at given version as a new version.
+///
+/// Runs as an ordinary `SET` or `DEL`, so schemas apply and replicas, which don't share
+/// the history, receive the resulting write.
+pub fn rollback_cmd(args: &str, db: &Database) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let [key, version] = args.as_slice() else {
+        return Response::builder().set_body("Invalid arguments")
+    };
+    let Some(version) = version.strip_prefix('v').and_then(|version| version.parse().ok()) else {
+        return Response::builder().set_body(format!("Invalid version {version}"))
+    };
+    // versions never change once written, so the value can't go stale before it's written back
+    let value = {
+        let shard = db.storage.read(key);
+        let history = db.history.read().unwrap();
+        if !history.is_versioned(key) {
+            return Response::builder().set_body("Key is not versioned")
+        }
+        history.get(key, version, shard.get(*key).and_then(Entry::value))
+    };
+    match value {
+        None => Response::builder().set_body(format!("Version {version} not found")),
+        Some(Some(value)) => execute(&format!("SET {key} {}", value.serialize()), db),
+        Some(None) => execute(&format!("DEL {key}"), db)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let mut history = History::default();
+        history.configure("doc:*", 2);
+        history.configure("doc:important", 5);
+        assert!(!history.is_versioned("other"));
+        assert_eq!(history.limit("doc:important"), Some(5));
+
+        let values: Vec<Value> = (1..=4).map(Value::Integer).collect();
+        history.record("doc:1", None, Some(&values[0]));
+        history.record("doc:1", Some(values[0].clone()), Some(&values[0]));
+        for pair in values.windows(2) {
+            history.record("doc:1", Some(pair[0].clone()), Some(&pair[1]));
+        }
+        // versions 0 (missing) and 1 were trimmed
+        let versions: Vec<u64> = history.list("doc:1", Some(&values[3])).iter().map(|v| v.version).collect();
+        assert_eq!(versions, vec![4, 3, 2]);
+        assert_eq!(history.get("doc:1", 2, Some(&values[3])), Some(Some(values[1].clone())));
+        assert_eq!(history.get("doc:1", 4, Some(&values[3])), Some(Some(values[3].clone())));
+        assert_eq!(history.get("doc:1", 1, Some(&values[3])), None);
+
+        history.configure("doc:*", 0);
+        assert!(history.list("doc:1", None).is_empty());
+    }
+
+    #[test]
+    fn test_parse_versioned() {
+        let mut history = History::default();
+        history.configure("doc", 1);
+        assert_eq!(parse_versioned("doc@v12", &history), Some(("doc", 12)));
+        assert_eq!(parse_versioned("user@v12", &history), None);
+        assert_eq!(parse_versioned("doc@vx", &history), None);
+    }
+}
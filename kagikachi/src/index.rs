@@ -0,0 +1,346 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::Bound
+};
+
+use mini_json::{escape, Path, Value};
+use sockets::response::Response;
+
+use crate::Database;
+
+/// Indexed field value. Integers and floats share one numeric space, so `1` and `1.0` are the same entry.
+#[derive(Clone, Debug)]
+pub enum IndexKey {
+    Null,
+    Boolean(bool),
+    Number(f64),
+    String(String)
+}
+
+impl IndexKey {
+    /// Arrays and objects are not indexable.
+    pub fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Null => Some(IndexKey::Null),
+            Value::Boolean(b) => Some(IndexKey::Boolean(*b)),
+            Value::Integer(i) => Some(IndexKey::Number(*i as f64)),
+            // -0.0 and 0.0 are the same number, but not for total ordering
+            Value::Float(f) => Some(IndexKey::Number(if *f == 0.0 { 0.0 } else { *f })),
+            Value::String(s) => Some(IndexKey::String(s.clone())),
+            Value::Array(_) | Value::Object(_) => None
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            IndexKey::Null => 0,
+            IndexKey::Boolean(_) => 1,
+            IndexKey::Number(_) => 2,
+            IndexKey::String(_) => 3
+        }
+    }
+}
+
+impl Ord for IndexKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (IndexKey::Boolean(a), IndexKey::Boolean(b)) => a.cmp(b),
+            (IndexKey::Number(a), IndexKey::Number(b)) => a.total_cmp(b),
+            (IndexKey::String(a), IndexKey::String(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank())
+        }
+    }
+}
+
+impl PartialOrd for IndexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for IndexKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for IndexKey {}
+
+pub enum Query {
+    Equal(IndexKey),
+    Range(Bound<f64>, Bound<f64>)
+}
+
+impl Query {
+    /// Parses query like `= "john@example.com"`, `> 10` or `BETWEEN 10 20`.
+    pub fn parse(query: &str) -> Result<Self, String> {
+        let (op, operand) = match query.trim().split_once(" ") {
+            Some((op, operand)) => (op, operand.trim()),
+            None => return Err("Invalid query".to_string())
+        };
+        if op == "=" {
+            // bare words are treated as strings, so quoting emails and such is optional
+            let value = Value::deserialize(operand).unwrap_or(Value::String(operand.to_string()));
+            return match IndexKey::from_value(&value) {
+                Some(key) => Ok(Query::Equal(key)),
+                None => Err("Arrays and objects can't be queried".to_string())
+            }
+        }
+        let number = |s: &str| s.trim().parse::<f64>().map_err(|_| "Range queries require numeric values".to_string());
+        match op.to_ascii_lowercase().as_str() {
+            ">" => Ok(Query::Range(Bound::Excluded(number(operand)?), Bound::Unbounded)),
+            ">=" => Ok(Query::Range(Bound::Included(number(operand)?), Bound::Unbounded)),
+            "<" => Ok(Query::Range(Bound::Unbounded, Bound::Excluded(number(operand)?))),
+            "<=" => Ok(Query::Range(Bound::Unbounded, Bound::Included(number(operand)?))),
+            "between" => match operand.split_once(" ") {
+                Some((min, max)) => Ok(Query::Range(Bound::Included(number(min)?), Bound::Included(number(max)?))),
+                None => Err("BETWEEN requires two values".to_string())
+            },
+            _ => Err(format!("Unknown operator {op}"))
+        }
+    }
+}
+
+pub struct Index {
+    prefix: String,
+    field: Path,
+    entries: BTreeMap<IndexKey, BTreeSet<String>>,
+    indexed: HashMap<String, IndexKey>
+}
+
+impl Index {
+    /// `pattern` is either `*` or prefix followed by `*`, eg. `user:*`
+    pub fn new(pattern: &str, field: &str) -> Result<Self, String> {
+        let prefix = match pattern.strip_suffix('*') {
+            Some(prefix) => prefix,
+            None => return Err("Key pattern must end with *".to_string())
+        };
+        Ok(Self {
+            prefix: prefix.to_string(),
+            field: Path::parse(field)?,
+            entries: BTreeMap::new(),
+            indexed: HashMap::new()
+        })
+    }
+
+    /// Reindexes `key` after its value changed, `None` meaning the key was deleted.
+    pub fn update(&mut self, key: &str, value: Option<&Value>) {
+        if !key.starts_with(&self.prefix) {
+            return
+        }
+        if let Some(old) = self.indexed.remove(key) {
+            if let Some(keys) = self.entries.get_mut(&old) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.entries.remove(&old);
+                }
+            }
+        }
+        let field = match value.map(|value| value.get_element(&self.field)) {
+            Some(Ok(field)) => field,
+            _ => return
+        };
+        if let Some(index_key) = IndexKey::from_value(field) {
+            self.entries.entry(index_key.clone()).or_default().insert(key.to_string());
+            self.indexed.insert(key.to_string(), index_key);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.indexed.clear();
+    }
+
+    /// Returns matching keys in order of indexed value.
+    pub fn find(&self, query: &Query) -> Vec<String> {
+        let range = match query {
+            Query::Equal(key) => (Bound::Included(key.clone()), Bound::Included(key.clone())),
+            Query::Range(min, max) => {
+                let bound = |bound: &Bound<f64>, default: f64| match bound {
+                    Bound::Included(n) => Bound::Included(IndexKey::Number(*n)),
+                    Bound::Excluded(n) => Bound::Excluded(IndexKey::Number(*n)),
+                    // stay within numbers, other types are ordered around them
+                    Bound::Unbounded => Bound::Included(IndexKey::Number(default))
+                };
+                (bound(min, f64::NEG_INFINITY), bound(max, f64::INFINITY))
+            }
+        };
+        if let (Bound::Included(min) | Bound::Excluded(min), Bound::Included(max) | Bound::Excluded(max)) = &range {
+            // BTreeMap::range panics on inverted ranges
+            if min > max {
+                return Vec::new()
+            }
+        }
+        self.entries.range(range).flat_map(|(_, keys)| keys.iter().cloned()).collect()
+    }
+
+    pub fn describe(&self) -> Value {
+        Value::Object(HashMap::from([
+            ("prefix".to_string(), Value::from_text(&format!("{}*", self.prefix))),
+            ("field".to_string(), Value::from_text(&self.field.to_string())),
+            ("keys".to_string(), Value::Integer(self.indexed.len() as isize))
+        ]))
+    }
+}
+
+#[derive(Default)]
+pub struct Indexes {
+    indexes: HashMap<String, Index>
+}
+
+impl Indexes {
+    pub fn create<'a>(&mut self, name: &str, mut index: Index, storage: impl Iterator<Item = (&'a String, &'a Value)>) -> Result<(), String> {
+        if self.indexes.contains_key(name) {
+            return Err(format!("Index {name} already exists"))
+        }
+        for (key, value) in storage {
+            index.update(key, Some(value));
+        }
+        self.indexes.insert(name.to_string(), index);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.indexes.remove(name).is_some()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Index> {
+        self.indexes.get(name)
+    }
+
+    /// Whether changes of `key` concern some index.
+    pub fn covers(&self, key: &str) -> bool {
+        self.indexes.values().any(|index| key.starts_with(&index.prefix))
+    }
+
+    pub fn update(&mut self, key: &str, value: Option<&Value>) {
+        for index in self.indexes.values_mut() {
+            index.update(key, value);
+        }
+    }
+
+    /// Rebuilds every index from scratch, used when the whole storage is replaced.
+    pub fn rebuild<'a>(&mut self, storage: impl Iterator<Item = (&'a String, &'a Value)>) {
+        for index in self.indexes.values_mut() {
+            index.clear();
+        }
+        for (key, value) in storage {
+            self.update(key, Some(value));
+        }
+    }
+
+    pub fn describe(&self) -> Value {
+        Value::Object(self.indexes.iter().map(|(name, index)| (escape(name), index.describe())).collect())
+    }
+}
+
+/// `INDEX CREATE name ON prefix* FIELD path`, `INDEX DROP name` or `INDEX LIST`
+pub fn index_cmd(args: &str, db: &Database) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    match args.as_slice() {
+        [subcommand, name, on, pattern, field, path] if subcommand.eq_ignore_ascii_case("create")
+            && on.eq_ignore_ascii_case("on") && field.eq_ignore_ascii_case("field") => {
+            let index = match Index::new(pattern, path) {
+                Ok(index) => index,
+                Err(e) => return Response::builder().set_body(e)
+            };
+            // writers are blocked while the index is built, so no update can be missed
+            let shards = db.storage.read_all();
+            // writes already applied may still be updating indexes, the new one is built from their result
+            let _after_write = shards.after_write();
+            match db.indexes.write().unwrap().create(name, index, shards.documents()) {
+                Ok(()) => Response::builder().set_body("OK"),
+                Err(e) => Response::builder().set_body(e)
+            }
+        },
+        [subcommand, name] if subcommand.eq_ignore_ascii_case("drop") => {
+            match db.indexes.write().unwrap().remove(name) {
+                true => Response::builder().set_body("OK"),
+                false => Response::builder().set_body("Index not found")
+            }
+        },
+        [subcommand] if subcommand.eq_ignore_ascii_case("list") => {
+            Response::builder().set_body(db.indexes.read().unwrap().describe().serialize())
+        },
+        _ => Response::builder().set_body("Invalid arguments")
+    }
+}
+
+/// `FIND name = value`, `FIND name > 10` or `FIND name BETWEEN 10 20`, returns array of matching keys
+pub fn find_cmd(args: &str, db: &Database) -> Response {
+    let (name, query) = match args.split_once(" ") {
+        Some((name, query)) => (name, query),
+        None => return Response::builder().set_body("Invalid arguments")
+    };
+    let query = match Query::parse(query) {
+        Ok(query) => query,
+        Err(e) => return Response::builder().set_body(e)
+    };
+    let indexes = db.indexes.read().unwrap();
+    match indexes.get(name) {
+        Some(index) => {
+            let keys = index.find(&query).iter().map(|key| Value::from_text(key)).collect();
+            Response::builder().set_body(Value::Array(keys).serialize())
+        },
+        None => Response::builder().set_body("Index not found")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{execute, Config};
+
+    fn user(email: &str, age: isize) -> Value {
+        Value::Object(HashMap::from([
+            ("email".to_string(), Value::String(email.to_string())),
+            ("age".to_string(), Value::Integer(age))
+        ]))
+    }
+
+    #[test]
+    fn test_index_equal() {
+        let mut index = Index::new("user:*", "email").unwrap();
+        index.update("user:1", Some(&user("a@example.com", 20)));
+        index.update("user:2", Some(&user("b@example.com", 30)));
+        index.update("post:1", Some(&user("a@example.com", 20)));
+        assert_eq!(index.find(&Query::parse("= a@example.com").unwrap()), vec!["user:1"]);
+        assert_eq!(index.find(&Query::parse(r#"= "b@example.com""#).unwrap()), vec!["user:2"]);
+    }
+
+    #[test]
+    fn test_index_update_and_delete() {
+        let mut index = Index::new("user:*", "email").unwrap();
+        index.update("user:1", Some(&user("a@example.com", 20)));
+        index.update("user:1", Some(&user("c@example.com", 20)));
+        assert!(index.find(&Query::parse("= a@example.com").unwrap()).is_empty());
+        assert_eq!(index.find(&Query::parse("= c@example.com").unwrap()), vec!["user:1"]);
+        index.update("user:1", None);
+        assert!(index.find(&Query::parse("= c@example.com").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_index_range() {
+        let mut index = Index::new("*", "age").unwrap();
+        index.update("a", Some(&user("a", 20)));
+        index.update("b", Some(&user("b", 30)));
+        index.update("c", Some(&user("c", 40)));
+        index.update("d", Some(&Value::Object(HashMap::from([("age".to_string(), Value::String("old".to_string()))]))));
+        assert_eq!(index.find(&Query::parse("> 20").unwrap()), vec!["b", "c"]);
+        assert_eq!(index.find(&Query::parse("<= 30").unwrap()), vec!["a", "b"]);
+        assert_eq!(index.find(&Query::parse("BETWEEN 25 40").unwrap()), vec!["b", "c"]);
+        assert_eq!(index.find(&Query::parse("= 30.0").unwrap()), vec!["b"]);
+        assert!(index.find(&Query::parse("BETWEEN 40 25").unwrap()).is_empty());
+        assert!(Query::parse("> old").is_err());
+    }
+
+    #[test]
+    fn test_find_cmd() {
+        let db = Database::new(&Config::default());
+        execute(r#"SET user:"1" {"age": 20}"#, &db);
+        let body = |response: Response| response.payload.string().unwrap();
+        assert_eq!(body(index_cmd("CREATE by_age ON user:* FIELD age", &db)), "OK");
+        assert_eq!(body(find_cmd("by_age = 20", &db)), r#"["user:\"1\""]"#);
+    }
+}
@@ -0,0 +1,179 @@
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+
+use sockets::{response::Response, Client};
+
+use crate::Database;
+
+#[derive(Debug)]
+struct Lock {
+    token: u64,
+    /// Id of the connection holding the lock
+    owner: u64,
+    expires: Instant
+}
+
+/// Named locks with expiry, held by connections. Every acquired lock gets a fencing token
+/// greater than any given out before, so writes from a holder whose lock already expired can be told apart.
+///
+/// Locks live only in memory of the primary, they aren't replicated.
+#[derive(Debug, Default)]
+pub struct Locks {
+    last_token: u64,
+    locks: HashMap<String, Lock>,
+    /// Locks left after expired ones were last dropped
+    pruned: usize
+}
+
+impl Locks {
+    /// Acquires the lock unless someone holds it, returning its fencing token.
+    fn acquire(&mut self, name: &str, owner: u64, ttl: Duration, now: Instant) -> Option<u64> {
+        if self.locks.get(name).is_some_and(|lock| lock.expires > now) {
+            return None
+        }
+        self.prune(now);
+        self.last_token += 1;
+        self.locks.insert(name.to_string(), Lock { token: self.last_token, owner, expires: now + ttl });
+        Some(self.last_token)
+    }
+
+    /// Lock held with the given token, `None` if the token is stale or the lock expired.
+    fn held(&mut self, name: &str, token: u64, now: Instant) -> Option<&mut Lock> {
+        self.locks.get_mut(name).filter(|lock| lock.token == token && lock.expires > now)
+    }
+
+    fn release(&mut self, name: &str, token: u64, now: Instant) -> bool {
+        let held = self.held(name, token, now).is_some();
+        if held {
+            self.locks.remove(name);
+        }
+        held
+    }
+
+    fn extend(&mut self, name: &str, token: u64, ttl: Duration, now: Instant) -> bool {
+        match self.held(name, token, now) {
+            Some(lock) => {
+                lock.expires = now + ttl;
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Drops expired locks once the map doubled since the last time, so locks left to expire by connections
+    /// that stay open don't pile up, while pruning costs next to nothing per acquired lock.
+    fn prune(&mut self, now: Instant) {
+        if self.locks.len() >= 2 * self.pruned.max(8) {
+            self.locks.retain(|_, lock| lock.expires > now);
+            self.pruned = self.locks.len();
+        }
+    }
+
+    /// Releases every lock of the closed connection, dropping expired ones along the way.
+    pub fn release_all(&mut self, owner: u64) {
+        let now = Instant::now();
+        self.locks.retain(|_, lock| lock.owner != owner && lock.expires > now);
+    }
+}
+
+fn parse_ttl(ttl: &str) -> Option<Duration> {
+    ttl.parse().ok().filter(|ttl| *ttl > 0).map(Duration::from_millis)
+}
+
+/// `LOCK name ttl`, acquires the lock for `ttl` milliseconds and returns its fencing token.
+/// The lock is released once it expires or the connection closes.
+pub fn lock_cmd(args: &str, client: &Arc<Client>, db: &Database) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let [name, ttl] = args.as_slice() else {
+        return Response::builder().set_body("Invalid arguments")
+    };
+    let Some(ttl) = parse_ttl(ttl) else {
+        return Response::builder().set_body(format!("Invalid ttl {ttl}"))
+    };
+    match db.locks.lock().unwrap().acquire(name, client.id, ttl, Instant::now()) {
+        Some(token) => Response::builder().set_body(token.to_string()),
+        None => Response::builder().set_body("Lock is held")
+    }
+}
+
+/// `UNLOCK name token`, releases the lock if it's still held with the token.
+pub fn unlock_cmd(args: &str, db: &Database) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let [name, token] = args.as_slice() else {
+        return Response::builder().set_body("Invalid arguments")
+    };
+    let Ok(token) = token.parse() else {
+        return Response::builder().set_body(format!("Invalid token {token}"))
+    };
+    match db.locks.lock().unwrap().release(name, token, Instant::now()) {
+        true => Response::builder().set_body("OK"),
+        false => Response::builder().set_body("Lock not held")
+    }
+}
+
+/// `EXTEND name token ttl`, makes the lock still held with the token expire `ttl` milliseconds from now.
+pub fn extend_cmd(args: &str, db: &Database) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let [name, token, ttl] = args.as_slice() else {
+        return Response::builder().set_body("Invalid arguments")
+    };
+    let Ok(token) = token.parse() else {
+        return Response::builder().set_body(format!("Invalid token {token}"))
+    };
+    let Some(ttl) = parse_ttl(ttl) else {
+        return Response::builder().set_body(format!("Invalid ttl {ttl}"))
+    };
+    match db.locks.lock().unwrap().extend(name, token, ttl, Instant::now()) {
+        true => Response::builder().set_body("OK"),
+        false => Response::builder().set_body("Lock not held")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locks() {
+        let mut locks = Locks::default();
+        let now = Instant::now();
+        let ttl = Duration::from_secs(1);
+        assert_eq!(locks.acquire("a", 1, ttl, now), Some(1));
+        assert_eq!(locks.acquire("a", 2, ttl, now), None);
+        assert_eq!(locks.acquire("b", 2, ttl, now), Some(2));
+        assert!(locks.extend("a", 1, ttl, now + Duration::from_millis(900)));
+        assert_eq!(locks.acquire("a", 2, ttl, now + Duration::from_millis(1500)), None);
+        // expired lock is free to take and the old token no longer works
+        let later = now + Duration::from_secs(2);
+        assert_eq!(locks.acquire("a", 2, ttl, later), Some(3));
+        assert!(!locks.release("a", 1, later));
+        assert!(!locks.extend("a", 1, ttl, later));
+        assert!(locks.release("a", 3, later));
+        assert_eq!(locks.acquire("a", 1, ttl, later), Some(4));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut locks = Locks::default();
+        let now = Instant::now();
+        // a lock a millisecond, each expiring after ten, on a connection that never closes
+        for i in 0..1000 {
+            let at = now + Duration::from_millis(i);
+            assert!(locks.acquire(&format!("job:{i}"), 1, Duration::from_millis(10), at).is_some());
+        }
+        assert!(locks.locks.len() <= 20, "{} locks kept", locks.locks.len());
+        assert_eq!(locks.locks.values().filter(|lock| lock.expires > now + Duration::from_millis(999)).count(), 10);
+        assert_eq!(locks.acquire("job:999", 2, Duration::from_millis(10), now + Duration::from_millis(1005)), None);
+        assert_eq!(locks.acquire("job:990", 2, Duration::from_millis(10), now + Duration::from_millis(1005)), Some(1001));
+    }
+
+    #[test]
+    fn test_release_all() {
+        let mut locks = Locks::default();
+        let now = Instant::now();
+        locks.acquire("a", 1, Duration::from_secs(60), now);
+        locks.acquire("b", 2, Duration::from_secs(60), now);
+        locks.release_all(1);
+        assert_eq!(locks.acquire("a", 2, Duration::from_secs(60), now), Some(3));
+        assert_eq!(locks.acquire("b", 1, Duration::from_secs(60), now), None);
+    }
+}
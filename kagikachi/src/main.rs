@@ -1,184 +1,732 @@
-use mini_json::Value;
+mod aggregate;
+mod blocking;
+mod bulk;
+mod config;
+mod feed;
+mod fulltext;
+mod history;
+mod index;
+mod lock;
+mod pattern;
+mod projection;
+mod ratelimit;
+mod relocate;
+mod replication;
+mod scheduler;
+mod schema;
+mod script;
+mod set;
+mod sort;
+mod storage;
+mod stream;
+#[cfg(test)]
+mod testing;
+mod zset;
 
-use std::collections::HashMap;
+use mini_json::{ParseLimits, Path, Segment, Value};
 
-use sockets::{errors::SocketError, frame::DataFrame, response::Response, SocketServer, frame::Opcode};
+use std::{collections::HashMap, net::SocketAddr, sync::{Arc, Mutex, RwLock}, thread, time::{SystemTime, UNIX_EPOCH}};
 
+use sockets::{errors::SocketError, frame::DataFrame, response::Response, Client, Clients, SocketServer, frame::Opcode};
 
-fn set_cmd(args: &str, storage: &mut HashMap<String, Value>) -> Response {
-    let (key, value) = match args.split_once(" ") {
-        Some((key, value)) => (key, value),
-        None => return Response::builder().set_body("Invalid arguments")
+use blocking::{Popped, Waiters};
+use config::Config;
+use feed::Feed;
+use fulltext::FtIndexes;
+use history::History;
+use index::Indexes;
+use lock::Locks;
+use projection::Projection;
+use ratelimit::{RateLimiter, Verdict};
+use replication::Replication;
+use scheduler::Scheduler;
+use schema::{Schemas, Violation};
+use set::Operation;
+use storage::{Entry, Shard, Shards, Storage};
+
+
+struct Database {
+    storage: Storage,
+    monitors: RwLock<Vec<Feed>>,
+    replication: Mutex<Replication>,
+    indexes: RwLock<Indexes>,
+    fulltext: RwLock<FtIndexes>,
+    scripts: Mutex<HashMap<String, String>>,
+    schemas: RwLock<Schemas>,
+    history: RwLock<History>,
+    locks: Mutex<Locks>,
+    waiters: RwLock<Waiters>,
+    scheduler: Mutex<Scheduler>,
+    /// Bounds on values sent by clients
+    parse_limits: ParseLimits,
+    /// Directory of files written by `EXPORT` and read by `IMPORT`
+    dir: String,
+    limiter: RateLimiter
+}
+
+impl Database {
+    fn new(config: &Config) -> Self {
+        Self {
+            storage: Storage::new(config.shards),
+            monitors: RwLock::new(Vec::new()),
+            replication: Mutex::new(Replication::new(config.replicaof.clone())),
+            indexes: RwLock::new(Indexes::default()),
+            fulltext: RwLock::new(FtIndexes::default()),
+            scripts: Mutex::new(HashMap::new()),
+            schemas: RwLock::new(Schemas::default()),
+            history: RwLock::new(History::default()),
+            locks: Mutex::new(Locks::default()),
+            waiters: RwLock::new(Waiters::default()),
+            scheduler: Mutex::new(Scheduler::default()),
+            parse_limits: config.parse_limits,
+            dir: config.dir.clone(),
+            limiter: RateLimiter::new(config.client_limits, config.host_limits, config.max_throttled)
+        }
+    }
+
+    /// Runs mutation on the shard owning `key`. Indexes are updated and the write propagated once the shard is unlocked,
+    /// but before the next write to the shard gets to it, so replicas see writes to the same key in the order they were applied.
+    fn write<R>(&self, key: &str, message: &str, f: impl FnOnce(&mut HashMap<String, Entry>) -> R) -> R {
+        self.write_key(key, Some(message), f)
+    }
+
+    /// Same as `write`, but the command itself isn't propagated, only elements it hands to `BPOP` waiters are.
+    fn write_local<R>(&self, key: &str, f: impl FnOnce(&mut HashMap<String, Entry>) -> R) -> R {
+        self.write_key(key, None, f)
+    }
+
+    fn write_key<R>(&self, key: &str, message: Option<&str>, f: impl FnOnce(&mut HashMap<String, Entry>) -> R) -> R {
+        let mut shard = self.storage.write(key);
+        let previous = match self.history.read().unwrap().is_versioned(key) {
+            true => Some(shard.get(key).and_then(Entry::value).cloned()),
+            false => None
+        };
+        let result = f(&mut shard);
+        let popped = self.wake_waiters(key, &mut shard);
+        let value = shard.get(key).and_then(Entry::value);
+        // versions are read along with the current value, so they're recorded before anyone can see it
+        if let Some(previous) = previous {
+            self.history.write().unwrap().record(key, previous, value);
+        }
+        let indexed = self.is_indexed(key).then(|| value.cloned());
+        let after_write = self.storage.after_write(key);
+        drop(shard);
+        if let Some(value) = indexed {
+            self.reindex(key, value.as_ref());
+        }
+        self.propagate(message, &popped);
+        drop(after_write);
+        popped.into_iter().for_each(Popped::reply);
+        result
+    }
+
+    /// Same as `write`, but for commands that touch the whole storage.
+    fn write_all<R>(&self, message: &str, f: impl FnOnce(&mut Shards) -> R) -> R {
+        self.write_shards(self.storage.write_all(), message, f)
+    }
+
+    /// Same as `write`, but for commands that touch several keys.
+    fn write_keys<R>(&self, keys: &[impl AsRef<str>], message: &str, f: impl FnOnce(&mut Shards) -> R) -> R {
+        self.write_shards(self.storage.write_keys(keys), message, f)
+    }
+
+    fn write_shards<R>(&self, mut shards: Shards, message: &str, f: impl FnOnce(&mut Shards) -> R) -> R {
+        let previous: HashMap<String, Value> = {
+            let history = self.history.read().unwrap();
+            shards.documents().filter(|(key, _)| history.is_versioned(key)).map(|(key, value)| (key.clone(), value.clone())).collect()
+        };
+        let result = f(&mut shards);
+        let mut popped = Vec::new();
+        let mut indexed = Vec::new();
+        for key in shards.touched().clone() {
+            popped.extend(self.wake_waiters(&key, shards.shard_for_write(&key)));
+            let value = shards.get(&key).and_then(Entry::value);
+            if self.history.read().unwrap().is_versioned(&key) {
+                self.history.write().unwrap().record(&key, previous.get(&key).cloned(), value);
+            }
+            if self.is_indexed(&key) {
+                indexed.push((key, value.cloned()));
+            }
+        }
+        let after_write = shards.after_write();
+        drop(shards);
+        for (key, value) in indexed {
+            self.reindex(&key, value.as_ref());
+        }
+        self.propagate(Some(message), &popped);
+        drop(after_write);
+        popped.into_iter().for_each(Popped::reply);
+        result
+    }
+
+    /// Hands elements of arrays under `key` to clients blocked in `BPOP`, must be called while its shard is locked.
+    /// Waiters are only queued under the shard lock too, so nobody can start waiting on the key in between the two locks.
+    fn wake_waiters(&self, key: &str, shard: &mut Shard) -> Vec<Popped> {
+        if !self.waiters.read().unwrap().is_waiting(key) {
+            return Vec::new()
+        }
+        self.waiters.write().unwrap().serve(key, shard)
+    }
+
+    /// Sends the write to replicas, followed by removals of elements it handed to waiters.
+    fn propagate(&self, message: Option<&str>, popped: &[Popped]) {
+        let mut replication = self.replication.lock().unwrap();
+        if let Some(message) = message {
+            replication.propagate(message);
+        }
+        for popped in popped {
+            replication.propagate(&popped.message);
+        }
+    }
+
+    /// Replaces whole storage and scheduled jobs with given snapshot.
+    fn load_snapshot(&self, mut snapshot: HashMap<String, Value>) -> Result<(), String> {
+        let entries = storage::take_entries(&mut snapshot)?;
+        let jobs = scheduler::take_jobs(&mut snapshot)?;
+        if let Some(section) = snapshot.keys().next() {
+            return Err(format!("Unknown section {section}"))
+        }
+        let mut shards = self.storage.write_all();
+        // writes applied before still update indexes, they'd override the rebuilt ones
+        let _after_write = shards.after_write();
+        shards.clear();
+        for (key, entry) in entries {
+            shards.insert(key, entry);
+        }
+        self.rebuild_indexes(&shards);
+        let mut scheduler = self.scheduler.lock().unwrap();
+        scheduler.clear();
+        scheduler.restore(jobs);
+        Ok(())
+    }
+
+    /// Whether some index covers `key`, its value has to be kept for `reindex` then.
+    fn is_indexed(&self, key: &str) -> bool {
+        self.indexes.read().unwrap().covers(key) || self.fulltext.read().unwrap().covers(key)
+    }
+
+    /// Keeps indexes up to date after `key` changed, must be called before the next write to its shard gets to it.
+    fn reindex(&self, key: &str, value: Option<&Value>) {
+        self.indexes.write().unwrap().update(key, value);
+        self.fulltext.write().unwrap().update(key, value);
+    }
+
+    fn rebuild_indexes(&self, shards: &Shards) {
+        self.indexes.write().unwrap().rebuild(shards.documents());
+        self.fulltext.write().unwrap().rebuild(shards.documents());
+    }
+}
+
+
+/// Runs mutation of the document under `key`. If the key has a schema, mutation runs on a copy
+/// which replaces the document only if it still conforms, so rejected writes leave no trace.
+fn mutate_document<R>(key: &str, storage: &mut HashMap<String, Entry>, schemas: &Schemas, f: impl FnOnce(&mut Value) -> Result<R, String>) -> Result<R, Response> {
+    let value = match storage.get_mut(key).map(Entry::value_mut) {
+        Some(Ok(value)) => value,
+        Some(Err(e)) => return Err(Response::builder().set_body(e)),
+        None => return Err(Response::builder().set_body("Key not found"))
+    };
+    if !schemas.applies(key) {
+        return f(value).map_err(|e| Response::builder().set_body(e))
+    }
+    let mut copy = value.clone();
+    let result = f(&mut copy).map_err(|e| Response::builder().set_body(e))?;
+    schemas.check(key, &copy)?;
+    *value = copy;
+    Ok(result)
+}
+
+/// Sets node at `path` of the document under `key`. Missing key and parents along the path are created
+/// unless `create` is unset, in which case only existing node is replaced.
+fn set_value(key: String, path: &Path, value: Value, create: bool, storage: &mut HashMap<String, Entry>, schemas: &Schemas) -> Result<(), Response> {
+    let exists = storage.contains_key(&key);
+    if !exists && !create {
+        return Err(Response::builder().set_body("Key not found"))
+    }
+    if exists && !path.is_empty() {
+        return mutate_document(&key, storage, schemas, |val| match create {
+            true => val.set_element(path, value),
+            false => val.get_mut_element(path).map(|val| *val = value)
+        })
+    }
+    let value = Value::from_path(path, value).map_err(|e| Response::builder().set_body(e))?;
+    schemas.check(&key, &value)?;
+    storage.insert(key, Entry::Value(value));
+    Ok(())
+}
+
+/// Checks value built from stored data, eg. by `MOVE` or `SORT STORE`, before it's written at `path` of a document.
+/// Values sent by clients are checked while they're parsed, these are held to the same limits.
+fn check_limits(path: &Path, value: &Value, limits: &ParseLimits) -> Result<(), String> {
+    if path.len() + value.depth() > limits.max_depth {
+        return Err("Maximum nesting depth exceeded".to_string())
+    }
+    if limits.max_size != usize::MAX && value.serialize().len() > limits.max_size {
+        return Err("Value too large".to_string())
+    }
+    Ok(())
+}
+
+/// `SET key.path [NOCREATE] value`. Missing key and parents along the path are created, `-` appends to an array.
+/// With `NOCREATE` only nodes which already exist are replaced.
+///
+/// Path with `*` wildcards sets every node it matches, skipping ones it can't be set at, and returns their count.
+fn set_cmd(args: &str, storage: &mut HashMap<String, Entry>, schemas: &Schemas, limits: &ParseLimits) -> Response {
+    let (key, path, rest) = match parse_target(args) {
+        Ok(target) => target,
+        Err(response) => return response
     };
-    let value = match Value::deserialize(value) {
+    let (create, value) = match rest.split_once(char::is_whitespace) {
+        Some((option, value)) if option.eq_ignore_ascii_case("nocreate") => (false, value.trim_start()),
+        _ => (true, rest)
+    };
+    if value.is_empty() {
+        return Response::builder().set_body("Invalid arguments")
+    }
+    let value = match Value::deserialize_with(value, limits) {
         Ok(v) => v,
         Err(e) => return Response::builder().set_body(format!("Invalid value: {e:?}"))
     };
-    let (key, path) = match key.split_once(".") {
-        Some((key, path)) => (key, Some(path)),
-        None => (key, None)
-    };
-    match path {
-        None => {
-            storage.insert(key.to_string(), value);
-            Response::builder().set_body("OK")
-        },
-        Some(path) => {
-            match storage.get_mut(key) {
-                Some(val) => match val.get_mut_element(path) {
-                    Ok(val) => {
-                        *val = value;
-                        Response::builder().set_body("OK")
-                    },
-                    Err(e) => Response::builder().set_body(e)
-                },
-                None => Response::builder().set_body("Key not found")
-            }
+    // value is nested as deep as the path goes, so documents can't grow past the limit one SET at a time
+    if path.len() + value.depth() > limits.max_depth {
+        return Response::builder().set_body("Maximum nesting depth exceeded")
+    }
+    if !path.has_wildcard() {
+        return match set_value(key, &path, value, create, storage, schemas) {
+            Ok(()) => Response::builder().set_body("OK"),
+            Err(response) => response
         }
     }
+    let result = mutate_document(&key, storage, schemas, |val| {
+        let paths = val.expand(&path);
+        Ok(paths.iter().filter(|path| match create {
+            true => val.set_element(path, value.clone()).is_ok(),
+            false => val.get_mut_element(path).map(|val| *val = value.clone()).is_ok()
+        }).count())
+    });
+    match result {
+        Ok(count) => Response::builder().set_body(count.to_string()),
+        Err(response) => response
+    }
 }
 
-fn get_cmd(args: &str, storage: &mut HashMap<String, Value>) -> Response {
-    let (key, path) = match args.split_once(".") {
-        Some((key, path)) => (key, Some(path)),
-        None => (args, None)
+/// `GET key.path [FIELDS path,..] [EXCLUDE path,..]`, path with `*` wildcards returns array of every node it matches.
+/// `FIELDS` returns only selected parts of the value and `EXCLUDE` drops them, both applying to each matched node.
+fn get_cmd(args: &str, storage: &HashMap<String, Entry>) -> Response {
+    let (key, path, rest) = match parse_target(args) {
+        Ok(target) => target,
+        Err(response) => return response
     };
-    let value = match storage.get(key) {
-        Some(value) => match path {
-            None => value,
-            Some(path) => match value.get_element(path) {
-                Ok(value) => value,
-                Err(e) => return Response::builder().set_body(e)
-            }
+    let projection = match Projection::parse(rest) {
+        Ok(projection) => projection,
+        Err(e) => return Response::builder().set_body(e)
+    };
+    let value = match storage.get(&key) {
+        Some(Entry::Value(value)) if path.has_wildcard() => {
+            let nodes = value.expand(&path).iter().filter_map(|path| value.get_element(path).ok()).map(|node| projection.apply(node)).collect();
+            return Response::builder().set_body(Value::Array(nodes).serialize())
+        },
+        Some(Entry::Value(value)) => match value.get_element(&path) {
+            Ok(value) => value,
+            Err(e) => return Response::builder().set_body(e)
         },
+        Some(entry) => return Response::builder().set_body(format!("Invalid type: expected Value, got {}", entry.typename())),
         None => return Response::builder().set_body("Key not found")
     };
-    Response::builder().set_body(Into::<String>::into(value))
+    match projection.is_empty() {
+        true => Response::builder().set_body(Into::<String>::into(value)),
+        false => Response::builder().set_body(projection.apply(value).serialize())
+    }
 }
 
-fn del_cmd(args: &str, storage: &mut HashMap<String, Value>) -> Response {
-    let (key, path) = match args.split_once(".") {
-        Some((key, path)) => (key, Some(path)),
-        None => (args, None)
+/// `DEL key.path`, path with `*` wildcards removes every node it matches and returns their count.
+fn del_cmd(args: &str, storage: &mut HashMap<String, Entry>, schemas: &Schemas) -> Response {
+    let (key, path) = match parse_target(args) {
+        Ok((key, path, "")) => (key, path),
+        Ok(_) => return Response::builder().set_body("Invalid arguments"),
+        Err(response) => return response
     };
-    match path {
-        None => {
-            storage.remove(key);
-            Response::builder().set_body("OK")
-        }
-        Some(path) => {
-            match storage.get_mut(key) {
-                Some(val) => {
-                    let (parent, key) = match path.rsplit_once(".") {
-                        Some((parent, key)) => (parent, key),
-                        None => {
-                            match val {
-                                Value::Object(map) => {
-                                    match map.remove(key) {
-                                        Some(_) => return Response::builder().set_body("OK"),
-                                        None => return Response::builder().set_body("Key not found")
-                                    }
-                                },
-                                Value::Array(arr) => {
-                                    let index = match path.parse::<usize>() {
-                                        Ok(i) => i,
-                                        Err(_) => return Response::builder().set_body("Invalid index")
-                                    };
-                                    if index >= arr.len() {
-                                        return Response::builder().set_body("Index out of range")
-                                    }
-                                    arr.remove(index);
-                                    return Response::builder().set_body("OK")
-                                },
-                                _ => return Response::builder().set_body("Invalid type")
-                            }
-                        }
-                    };
-                    match val.get_mut_element(parent) {
-                        Ok(val) => match val {
-                            Value::Object(map) => {
-                                match map.remove(key) {
-                                    Some(_) => Response::builder().set_body("OK"),
-                                    None => Response::builder().set_body("Key not found")
-                                }
-                            },
-                            Value::Array(arr) => {
-                                let index = match key.parse::<usize>() {
-                                    Ok(i) => i,
-                                    Err(_) => return Response::builder().set_body("Invalid index")
-                                };
-                                arr.remove(index);
-                                Response::builder().set_body("OK")
-                            },
-                            _ => Response::builder().set_body("Invalid type")
-                        },
-                        Err(e) => Response::builder().set_body(e)
-                    }
-                },
-                None => {
-                    Response::builder().set_body("Key not found")
-                }
-            }
-        }
+    if path.is_empty() {
+        storage.remove(&key);
+        return Response::builder().set_body("OK")
+    }
+    let result = mutate_document(&key, storage, schemas, |val| match path.has_wildcard() {
+        // from the last one, so removed array items don't shift the ones still to be removed
+        true => Ok(val.expand(&path).iter().rev().filter(|path| val.remove_element(path).is_ok()).count().to_string()),
+        false => val.remove_element(&path).map(|_| "OK".to_string())
+    });
+    match result {
+        Ok(body) => Response::builder().set_body(body),
+        Err(response) => response
     }
 }
 
-fn dump_cmd(storage: &HashMap<String, Value>) -> Response {
-    let value = Value::Object(storage.clone());
-    Response::builder().set_body(Into::<String>::into(value))
+/// `DUMP` returns snapshot of the storage, entries grouped by their type into sections, eg. `{"values": {"key": {...}}}`,
+/// and jobs waiting to run in the `scheduled` section.
+fn dump_cmd(mut snapshot: HashMap<String, Value>, scheduler: &Mutex<Scheduler>) -> Response {
+    scheduler.lock().unwrap().save(&mut snapshot);
+    Response::builder().set_body(Value::Object(snapshot).serialize())
 }
 
-fn load_cmd(args: &str, storage: &mut HashMap<String, Value>) -> Response {
-    let value = match Value::deserialize(args) {
-        Ok(v) => v,
+/// `LOAD snapshot` writes entries of the snapshot returned by `DUMP` and schedules its jobs under new ids.
+/// Nothing is loaded unless every document conforms to its schema and every job could be scheduled.
+fn load_cmd(args: &str, storage: &mut Shards, schemas: &Schemas, limits: &ParseLimits, scheduler: &Mutex<Scheduler>) -> Response {
+    // documents sit two levels down in the snapshot, which holds every key, so only their depth is limited
+    let limits = ParseLimits { max_depth: limits.max_depth + 2, ..ParseLimits::default() };
+    let mut snapshot = match Value::deserialize_with(args, &limits) {
+        Ok(Value::Object(snapshot)) => snapshot,
+        Ok(_) => return Response::builder().set_body("Invalid type"),
         Err(e) => return Response::builder().set_body(format!("Invalid value: {e:?}"))
     };
-    match value {
-        Value::Object(map) => {
-            for (key, value) in map {
-                storage.insert(key, value);
-            }
+    let (entries, jobs) = match storage::take_entries(&mut snapshot).and_then(|entries| Ok((entries, scheduler::take_jobs(&mut snapshot)?))) {
+        Ok(taken) => taken,
+        Err(e) => return Response::builder().set_body(format!("Invalid snapshot: {e}"))
+    };
+    if let Some(section) = snapshot.keys().next() {
+        return Response::builder().set_body(format!("Invalid snapshot: Unknown section {section}"))
+    }
+    match load_entries(entries, storage, schemas) {
+        Ok(()) => {
+            scheduler.lock().unwrap().append(jobs);
             Response::builder().set_body("OK")
         },
-        _ => Response::builder().set_body("Invalid type")
+        Err(violation) => violation.into()
+    }
+}
+
+/// Inserts every entry, or none if some document doesn't conform to its schema.
+fn load_entries(mut entries: Vec<(String, Entry)>, storage: &mut Shards, schemas: &Schemas) -> Result<(), Violation> {
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (key, entry) in &entries {
+        schemas.check_entry(key, entry)?;
+    }
+    for (key, entry) in entries {
+        storage.insert(key, entry);
     }
+    Ok(())
 }
 
 fn ping_cmd() -> Response {
     Response::builder().set_body("PONG")
 }
 
-fn message_handler(msg: DataFrame, storage: &mut HashMap<String, Value>) -> Response {
-    match msg.opcode {
-        Opcode::Text => (),
-        _ => return Response::builder().set_body("Invalid message type")
+fn monitor_cmd(client: &Arc<Client>, monitors: &RwLock<Vec<Feed>>) -> Response {
+    let mut monitors = monitors.write().unwrap();
+    if !monitors.iter().any(|monitor| monitor.client.id == client.id) {
+        monitors.push(Feed::new(client.clone()));
     }
-    let message = msg.payload.string().expect("Assertion failed, check if payload was properly decoded");
+    Response::builder().set_body("OK")
+}
+
+fn client_info(client: &Client) -> String {
+    format!(
+        "id={} addr={} name={} age={} idle={} cmd={}",
+        client.id,
+        client.addr,
+        client.name().unwrap_or_default(),
+        client.age().as_secs(),
+        client.idle().as_secs(),
+        client.last_command()
+    )
+}
+
+fn client_cmd(args: &str, client: &Client, clients: &Clients) -> Response {
+    let (subcommand, args) = match args.split_once(" ") {
+        Some((subcommand, args)) => (subcommand, args.trim()),
+        None => (args, "")
+    };
+    match subcommand.to_ascii_lowercase().as_str() {
+        "list" => {
+            let list: Vec<String> = clients.list().iter().map(|c| client_info(c)).collect();
+            Response::builder().set_body(list.join("\n"))
+        },
+        "info" => Response::builder().set_body(client_info(client)),
+        "getname" => Response::builder().set_body(client.name().unwrap_or_default()),
+        "setname" => {
+            if args.contains(char::is_whitespace) {
+                return Response::builder().set_body("Client name cannot contain spaces")
+            }
+            client.set_name(if args.is_empty() { None } else { Some(args.to_string()) });
+            Response::builder().set_body("OK")
+        },
+        "kill" => {
+            let target = match args.parse::<SocketAddr>() {
+                Ok(addr) => clients.find_by_addr(addr),
+                Err(_) => match args.parse::<u64>() {
+                    Ok(id) => clients.get(id),
+                    Err(_) => return Response::builder().set_body("Invalid arguments")
+                }
+            };
+            match target {
+                Some(target) => {
+                    let _ = target.close(1000, "Killed by CLIENT KILL");
+                    Response::builder().set_body("OK")
+                },
+                None => Response::builder().set_body("No such client")
+            }
+        },
+        _ => Response::builder().set_body("Unknown subcommand")
+    }
+}
+
+/// Sends processed command to every monitoring client, dropping the ones that disconnected.
+/// Runs for every command, so monitors are only read locked and lines are queued, commands neither wait
+/// on each other here nor on a monitor that reads slowly.
+fn feed_monitors(message: &str, client: &Client, monitors: &RwLock<Vec<Feed>>) {
+    let disconnected = {
+        let monitors = monitors.read().unwrap();
+        if monitors.is_empty() {
+            return
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let line = format!("{}.{:06} [{} {}] {}", now.as_secs(), now.subsec_micros(), client.id, client.addr, message);
+        monitors.iter().filter(|monitor| !monitor.send(&line)).map(|monitor| monitor.client.id).collect::<Vec<u64>>()
+    };
+    if !disconnected.is_empty() {
+        monitors.write().unwrap().retain(|monitor| !disconnected.contains(&monitor.client.id));
+    }
+}
+
+fn is_write_command(command: &str, args: &str) -> bool {
+    match command {
+        "schema" => !schema::is_read_only(args),
+        "versioning" => !args.trim().eq_ignore_ascii_case("list"),
+        "sort" => sort::stores(args),
+        "scheduled" => !scheduler::is_read_only(args),
+        _ => matches!(command, "set" | "del" | "load" | "zadd" | "zincrby" | "zrem" | "sadd" | "srem" | "sinterstore" | "sunionstore" | "sdiffstore"
+            | "xadd" | "xtrim" | "xgroup" | "xreadgroup" | "xack" | "eval" | "evalsha" | "rollback"
+            | "rename" | "copy" | "move" | "lock" | "unlock" | "extend" | "bpop" | "schedule" | "import")
+    }
+}
+
+fn split_command(message: &str) -> (String, &str) {
     let (command, args) = match message.split_once(" ") {
         Some((command, args)) => (command, args),
-        None => (message.as_str(), "")
+        None => (message, "")
     };
+    (command.to_ascii_lowercase(), args)
+}
 
-    match command.to_ascii_lowercase().as_str() {
-        "set" => set_cmd(args, storage),
-        "get" => get_cmd(args, storage),
-        "del" => del_cmd(args, storage),
-        "dump" => dump_cmd(storage),
-        "load" => load_cmd(args, storage),
+/// Splits `key.path rest` into the key, path within its document and the rest of the arguments.
+fn parse_target(args: &str) -> Result<(String, Path, &str), Response> {
+    let (path, rest) = match Path::parse_prefix(args.trim_start()) {
+        Ok(parsed) => parsed,
+        Err(e) => return Err(Response::builder().set_body(e))
+    };
+    match path.first() {
+        Some(Segment::Key(key)) => Ok((key.clone(), path.rest(), rest.trim())),
+        _ => Err(Response::builder().set_body("Invalid arguments"))
+    }
+}
+
+/// Name of the key command operates on, used to pick the storage shard.
+fn key_of(args: &str) -> String {
+    parse_target(args).map(|(key, _, _)| key).unwrap_or_default()
+}
+
+/// Same as `key_of`, for commands whose key is not followed by path.
+fn first_arg(args: &str) -> &str {
+    nth_arg(args, 0)
+}
+
+/// Key of commands which don't start with it, eg. `XREADGROUP group consumer key`
+fn nth_arg(args: &str, n: usize) -> &str {
+    args.split_whitespace().nth(n).unwrap_or_default()
+}
+
+/// Keys of commands taking nothing but keys.
+fn all_args(args: &str) -> Vec<&str> {
+    args.split_whitespace().collect()
+}
+
+/// Executes storage command, shared by client connections and replication link.
+fn execute(message: &str, db: &Database) -> Response {
+    let (command, args) = split_command(message);
+    match command.as_str() {
+        "set" => db.write(&key_of(args), message, |shard| set_cmd(args, shard, &db.schemas.read().unwrap(), &db.parse_limits)),
+        "get" => history::get_version_cmd(args, db).unwrap_or_else(|| get_cmd(args, &db.storage.read(&key_of(args)))),
+        "del" => db.write(&key_of(args), message, |shard| del_cmd(args, shard, &db.schemas.read().unwrap())),
+        "dump" => dump_cmd(db.storage.dump(), &db.scheduler),
+        "load" => db.write_all(message, |shards| load_cmd(args, shards, &db.schemas.read().unwrap(), &db.parse_limits, &db.scheduler)),
+        "zadd" => db.write(first_arg(args), message, |shard| zset::zadd_cmd(args, shard)),
+        "zincrby" => db.write(first_arg(args), message, |shard| zset::zincrby_cmd(args, shard)),
+        "zrem" => db.write(first_arg(args), message, |shard| zset::zrem_cmd(args, shard)),
+        "zscore" => zset::zscore_cmd(args, &db.storage.read(first_arg(args))),
+        "zrank" => zset::zrank_cmd(args, &db.storage.read(first_arg(args))),
+        "zrange" => zset::zrange_cmd(args, &db.storage.read(first_arg(args))),
+        "zcard" => zset::zcard_cmd(args, &db.storage.read(first_arg(args))),
+        "sadd" => db.write(first_arg(args), message, |shard| set::sadd_cmd(args, shard)),
+        "srem" => db.write(first_arg(args), message, |shard| set::srem_cmd(args, shard)),
+        "sismember" => set::sismember_cmd(args, &db.storage.read(first_arg(args))),
+        "smembers" => set::smembers_cmd(args, &db.storage.read(first_arg(args))),
+        "scard" => set::scard_cmd(args, &db.storage.read(first_arg(args))),
+        "xadd" => db.write(first_arg(args), message, |shard| stream::xadd_cmd(args, shard, &db.parse_limits)),
+        "xtrim" => db.write(first_arg(args), message, |shard| stream::xtrim_cmd(args, shard)),
+        "xgroup" => db.write(nth_arg(args, 1), message, |shard| stream::xgroup_cmd(args, shard)),
+        "xreadgroup" => db.write(nth_arg(args, 2), message, |shard| stream::xreadgroup_cmd(args, shard)),
+        "xack" => db.write(first_arg(args), message, |shard| stream::xack_cmd(args, shard)),
+        "xlen" => stream::xlen_cmd(args, &db.storage.read(first_arg(args))),
+        "xrange" => stream::xrange_cmd(args, &db.storage.read(first_arg(args))),
+        "xpending" => stream::xpending_cmd(args, &db.storage.read(first_arg(args))),
+        "agg" => aggregate::agg_cmd(args, &db.storage.read(&key_of(args))),
+        "sort" => sort::sort(args, message, db),
+        "sinter" => set::operation_cmd(Operation::Inter, args, &db.storage.read_keys(&all_args(args))),
+        "sunion" => set::operation_cmd(Operation::Union, args, &db.storage.read_keys(&all_args(args))),
+        "sdiff" => set::operation_cmd(Operation::Diff, args, &db.storage.read_keys(&all_args(args))),
+        "sinterstore" => db.write_keys(&all_args(args), message, |shards| set::operation_store_cmd(Operation::Inter, args, shards)),
+        "sunionstore" => db.write_keys(&all_args(args), message, |shards| set::operation_store_cmd(Operation::Union, args, shards)),
+        "sdiffstore" => db.write_keys(&all_args(args), message, |shards| set::operation_store_cmd(Operation::Diff, args, shards)),
+        "rename" => db.write_keys(&relocate::keys(args), message, |shards| relocate::rename_cmd(args, shards, &db.schemas.read().unwrap())),
+        "copy" => db.write_keys(&relocate::keys(args), message, |shards| relocate::copy_cmd(args, shards, &db.schemas.read().unwrap())),
+        "move" => db.write_keys(&relocate::keys(args), message, |shards| relocate::move_cmd(args, shards, &db.schemas.read().unwrap(), &db.parse_limits)),
+        "schema" => schema::schema_cmd(args, message, db),
+        "versioning" => history::versioning_cmd(args, message, db),
+        "eval" => script::eval_cmd(args, message, db),
+        "evalsha" => script::evalsha_cmd(args, db),
+        "schedule" => scheduler::schedule_cmd(args, db),
+        "scheduled" => scheduler::scheduled_cmd(args, message, db),
         "ping" => ping_cmd(),
         _ => Response::builder().set_body("Unknown command")
     }
 }
 
+/// Same as `execute`, but on shards locked by the caller, used by scripts running many commands under one lock.
+/// Writes are neither reindexed nor propagated, that's up to the caller.
+fn execute_locked(message: &str, shards: &mut Shards, schemas: &Schemas, db: &Database) -> Response {
+    let (command, args) = split_command(message);
+    let limits = &db.parse_limits;
+    match command.as_str() {
+        "set" => set_cmd(args, shards.shard_for_write(&key_of(args)), schemas, limits),
+        "get" => get_cmd(args, shards.shard(&key_of(args))),
+        "del" => del_cmd(args, shards.shard_for_write(&key_of(args)), schemas),
+        "dump" => dump_cmd(shards.dump(), &db.scheduler),
+        "load" => load_cmd(args, shards, schemas, limits, &db.scheduler),
+        "zadd" => zset::zadd_cmd(args, shards.shard_for_write(first_arg(args))),
+        "zincrby" => zset::zincrby_cmd(args, shards.shard_for_write(first_arg(args))),
+        "zrem" => zset::zrem_cmd(args, shards.shard_for_write(first_arg(args))),
+        "zscore" => zset::zscore_cmd(args, shards.shard(first_arg(args))),
+        "zrank" => zset::zrank_cmd(args, shards.shard(first_arg(args))),
+        "zrange" => zset::zrange_cmd(args, shards.shard(first_arg(args))),
+        "zcard" => zset::zcard_cmd(args, shards.shard(first_arg(args))),
+        "sadd" => set::sadd_cmd(args, shards.shard_for_write(first_arg(args))),
+        "srem" => set::srem_cmd(args, shards.shard_for_write(first_arg(args))),
+        "sismember" => set::sismember_cmd(args, shards.shard(first_arg(args))),
+        "smembers" => set::smembers_cmd(args, shards.shard(first_arg(args))),
+        "scard" => set::scard_cmd(args, shards.shard(first_arg(args))),
+        "xadd" => stream::xadd_cmd(args, shards.shard_for_write(first_arg(args)), limits),
+        "xtrim" => stream::xtrim_cmd(args, shards.shard_for_write(first_arg(args))),
+        "xgroup" => stream::xgroup_cmd(args, shards.shard_for_write(nth_arg(args, 1))),
+        "xreadgroup" => stream::xreadgroup_cmd(args, shards.shard_for_write(nth_arg(args, 2))),
+        "xack" => stream::xack_cmd(args, shards.shard_for_write(first_arg(args))),
+        "xlen" => stream::xlen_cmd(args, shards.shard(first_arg(args))),
+        "xrange" => stream::xrange_cmd(args, shards.shard(first_arg(args))),
+        "xpending" => stream::xpending_cmd(args, shards.shard(first_arg(args))),
+        "agg" => aggregate::agg_cmd(args, shards.shard(&key_of(args))),
+        "sort" => sort::sort_cmd(args, shards, schemas, limits),
+        "sinter" => set::operation_cmd(Operation::Inter, args, shards),
+        "sunion" => set::operation_cmd(Operation::Union, args, shards),
+        "sdiff" => set::operation_cmd(Operation::Diff, args, shards),
+        "sinterstore" => set::operation_store_cmd(Operation::Inter, args, shards),
+        "sunionstore" => set::operation_store_cmd(Operation::Union, args, shards),
+        "sdiffstore" => set::operation_store_cmd(Operation::Diff, args, shards),
+        "rename" => relocate::rename_cmd(args, shards, schemas),
+        "copy" => relocate::copy_cmd(args, shards, schemas),
+        "move" => relocate::move_cmd(args, shards, schemas, limits),
+        "ping" => ping_cmd(),
+        _ => Response::builder().set_body("Unknown command")
+    }
+}
+
+fn message_handler(msg: DataFrame, db: &Database, client: &Arc<Client>, clients: &Clients) -> Option<Response> {
+    match msg.opcode {
+        Opcode::Text => (),
+        _ => return Some(Response::builder().set_body("Invalid message type"))
+    }
+    let message = msg.payload.string().expect("Assertion failed, check if payload was properly decoded");
+    match db.limiter.check(client, message.len()) {
+        Verdict::Allow => (),
+        Verdict::Throttle => return Some(Response::builder().set_body("Rate limit exceeded")),
+        Verdict::Disconnect => {
+            // 1008 is policy violation
+            let _ = client.close(1008, "Rate limit exceeded");
+            return None
+        }
+    }
+    let (command, args) = split_command(&message);
+
+    client.set_last_command(&command);
+    if command != "monitor" {
+        feed_monitors(&message, client, &db.monitors);
+    }
+
+    if is_write_command(&command, args) && db.replication.lock().unwrap().is_replica() {
+        return Some(Response::builder().set_body("READONLY You can't write against a read only replica"))
+    }
+
+    let response = match command.as_str() {
+        "monitor" => monitor_cmd(client, &db.monitors),
+        "client" => client_cmd(args, client, clients),
+        "index" => index::index_cmd(args, db),
+        "find" => index::find_cmd(args, db),
+        "ftindex" => fulltext::ftindex_cmd(args, db),
+        "ftsearch" => fulltext::ftsearch_cmd(args, db),
+        "script" => script::script_cmd(args, db),
+        "history" => history::history_cmd(args, db),
+        "rollback" => history::rollback_cmd(args, db),
+        "sync" => return replication::sync_cmd(client, db),
+        "role" => replication::role_cmd(&db.replication.lock().unwrap()),
+        "lock" => lock::lock_cmd(args, client, db),
+        "unlock" => lock::unlock_cmd(args, db),
+        "extend" => lock::extend_cmd(args, db),
+        "bpop" => return blocking::bpop_cmd(args, client, db),
+        "export" => bulk::export_cmd(args, db),
+        "import" => bulk::import_cmd(args, db),
+        _ => execute(&message, db)
+    };
+    Some(response)
+}
+
+/// Releases whatever the closed connection held.
+fn close_handler(db: &Database, client: &Arc<Client>) {
+    db.limiter.disconnect(client);
+    db.locks.lock().unwrap().release_all(client.id);
+    db.waiters.write().unwrap().remove_client(client.id);
+    db.monitors.write().unwrap().retain(|monitor| monitor.client.id != client.id);
+}
+
 fn error_handler(e: SocketError) {
     println!("Error: {e}")
 }
 
 
+fn server(config: &Config) -> SocketServer<Database> {
+    SocketServer::new(message_handler, error_handler, Database::new(config))
+        .set_address(format!("0.0.0.0:{}", config.port))
+        .set_close_handler(close_handler)
+        .set_max_frame_size(config.max_frame_size)
+}
+
+/// Serves clients along with the background work, replication, expiry of `BPOP` timeouts and scheduled jobs.
+fn run(server: &SocketServer<Database>, config: &Config) {
+    thread::scope(|s| {
+        if let Some(primary) = &config.replicaof {
+            s.spawn(move || replication::replicate(server, primary));
+        }
+        s.spawn(|| blocking::expire_waiters(server.data()));
+        s.spawn(|| scheduler::run_jobs(server.data()));
+        server.run();
+    });
+}
+
 fn main() {
-    let cache: HashMap<String, Value> = HashMap::new();
-    let server = SocketServer::new(message_handler, error_handler, cache);
-    server.run();
+    let config = match Config::from_args() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    run(&server(&config), &config);
     // json_benchmark();
 }
 
@@ -286,4 +834,134 @@ fn json_benchmark() {
         let _ = Value::deserialize(json).unwrap();
     }
     println!("My impl: {}ms", start.elapsed().as_millis());
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::{ptr, sync::mpsc, time::Duration};
+
+    use super::*;
+    use testing::{wait_until, Connection};
+
+    fn body(response: Response) -> String {
+        response.payload.string().unwrap()
+    }
+
+    fn same_shard(storage: &Storage, a: &str, b: &str) -> bool {
+        ptr::eq(&*storage.read(a), &*storage.read(b))
+    }
+
+    /// Runs commands on another thread while `guard` is held, None for those that didn't finish in time.
+    fn run_beside<G>(db: &Database, guard: G, commands: &[&str]) -> Vec<Option<String>> {
+        thread::scope(|s| {
+            let (sender, receiver) = mpsc::channel();
+            let sent: Vec<String> = commands.iter().map(|command| command.to_string()).collect();
+            s.spawn(move || for command in sent {
+                let _ = sender.send(body(execute(&command, db)));
+            });
+            let replies = commands.iter().map(|_| receiver.recv_timeout(Duration::from_secs(2)).ok()).collect();
+            drop(guard);
+            replies
+        })
+    }
+
+    #[test]
+    fn test_locking() {
+        let db = Database::new(&Config { shards: 4, ..Config::default() });
+        for command in ["SET a {\"list\": [3, 1, 2]}", "SADD s x y", "SADD t y", "SET user:1 {\"n\": 2}", "SET user:2 {\"n\": 1}"] {
+            execute(command, &db);
+        }
+        // commands reading many keys don't wait on other readers
+        let replies = run_beside(&db, db.storage.read_all(), &["GET a.list", "SINTER s t", "SORT a.list", "SORT KEYS user:* BY n"]);
+        assert_eq!(replies, vec![Some("[3, 1, 2]".to_string()), Some("[\"y\"]".to_string()), Some("[1, 2, 3]".to_string()),
+            Some("[\"user:2\", \"user:1\"]".to_string())]);
+
+        // writes only wait on their own shard
+        let other = (0..).map(|i| format!("b{i}")).find(|key| !same_shard(&db.storage, "a", key)).unwrap();
+        let replies = run_beside(&db, db.storage.write("a"), &[&format!("SET {other} 1"), "SET a 1"]);
+        assert_eq!(replies, vec![Some("OK".to_string()), None]);
+        assert_eq!(body(execute("GET a", &db)), "1");
+    }
+
+    #[test]
+    fn test_concurrent_writes() {
+        let db = Database::new(&Config { shards: 4, ..Config::default() });
+        index::index_cmd("CREATE by_n ON user:* FIELD n", &db);
+        thread::scope(|s| {
+            for t in 0..8 {
+                let db = &db;
+                s.spawn(move || for i in 0..300 {
+                    let key = format!("user:{}", (i * 7 + t * 13) % 20);
+                    let command = match i % 4 {
+                        0 => format!("DEL {key}"),
+                        1 => format!("COPY user:{} {key} REPLACE", (i + t) % 20),
+                        _ => format!("SET {key} {{\"n\": {}}}", (i + t) % 5)
+                    };
+                    execute(&command, db);
+                });
+            }
+        });
+        // every key is found under the value it ended up with, however writes to it interleaved
+        for i in 0..20 {
+            let key = format!("user:{i}");
+            let value = body(execute(&format!("GET {key}.n"), &db));
+            for n in 0..5 {
+                let found = body(index::find_cmd(&format!("by_n = {n}"), &db)).contains(&format!("\"{key}\""));
+                assert_eq!(found, value == n.to_string(), "{key} is {value}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_monitor() {
+        let (db, address) = testing::start(Config::default());
+        let mut monitor = Connection::open(&address);
+        let mut client = Connection::open(&address);
+        assert_eq!(monitor.command("MONITOR"), "OK");
+        assert_eq!(client.command("SET a 1"), "OK");
+        assert_eq!(client.command("GET a"), "1");
+        assert!(monitor.read().ends_with("] SET a 1"));
+        assert!(monitor.read().ends_with("] GET a"));
+
+        // closed monitor is forgotten right away, not on the next command
+        drop(monitor);
+        wait_until(|| db.monitors.read().unwrap().is_empty());
+        assert_eq!(client.command("GET a"), "1");
+    }
+
+    /// Measures command throughput for growing number of client threads, comparing single shard storage with the
+    /// sharded one. Run with `cargo test --release -- --ignored --nocapture test_throughput`
+    #[test]
+    #[ignore]
+    fn test_throughput() {
+        use std::time::Instant;
+        const OPERATIONS: usize = 200000;
+
+        for shards in [1, 16] {
+            let db = Database::new(&Config { shards, ..Config::default() });
+            for i in 0..1000 {
+                execute(&format!("set key{i} {{\"id\": {i}, \"tags\": [1, 2, 3]}}"), &db);
+            }
+            for threads in [1, 2, 4, 8] {
+                let start = Instant::now();
+                thread::scope(|s| {
+                    for t in 0..threads {
+                        let db = &db;
+                        s.spawn(move || {
+                            for i in 0..OPERATIONS / threads {
+                                let key = (i * 7 + t * 13) % 1000;
+                                // every 10th operation is a write
+                                if i % 10 == 0 {
+                                    execute(&format!("set key{key}.id {i}"), db);
+                                } else {
+                                    execute(&format!("get key{key}.tags"), db);
+                                }
+                            }
+                        });
+                    }
+                });
+                let elapsed = start.elapsed();
+                println!("shards: {shards:>2}, threads: {threads}: {:.0} ops/s", OPERATIONS as f64 / elapsed.as_secs_f64());
+            }
+        }
+    }
+}
@@ -0,0 +1,403 @@
+/// Upper bound on compiled program size, counted repetitions are expanded so `(a{100}){100}` grows fast.
+const MAX_PROGRAM: usize = 10_000;
+/// Upper bound on counts of `{n,m}`, empty atoms like `(){n}` compile to nothing and don't grow the program.
+const MAX_REPEAT: usize = 1000;
+
+#[derive(Clone, Debug)]
+struct Class {
+    ranges: Vec<(char, char)>,
+    negated: bool
+}
+
+impl Class {
+    fn of(ranges: &[(char, char)], negated: bool) -> Self {
+        Self { ranges: ranges.to_vec(), negated }
+    }
+
+    fn matches(&self, c: char) -> bool {
+        self.ranges.iter().any(|(from, to)| (*from..=*to).contains(&c)) != self.negated
+    }
+}
+
+const DIGIT: &[(char, char)] = &[('0', '9')];
+const WORD: &[(char, char)] = &[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')];
+const SPACE: &[(char, char)] = &[(' ', ' '), ('\t', '\r')];
+
+#[derive(Clone, Debug)]
+enum Node {
+    Char(char),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    Group(Vec<Vec<Node>>),
+    Repeat(Box<Node>, usize, Option<usize>)
+}
+
+#[derive(Clone, Debug)]
+enum Inst {
+    Char(char),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    Split(usize, usize),
+    Jump(usize),
+    Match
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn alternatives(&mut self) -> Result<Vec<Vec<Node>>, String> {
+        let mut alternatives = vec![self.sequence()?];
+        while self.eat('|') {
+            alternatives.push(self.sequence()?);
+        }
+        Ok(alternatives)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>, String> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantifier(atom)?);
+        }
+        Ok(nodes)
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        let c = self.peek().ok_or("Unexpected end of pattern")?;
+        self.position += 1;
+        match c {
+            '(' => {
+                // non-capturing groups behave the same, nothing is captured anyway
+                if self.eat('?') && !self.eat(':') {
+                    return Err("Unsupported group".to_string())
+                }
+                let alternatives = self.alternatives()?;
+                match self.eat(')') {
+                    true => Ok(Node::Group(alternatives)),
+                    false => Err("Missing )".to_string())
+                }
+            },
+            '.' => Ok(Node::Any),
+            '^' => Ok(Node::Start),
+            '$' => Ok(Node::End),
+            '[' => self.class(),
+            '\\' => self.escape(),
+            '*' | '+' | '?' => Err(format!("Nothing to repeat before {c}")),
+            c => Ok(Node::Char(c))
+        }
+    }
+
+    fn escape(&mut self) -> Result<Node, String> {
+        let c = self.peek().ok_or("Unexpected end of pattern")?;
+        self.position += 1;
+        Ok(match c {
+            'd' => Node::Class(Class::of(DIGIT, false)),
+            'D' => Node::Class(Class::of(DIGIT, true)),
+            'w' => Node::Class(Class::of(WORD, false)),
+            'W' => Node::Class(Class::of(WORD, true)),
+            's' => Node::Class(Class::of(SPACE, false)),
+            'S' => Node::Class(Class::of(SPACE, true)),
+            'n' => Node::Char('\n'),
+            't' => Node::Char('\t'),
+            c => Node::Char(c)
+        })
+    }
+
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.peek().ok_or("Missing ]")?;
+            self.position += 1;
+            let from = match c {
+                ']' if !first => return Ok(Node::Class(Class { ranges, negated })),
+                '\\' => {
+                    let escaped = self.peek().ok_or("Missing ]")?;
+                    self.position += 1;
+                    match escaped {
+                        'd' => {
+                            ranges.extend_from_slice(DIGIT);
+                            continue
+                        },
+                        'w' => {
+                            ranges.extend_from_slice(WORD);
+                            continue
+                        },
+                        's' => {
+                            ranges.extend_from_slice(SPACE);
+                            continue
+                        },
+                        'D' | 'W' | 'S' => return Err(format!("Unsupported \\{escaped} inside []")),
+                        'n' => '\n',
+                        't' => '\t',
+                        escaped => escaped
+                    }
+                },
+                c => c
+            };
+            first = false;
+            let to = match (self.peek(), self.chars.get(self.position + 1)) {
+                (Some('-'), Some(to)) if *to != ']' => {
+                    self.position += 2;
+                    *to
+                },
+                _ => from
+            };
+            if to < from {
+                return Err(format!("Invalid range {from}-{to}"))
+            }
+            ranges.push((from, to));
+        }
+    }
+
+    fn quantifier(&mut self, atom: Node) -> Result<Node, String> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => match self.counted() {
+                Some(bounds) => bounds,
+                None => return Ok(atom)
+            },
+            _ => return Ok(atom)
+        };
+        if matches!(atom, Node::Start | Node::End) {
+            return Err("Nothing to repeat".to_string())
+        }
+        if min > MAX_REPEAT || max.is_some_and(|max| max > MAX_REPEAT) {
+            return Err(format!("Repetition count is over {MAX_REPEAT}"))
+        }
+        // skip the quantifier or the closing brace of counted one
+        self.position += 1;
+        // lazy quantifiers match the same strings
+        self.eat('?');
+        Ok(Node::Repeat(Box::new(atom), min, max))
+    }
+
+    /// `{n}`, `{n,}` or `{n,m}`, anything else is taken literally. Leaves position at the closing brace.
+    fn counted(&mut self) -> Option<(usize, Option<usize>)> {
+        let rest: String = self.chars[self.position + 1..].iter().collect();
+        let end = rest.find('}')?;
+        let bounds = match rest[..end].split_once(',') {
+            None => {
+                let n = rest[..end].parse().ok()?;
+                (n, Some(n))
+            },
+            Some((min, "")) => (min.parse().ok()?, None),
+            Some((min, max)) => (min.parse().ok()?, Some(max.parse().ok()?))
+        };
+        if bounds.1.is_some_and(|max| max < bounds.0) {
+            return None
+        }
+        self.position += rest[..end].chars().count() + 1;
+        Some(bounds)
+    }
+}
+
+fn compile(nodes: &[Node], program: &mut Vec<Inst>) -> Result<(), String> {
+    for node in nodes {
+        compile_node(node, program)?;
+    }
+    Ok(())
+}
+
+fn compile_node(node: &Node, program: &mut Vec<Inst>) -> Result<(), String> {
+    if program.len() > MAX_PROGRAM {
+        return Err("Pattern is too large".to_string())
+    }
+    match node {
+        Node::Char(c) => program.push(Inst::Char(*c)),
+        Node::Any => program.push(Inst::Any),
+        Node::Class(class) => program.push(Inst::Class(class.clone())),
+        Node::Start => program.push(Inst::Start),
+        Node::End => program.push(Inst::End),
+        Node::Group(alternatives) => {
+            let mut jumps = Vec::new();
+            for (i, alternative) in alternatives.iter().enumerate() {
+                if i + 1 == alternatives.len() {
+                    compile(alternative, program)?;
+                    break
+                }
+                let split = program.len();
+                program.push(Inst::Split(split + 1, 0));
+                compile(alternative, program)?;
+                jumps.push(program.len());
+                program.push(Inst::Jump(0));
+                program[split] = Inst::Split(split + 1, program.len());
+            }
+            let end = program.len();
+            for jump in jumps {
+                program[jump] = Inst::Jump(end);
+            }
+        },
+        Node::Repeat(node, min, max) => {
+            for _ in 0..*min {
+                let start = program.len();
+                compile_node(node, program)?;
+                // empty groups compile to nothing, so repeating them would only take time
+                if program.len() == start {
+                    break
+                }
+            }
+            match max {
+                None => {
+                    let split = program.len();
+                    program.push(Inst::Split(split + 1, 0));
+                    compile_node(node, program)?;
+                    program.push(Inst::Jump(split));
+                    program[split] = Inst::Split(split + 1, program.len());
+                },
+                Some(max) => {
+                    let mut splits = Vec::new();
+                    for _ in *min..*max {
+                        splits.push(program.len());
+                        program.push(Inst::Split(0, 0));
+                        compile_node(node, program)?;
+                    }
+                    let end = program.len();
+                    for split in splits {
+                        program[split] = Inst::Split(split + 1, end);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Regular expression used by JSON Schema `pattern`. Supports literals, `.`, classes like `[a-z]` and `[^0-9]`,
+/// `\d`, `\w`, `\s` and their negations, groups with alternatives, quantifiers `*`, `+`, `?`, `{n,m}`
+/// and anchors `^` and `$`. Same as in JSON Schema, unanchored pattern may match anywhere in the string.
+///
+/// Matching simulates all alternatives at once, so it runs in linear time no matter the pattern.
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    source: String,
+    program: Vec<Inst>
+}
+
+impl Pattern {
+    pub fn new(source: &str) -> Result<Self, String> {
+        let mut parser = Parser { chars: source.chars().collect(), position: 0 };
+        let alternatives = parser.alternatives()?;
+        if parser.position != parser.chars.len() {
+            return Err("Unmatched )".to_string())
+        }
+        let mut program = Vec::new();
+        compile_node(&Node::Group(alternatives), &mut program)?;
+        program.push(Inst::Match);
+        Ok(Self { source: source.to_string(), program })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Adds thread at `pc` and every thread reachable from it without consuming input.
+    fn add_thread(&self, threads: &mut Vec<usize>, seen: &mut [bool], pc: usize, position: usize, len: usize) {
+        if seen[pc] {
+            return
+        }
+        seen[pc] = true;
+        match self.program[pc] {
+            Inst::Jump(to) => self.add_thread(threads, seen, to, position, len),
+            Inst::Split(first, second) => {
+                self.add_thread(threads, seen, first, position, len);
+                self.add_thread(threads, seen, second, position, len);
+            },
+            Inst::Start if position == 0 => self.add_thread(threads, seen, pc + 1, position, len),
+            Inst::End if position == len => self.add_thread(threads, seen, pc + 1, position, len),
+            Inst::Start | Inst::End => {},
+            _ => threads.push(pc)
+        }
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        let chars: Vec<char> = text.chars().collect();
+        let len = chars.len();
+        let mut threads = Vec::new();
+        self.add_thread(&mut threads, &mut vec![false; self.program.len()], 0, 0, len);
+        for position in 0..=len {
+            if threads.iter().any(|pc| matches!(self.program[*pc], Inst::Match)) {
+                return true
+            }
+            let Some(c) = chars.get(position) else {
+                break
+            };
+            let mut next = Vec::new();
+            let mut seen = vec![false; self.program.len()];
+            for pc in &threads {
+                let matched = match &self.program[*pc] {
+                    Inst::Char(expected) => expected == c,
+                    Inst::Any => true,
+                    Inst::Class(class) => class.matches(*c),
+                    _ => false
+                };
+                if matched {
+                    self.add_thread(&mut next, &mut seen, pc + 1, position + 1, len);
+                }
+            }
+            // unanchored search, new attempt starts at every position
+            self.add_thread(&mut next, &mut seen, 0, position + 1, len);
+            threads = next;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Pattern::new(pattern).unwrap().is_match(text)
+    }
+
+    #[test]
+    fn test_matching() {
+        assert!(matches("^[a-z0-9._%+-]+@[a-z0-9.-]+\\.[a-z]{2,}$", "john.doe@example.com"));
+        assert!(!matches("^[a-z0-9._%+-]+@[a-z0-9.-]+\\.[a-z]{2,}$", "john.doe@example"));
+        assert!(matches("^\\d{3}-\\d{4}$", "555-1234"));
+        assert!(!matches("^\\d{3}-\\d{4}$", "555-12345"));
+        assert!(matches("(cat|dog)s?", "hotdogs"));
+        assert!(!matches("^(cat|dog)s?$", "hotdogs"));
+        assert!(matches("^a(b|c)*d$", "abcbcd"));
+        assert!(matches("^colou?r$", "color"));
+        assert!(matches("^[^0-9]+$", "abc"));
+        assert!(!matches("^[^0-9]+$", "a1c"));
+        assert!(matches("", "anything"));
+        assert!(matches("^(a*)*$", &"a".repeat(10000)));
+        assert!(!matches("^(a*)*b$", &"a".repeat(10000)));
+        assert!(matches("^((((){1000}){1000}){1000})a$", "a"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        for pattern in ["(ab", "ab)", "*a", "[a-", "[z-a]", "(a{100}){1000}", "(){18446744073709551615}", "a{1001}", "a{0,1001}"] {
+            assert!(Pattern::new(pattern).is_err(), "{pattern}");
+        }
+    }
+}
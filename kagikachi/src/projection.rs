@@ -0,0 +1,108 @@
+use mini_json::{Path, Value};
+
+/// `[FIELDS path,..] [EXCLUDE path,..]` options of `GET`, limiting which parts of the value are returned.
+#[derive(Debug, Default, PartialEq)]
+pub struct Projection {
+    fields: Option<Vec<Path>>,
+    exclude: Vec<Path>
+}
+
+impl Projection {
+    pub fn parse(args: &str) -> Result<Self, String> {
+        let mut projection = Projection::default();
+        let mut rest = args.trim_start();
+        while !rest.is_empty() {
+            let (option, paths) = rest.split_once(char::is_whitespace).ok_or("Invalid arguments")?;
+            let (paths, remaining) = split_paths(paths.trim_start());
+            if paths.is_empty() {
+                return Err("Invalid arguments".to_string())
+            }
+            let paths = paths.into_iter().filter(|path| !path.is_empty()).map(Path::parse).collect::<Result<Vec<Path>, String>>()?;
+            match option.to_ascii_lowercase().as_str() {
+                "fields" => projection.fields = Some(paths),
+                "exclude" => projection.exclude.extend(paths),
+                _ => return Err("Invalid arguments".to_string())
+            }
+            rest = remaining.trim_start();
+        }
+        Ok(projection)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_none() && self.exclude.is_empty()
+    }
+
+    /// Keeps only selected fields, if any, then drops excluded ones. Paths may use wildcards.
+    pub fn apply(&self, value: &Value) -> Value {
+        let mut value = match &self.fields {
+            Some(fields) => value.project(fields),
+            None => value.clone()
+        };
+        for path in &self.exclude {
+            // from the last one, so removed array items don't shift the ones still to be removed
+            for path in value.expand(path).iter().rev() {
+                let _ = value.remove_element(path);
+            }
+        }
+        value
+    }
+}
+
+/// Splits comma separated paths up to the first whitespace, returning them with the rest of `args`.
+/// Commas and whitespace inside quoted segments like `a["b, c"]`, or escaped, don't separate paths.
+fn split_paths(args: &str) -> (Vec<&str>, &str) {
+    let mut paths = Vec::new();
+    let mut start = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut previous = None;
+    for (i, c) in args.char_indices() {
+        if escaped {
+            escaped = false;
+            previous = None;
+            continue
+        }
+        match (c, quote) {
+            ('\\', _) => escaped = true,
+            (c, Some(open)) if c == open => quote = None,
+            (_, Some(_)) => (),
+            ('"' | '\'', None) if previous == Some('[') => quote = Some(c),
+            (',', None) => {
+                paths.push(&args[start..i]);
+                start = i + 1;
+            },
+            (c, None) if c.is_whitespace() => {
+                paths.push(&args[start..i]);
+                return (paths, &args[i..])
+            },
+            _ => ()
+        }
+        previous = Some(c);
+    }
+    if !args.is_empty() {
+        paths.push(&args[start..]);
+    }
+    (paths, "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_projection() {
+        let value = Value::deserialize(r#"{"name": "a", "avatar": "...", "posts": [{"title": "t", "body": "..."}], "meta": {"id": 1}}"#).unwrap();
+        let apply = |args: &str| Projection::parse(args).unwrap().apply(&value);
+        assert_eq!(apply("FIELDS name,posts[0].title"), Value::deserialize(r#"{"name": "a", "posts": [{"title": "t"}]}"#).unwrap());
+        assert_eq!(apply("EXCLUDE avatar,posts.*.body,meta"), Value::deserialize(r#"{"name": "a", "posts": [{"title": "t"}]}"#).unwrap());
+        assert_eq!(apply("FIELDS name,meta EXCLUDE meta.id"), Value::deserialize(r#"{"name": "a", "meta": {}}"#).unwrap());
+        // commas and spaces inside quoted segments are part of the key
+        let value = Value::deserialize(r#"{"a": {"x,y": 1, "b c": 2, "d": 3}, "e": 4}"#).unwrap();
+        let apply = |args: &str| Projection::parse(args).unwrap().apply(&value);
+        assert_eq!(apply(r#"FIELDS a["x,y"],a["b c"],e EXCLUDE e"#), Value::deserialize(r#"{"a": {"x,y": 1, "b c": 2}}"#).unwrap());
+        assert_eq!(apply(r#"EXCLUDE a['x,y'],a.b\ c"#), Value::deserialize(r#"{"a": {"d": 3}, "e": 4}"#).unwrap());
+        assert!(Projection::parse(r#"FIELDS a["b,c"#).is_err());
+        assert!(Projection::parse("FIELDS").is_err());
+        assert!(Projection::parse("ONLY a").is_err());
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::Instant
+};
+
+use sockets::Client;
+
+/// Commands and bytes allowed per second, zero meaning unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    pub commands: u32,
+    pub bytes: u32
+}
+
+/// Token bucket holding up to one second worth of tokens.
+/// Request is let through while any tokens are left and may overdraw the bucket,
+/// so a frame bigger than the limit gets through once and the debt is paid off afterwards.
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant
+}
+
+impl Bucket {
+    fn new(rate: u32, now: Instant) -> Self {
+        Self { rate: rate as f64, tokens: rate as f64, updated: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.rate
+    }
+
+    fn allows(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.rate == 0.0 || self.tokens > 0.0
+    }
+
+    fn take(&mut self, amount: usize) {
+        if self.rate > 0.0 {
+            self.tokens -= amount as f64;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    commands: Bucket,
+    bytes: Bucket
+}
+
+impl Buckets {
+    fn new(limits: Limits, now: Instant) -> Self {
+        Self { commands: Bucket::new(limits.commands, now), bytes: Bucket::new(limits.bytes, now) }
+    }
+
+    fn allows(&mut self, now: Instant) -> bool {
+        // both are refilled, even if the first one already refuses
+        let commands = self.commands.allows(now);
+        self.bytes.allows(now) && commands
+    }
+
+    fn take(&mut self, bytes: usize) {
+        self.commands.take(1);
+        self.bytes.take(bytes);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    Throttle,
+    /// Client was throttled too many times in a row
+    Disconnect
+}
+
+/// Limits commands of every connection, and of all connections from the same host together,
+/// since clients don't authenticate and the host is the closest thing to a user there is.
+pub struct RateLimiter {
+    client: Limits,
+    host: Limits,
+    /// Consecutive throttled commands after which the client is disconnected, zero meaning never
+    max_throttled: u32,
+    /// client id -> buckets and number of consecutive throttled commands
+    clients: Mutex<HashMap<u64, (Buckets, u32)>>,
+    hosts: Mutex<HashMap<IpAddr, Buckets>>
+}
+
+impl RateLimiter {
+    pub fn new(client: Limits, host: Limits, max_throttled: u32) -> Self {
+        Self { client, host, max_throttled, clients: Mutex::default(), hosts: Mutex::default() }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.client != Limits::default() || self.host != Limits::default()
+    }
+
+    /// Accounts command of `bytes` sent by `client`. Throttled commands don't use up any tokens.
+    pub fn check(&self, client: &Client, bytes: usize) -> Verdict {
+        self.check_at(client.id, client.addr.ip(), bytes, Instant::now())
+    }
+
+    fn check_at(&self, id: u64, host: IpAddr, bytes: usize, now: Instant) -> Verdict {
+        if !self.is_enabled() {
+            return Verdict::Allow
+        }
+        let mut clients = self.clients.lock().unwrap();
+        let mut hosts = self.hosts.lock().unwrap();
+        let (client_buckets, throttled) = clients.entry(id).or_insert_with(|| (Buckets::new(self.client, now), 0));
+        let host_buckets = hosts.entry(host).or_insert_with(|| Buckets::new(self.host, now));
+        if client_buckets.allows(now) && host_buckets.allows(now) {
+            client_buckets.take(bytes);
+            host_buckets.take(bytes);
+            *throttled = 0;
+            return Verdict::Allow
+        }
+        *throttled += 1;
+        match self.max_throttled {
+            max if max > 0 && *throttled >= max => Verdict::Disconnect,
+            _ => Verdict::Throttle
+        }
+    }
+
+    /// Forgets disconnected client. Host buckets are dropped once they're full again,
+    /// so hosts can't reset their limit by reconnecting.
+    pub fn disconnect(&self, client: &Client) {
+        self.clients.lock().unwrap().remove(&client.id);
+        let now = Instant::now();
+        self.hosts.lock().unwrap().retain(|_, buckets| {
+            buckets.commands.refill(now);
+            buckets.bytes.refill(now);
+            !(buckets.commands.is_full() && buckets.bytes.is_full())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use super::*;
+
+    const HOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn test_client_limit() {
+        let limiter = RateLimiter::new(Limits { commands: 2, bytes: 100 }, Limits::default(), 3);
+        let now = Instant::now();
+        assert_eq!(limiter.check_at(1, HOST, 10, now), Verdict::Allow);
+        assert_eq!(limiter.check_at(1, HOST, 10, now), Verdict::Allow);
+        assert_eq!(limiter.check_at(1, HOST, 10, now), Verdict::Throttle);
+        // other connection has its own bucket
+        assert_eq!(limiter.check_at(2, HOST, 10, now), Verdict::Allow);
+        let later = now + Duration::from_millis(500);
+        assert_eq!(limiter.check_at(1, HOST, 10, later), Verdict::Allow);
+        assert_eq!(limiter.check_at(1, HOST, 10, later), Verdict::Throttle);
+        assert_eq!(limiter.check_at(1, HOST, 10, later), Verdict::Throttle);
+        assert_eq!(limiter.check_at(1, HOST, 10, later), Verdict::Disconnect);
+    }
+
+    #[test]
+    fn test_bytes_and_host_limit() {
+        let limiter = RateLimiter::new(Limits { commands: 0, bytes: 100 }, Limits { commands: 3, bytes: 0 }, 0);
+        let now = Instant::now();
+        // oversized frame goes through, but has to be paid off
+        assert_eq!(limiter.check_at(1, HOST, 250, now), Verdict::Allow);
+        assert_eq!(limiter.check_at(1, HOST, 1, now + Duration::from_secs(1)), Verdict::Throttle);
+        assert_eq!(limiter.check_at(1, HOST, 1, now + Duration::from_secs(3)), Verdict::Allow);
+        assert_eq!(limiter.check_at(2, HOST, 1, now + Duration::from_secs(3)), Verdict::Allow);
+        assert_eq!(limiter.check_at(3, HOST, 1, now + Duration::from_secs(3)), Verdict::Allow);
+        // three commands per second from the host, whichever connection sends them
+        assert_eq!(limiter.check_at(3, HOST, 1, now + Duration::from_secs(3)), Verdict::Throttle);
+        assert_eq!(limiter.check_at(3, IpAddr::V4(Ipv4Addr::UNSPECIFIED), 1, now + Duration::from_secs(3)), Verdict::Allow);
+    }
+}
@@ -0,0 +1,267 @@
+use mini_json::{ParseLimits, Path, Segment, Value};
+use sockets::response::Response;
+
+use crate::{check_limits, parse_target, schema::Schemas, storage::{Entry, Shards}};
+
+/// Source or destination of a relocation, a key and path within its document.
+type Target = (String, Path);
+
+/// Keys whose shards have to be locked, for `RENAME`, `COPY` and `MOVE` arguments.
+pub fn keys(args: &str) -> Vec<String> {
+    match parse_args(args) {
+        Ok(((source, _), (destination, _), _)) => vec![source, destination],
+        Err(_) => Vec::new()
+    }
+}
+
+/// Splits arguments into source, destination and the `REPLACE` flag.
+fn parse_args(args: &str) -> Result<(Target, Target, bool), Response> {
+    let (source_key, source_path, rest) = parse_target(args)?;
+    let (destination_key, destination_path, rest) = parse_target(rest)?;
+    let replace = match rest {
+        "" => false,
+        replace if replace.eq_ignore_ascii_case("replace") => true,
+        _ => return Err(Response::builder().set_body("Invalid arguments"))
+    };
+    Ok(((source_key, source_path), (destination_key, destination_path), replace))
+}
+
+/// Keys, unlike paths, can't be relocated within a document.
+fn parse_keys(args: &str) -> Result<(String, String, bool), Response> {
+    match parse_args(args)? {
+        ((source, source_path), (destination, destination_path), replace) if source_path.is_empty() && destination_path.is_empty() =>
+            Ok((source, destination, replace)),
+        _ => Err(Response::builder().set_body("Invalid arguments"))
+    }
+}
+
+/// Places `node` at non-empty `path` of `value`. Existing node is only replaced if `replace` is set,
+/// index one past the end of an array appends.
+fn attach(value: &mut Value, path: &Path, node: Value, replace: bool) -> Result<(), String> {
+    let (Some(parent), Some(last)) = (path.parent(), path.last()) else {
+        return Err("Invalid path".to_string())
+    };
+    match (value.get_mut_element(&parent)?, last) {
+        (Value::Object(map), Segment::Key(key)) => {
+            if map.contains_key(key) && !replace {
+                return Err("Destination path already exists".to_string())
+            }
+            map.insert(key.clone(), node);
+        },
+        (Value::Array(arr), last) => match last.index(arr.len())? {
+            i if i < arr.len() && !replace => return Err("Destination path already exists".to_string()),
+            i if i < arr.len() => arr[i] = node,
+            i if i == arr.len() => arr.push(node),
+            i => return Err(format!("Index {i} out of range"))
+        },
+        _ => return Err("Invalid type: expected Object or Array".to_string())
+    }
+    Ok(())
+}
+
+/// Stores `entry` under `destination`, honouring the `REPLACE` flag and the destination's schema.
+fn store(destination: &str, entry: Entry, replace: bool, shards: &mut Shards, schemas: &Schemas) -> Result<(), Response> {
+    if shards.get(destination).is_some() && !replace {
+        return Err(Response::builder().set_body("Destination key already exists"))
+    }
+    if let Err(violation) = schemas.check_entry(destination, &entry) {
+        return Err(violation.into())
+    }
+    shards.insert(destination.to_string(), entry);
+    Ok(())
+}
+
+/// `RENAME source destination [REPLACE]`, works for every entry type
+pub fn rename_cmd(args: &str, shards: &mut Shards, schemas: &Schemas) -> Response {
+    let (source, destination, replace) = match parse_keys(args) {
+        Ok(args) => args,
+        Err(response) => return response
+    };
+    let Some(entry) = shards.get(&source).cloned() else {
+        return Response::builder().set_body("Key not found")
+    };
+    if source == destination {
+        return Response::builder().set_body("OK")
+    }
+    match store(&destination, entry, replace, shards, schemas) {
+        Ok(()) => {
+            shards.remove(&source);
+            Response::builder().set_body("OK")
+        },
+        Err(response) => response
+    }
+}
+
+/// `COPY source destination [REPLACE]`, works for every entry type
+pub fn copy_cmd(args: &str, shards: &mut Shards, schemas: &Schemas) -> Response {
+    let (source, destination, replace) = match parse_keys(args) {
+        Ok(args) => args,
+        Err(response) => return response
+    };
+    let Some(entry) = shards.get(&source).cloned() else {
+        return Response::builder().set_body("Key not found")
+    };
+    if source == destination {
+        return Response::builder().set_body("Source and destination are the same key")
+    }
+    match store(&destination, entry, replace, shards, schemas) {
+        Ok(()) => Response::builder().set_body("OK"),
+        Err(response) => response
+    }
+}
+
+fn document(key: &str, shards: &Shards) -> Result<Option<Value>, String> {
+    match shards.get(key) {
+        Some(Entry::Value(value)) => Ok(Some(value.clone())),
+        Some(entry) => Err(format!("Invalid type: expected Value, got {}", entry.typename())),
+        None => Ok(None)
+    }
+}
+
+/// `MOVE key.path key2.path [REPLACE]` detaches node from one document and attaches it to another, or elsewhere in the same one.
+/// Path-less source moves the whole document and path-less destination stores the node as a new document.
+///
+/// Both documents are changed on copies, so nothing is written unless every step, including schema and limit checks, succeeds.
+pub fn move_cmd(args: &str, shards: &mut Shards, schemas: &Schemas, limits: &ParseLimits) -> Response {
+    let ((source_key, source_path), (destination_key, destination_path), replace) = match parse_args(args) {
+        Ok(args) => args,
+        Err(response) => return response
+    };
+    if destination_key == source_key && destination_path.starts_with(&source_path) {
+        return Response::builder().set_body("Cannot move node into itself")
+    }
+    let mut documents = Vec::new();
+    let result = (|| {
+        let mut source_document = document(&source_key, shards)?.ok_or("Key not found")?;
+        let node = match source_path.is_empty() {
+            true => std::mem::replace(&mut source_document, Value::Null),
+            false => source_document.remove_element(&source_path)?
+        };
+        check_limits(&destination_path, &node, limits)?;
+        let source_document = (!source_path.is_empty()).then_some(source_document);
+        let mut destination_document = match destination_key == source_key {
+            true => source_document.clone(),
+            false => {
+                documents.push((source_key.as_str(), source_document));
+                document(&destination_key, shards)?
+            }
+        };
+        match (destination_path.is_empty(), destination_document.as_mut()) {
+            (true, Some(_)) if !replace => return Err("Destination key already exists".to_string()),
+            (true, _) => destination_document = Some(node),
+            (false, Some(document)) => attach(document, &destination_path, node, replace)?,
+            (false, None) => return Err("Key not found".to_string())
+        }
+        documents.push((destination_key.as_str(), destination_document));
+        Ok(())
+    })();
+    if let Err(e) = result {
+        return Response::builder().set_body(e)
+    }
+    for (key, document) in &documents {
+        if let Some(document) = document {
+            if let Err(violation) = schemas.check(key, document) {
+                return violation.into()
+            }
+        }
+    }
+    for (key, document) in documents {
+        match document {
+            Some(document) => shards.insert(key.to_string(), Entry::Value(document)),
+            None => shards.remove(key)
+        };
+    }
+    Response::builder().set_body("OK")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{schema::schema_cmd, storage::Storage, Config, Database};
+
+    fn get(shards: &Shards, key: &str) -> Option<String> {
+        shards.get(key).and_then(Entry::value).map(Value::serialize)
+    }
+
+    #[test]
+    fn test_move() {
+        let storage = Storage::new(4);
+        let mut shards = storage.write_all();
+        let schemas = Schemas::default();
+        let limits = ParseLimits::default();
+        shards.insert("a".to_string(), Entry::Value(Value::deserialize(r#"{"x": {"y": [1, 2]}, "z": 1}"#).unwrap()));
+        shards.insert("b".to_string(), Entry::Value(Value::deserialize(r#"{"list": []}"#).unwrap()));
+        let body = |response: Response| response.payload.string().unwrap();
+
+        assert_eq!(body(move_cmd("a.x.y.0 b.list.0", &mut shards, &schemas, &limits)), "OK");
+        assert_eq!(body(move_cmd("a.z b.list.0", &mut shards, &schemas, &limits)), "Destination path already exists");
+        assert_eq!(body(move_cmd("a.z b.list.5", &mut shards, &schemas, &limits)), "Index 5 out of range");
+        assert_eq!(body(move_cmd("a.x a.x.w", &mut shards, &schemas, &limits)), "Cannot move node into itself");
+        assert_eq!(body(move_cmd("a.x a.w", &mut shards, &schemas, &limits)), "OK");
+        assert_eq!(shards.get("a").and_then(Entry::value).and_then(|a| a.get_element(&Path::parse("w.y[0]").unwrap()).ok()), Some(&Value::Integer(2)));
+        assert_eq!(get(&shards, "b"), Some("{\"list\": [1]}".to_string()));
+
+        assert_eq!(body(move_cmd("a c", &mut shards, &schemas, &limits)), "OK");
+        assert!(shards.get("a").is_none());
+        assert_eq!(body(move_cmd("c.z b.list.1", &mut shards, &schemas, &limits)), "OK");
+        assert_eq!(get(&shards, "b"), Some("{\"list\": [1, 1]}".to_string()));
+    }
+
+    #[test]
+    fn test_rename_and_copy() {
+        let db = Database::new(&Config::default());
+        let body = |response: Response| response.payload.string().unwrap();
+        assert_eq!(body(schema_cmd(r#"SET user:* {"type": "object", "required": ["name"]}"#, "", &db)), "OK");
+        let schemas = db.schemas.read().unwrap();
+        let storage = Storage::new(4);
+        let mut shards = storage.write_all();
+        shards.insert("a".to_string(), Entry::Value(Value::deserialize(r#"{"name": "a"}"#).unwrap()));
+        shards.insert("b".to_string(), Entry::Value(Value::Integer(1)));
+        shards.insert("s".to_string(), Entry::Set(["x".to_string()].into()));
+
+        assert_eq!(body(rename_cmd("a b", &mut shards, &schemas)), "Destination key already exists");
+        assert_eq!(body(copy_cmd("a b", &mut shards, &schemas)), "Destination key already exists");
+        assert_eq!(get(&shards, "b"), Some("1".to_string()));
+        // REPLACE overwrites entries of another type
+        assert_eq!(body(copy_cmd("s b REPLACE", &mut shards, &schemas)), "OK");
+        assert!(matches!(shards.get("b"), Some(Entry::Set(_))));
+        assert_eq!(body(rename_cmd("a b REPLACE", &mut shards, &schemas)), "OK");
+        assert_eq!(get(&shards, "b"), Some(r#"{"name": "a"}"#.to_string()));
+        assert!(shards.get("a").is_none());
+
+        assert_eq!(body(rename_cmd("b b", &mut shards, &schemas)), "OK");
+        assert_eq!(get(&shards, "b"), Some(r#"{"name": "a"}"#.to_string()));
+        assert_eq!(body(copy_cmd("b b", &mut shards, &schemas)), "Source and destination are the same key");
+        assert_eq!(body(rename_cmd("missing c", &mut shards, &schemas)), "Key not found");
+
+        // destination's schema applies, the source is left in place
+        shards.insert("c".to_string(), Entry::Value(Value::deserialize(r#"{"id": 1}"#).unwrap()));
+        let message = |response: Response| Value::deserialize(&body(response)).ok()?.object().ok()?.get("message").cloned();
+        let missing = Some(Value::from_text("missing required field name"));
+        assert_eq!(message(rename_cmd("c user:1", &mut shards, &schemas)), missing);
+        assert_eq!(message(copy_cmd("c user:1", &mut shards, &schemas)), missing);
+        assert!(shards.get("user:1").is_none() && shards.get("c").is_some());
+        assert_eq!(body(copy_cmd("b user:1", &mut shards, &schemas)), "OK");
+    }
+
+    #[test]
+    fn test_move_limits() {
+        let storage = Storage::new(4);
+        let mut shards = storage.write_all();
+        let schemas = Schemas::default();
+        let limits = ParseLimits { max_depth: 3, max_size: 16, ..ParseLimits::default() };
+        let body = |response: Response| response.payload.string().unwrap();
+        for key in ["a", "b", "c"] {
+            shards.insert(key.to_string(), Entry::Value(Value::deserialize(r#"{"x": {"y": 1}}"#).unwrap()));
+        }
+        // each move nests the document one level deeper, until it's refused
+        assert_eq!(body(move_cmd("a b.x.z", &mut shards, &schemas, &limits)), "Maximum nesting depth exceeded");
+        assert_eq!(body(move_cmd("a b.z", &mut shards, &schemas, &limits)), "OK");
+        assert_eq!(body(move_cmd("b c.z", &mut shards, &schemas, &limits)), "Maximum nesting depth exceeded");
+        assert_eq!(shards.get("b").and_then(Entry::value).map(Value::depth), Some(3));
+        assert!(shards.get("a").is_none());
+        shards.insert("d".to_string(), Entry::Value(Value::deserialize(r#"{"s": "a rather long string"}"#).unwrap()));
+        assert_eq!(body(move_cmd("d.s c.v", &mut shards, &schemas, &limits)), "Value too large");
+        assert_eq!(get(&shards, "d"), Some(r#"{"s": "a rather long string"}"#.to_string()));
+    }
+}
@@ -0,0 +1,176 @@
+use std::{collections::HashMap, sync::Arc, thread, time::Duration};
+
+use mini_json::Value;
+use sockets::{errors::SocketError, frame::DataFrame, response::Response, Client, SocketClient, SocketServer};
+
+use crate::{execute, feed::Feed, Database};
+
+pub enum Role {
+    Primary,
+    Replica { primary: String }
+}
+
+#[derive(Clone, Copy)]
+pub enum LinkState {
+    Connecting,
+    Syncing,
+    Connected
+}
+
+impl LinkState {
+    fn name(&self) -> &'static str {
+        match self {
+            LinkState::Connecting => "connecting",
+            LinkState::Syncing => "sync",
+            LinkState::Connected => "connected"
+        }
+    }
+}
+
+pub struct Replication {
+    role: Role,
+    link: LinkState,
+    replicas: Vec<Feed>
+}
+
+impl Replication {
+    pub fn new(replicaof: Option<String>) -> Self {
+        Self {
+            role: match replicaof {
+                Some(primary) => Role::Replica { primary },
+                None => Role::Primary
+            },
+            link: LinkState::Connecting,
+            replicas: Vec::new()
+        }
+    }
+
+    pub fn is_replica(&self) -> bool {
+        matches!(self.role, Role::Replica { .. })
+    }
+
+    /// Queues mutating command for every connected replica, dropping the ones that disconnected or fell behind.
+    pub fn propagate(&mut self, message: &str) {
+        self.replicas.retain(|replica| replica.send(message));
+    }
+}
+
+pub fn sync_cmd(client: &Arc<Client>, db: &Database) -> Option<Response> {
+    // storage stays locked until replica is registered, so no write can slip between snapshot and propagation,
+    // and writes already applied but not yet propagated are waited for, the snapshot has them already
+    let shards = db.storage.write_all();
+    let _after_write = shards.after_write();
+    let mut replication = db.replication.lock().unwrap();
+    if replication.is_replica() {
+        return Some(Response::builder().set_body("Chained replication is not supported"))
+    }
+    let mut snapshot = shards.dump();
+    db.scheduler.lock().unwrap().save(&mut snapshot);
+    // the snapshot is queued ahead of the writes, like them it's sent once locks are released
+    let replica = Feed::new(client.clone());
+    replica.send(&Value::Object(snapshot).serialize());
+    // schemas decide which writes fail and versioning what gets recorded, replica needs them to apply the stream the same way
+    let commands = db.schemas.read().unwrap().commands().into_iter()
+        .chain(db.history.read().unwrap().commands());
+    for command in commands {
+        replica.send(&command);
+    }
+    replication.replicas.push(replica);
+    None
+}
+
+pub fn role_cmd(replication: &Replication) -> Response {
+    let role = match &replication.role {
+        Role::Primary => Value::Object(HashMap::from([
+            ("role".to_string(), Value::String("primary".to_string())),
+            ("replicas".to_string(), Value::Array(replication.replicas.iter().map(|Feed { client: replica, .. }| {
+                Value::Object(HashMap::from([
+                    ("id".to_string(), Value::Integer(replica.id as isize)),
+                    ("addr".to_string(), Value::String(replica.addr.to_string()))
+                ]))
+            }).collect()))
+        ])),
+        Role::Replica { primary } => Value::Object(HashMap::from([
+            ("role".to_string(), Value::String("replica".to_string())),
+            ("primary".to_string(), Value::String(primary.clone())),
+            ("state".to_string(), Value::String(replication.link.name().to_string()))
+        ]))
+    };
+    Response::builder().set_body(role.serialize())
+}
+
+/// Keeps replica in sync with its primary, reconnecting whenever the link breaks.
+pub fn replicate(server: &SocketServer<Database>, primary: &str) {
+    loop {
+        server.data().replication.lock().unwrap().link = LinkState::Connecting;
+        if let Err(e) = follow(server, primary) {
+            println!("Replication link to {primary} broken: {e}");
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+fn follow(server: &SocketServer<Database>, primary: &str) -> Result<(), SocketError> {
+    let mut conn = SocketClient::connect(primary)?;
+    conn.send(Response::builder().set_body("SYNC"))?;
+    let db = server.data();
+    db.replication.lock().unwrap().link = LinkState::Syncing;
+
+    let snapshot = match Value::deserialize(&text(conn.read()?)?) {
+        Ok(Value::Object(map)) => map,
+        _ => return Err(SocketError::InvalidFrame)
+    };
+    db.schemas.write().unwrap().clear();
+    db.history.write().unwrap().clear();
+    db.load_snapshot(snapshot).map_err(|_| SocketError::InvalidFrame)?;
+    db.replication.lock().unwrap().link = LinkState::Connected;
+
+    loop {
+        let message = text(conn.read()?)?;
+        execute(&message, db);
+    }
+}
+
+fn text(frame: DataFrame) -> Result<String, SocketError> {
+    frame.payload.string().ok_or(SocketError::InvalidFrame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, testing::{self, wait_until, Connection}};
+
+    fn get(key: &str, db: &Database) -> String {
+        execute(&format!("GET {key}"), db).payload.string().unwrap()
+    }
+
+    #[test]
+    fn test_replication() {
+        let (primary_db, primary) = testing::start(Config::default());
+        let mut client = Connection::open(&primary);
+        // written before the replica connects, so it arrives with the snapshot
+        assert_eq!(client.command("SET a {\"n\": 1}"), "OK");
+        assert_eq!(client.command("SCHEDULE AT 4102444800000 DEL a"), "1");
+
+        let (replica_db, replica) = testing::start(Config { replicaof: Some(primary.clone()), ..Config::default() });
+        wait_until(|| matches!(replica_db.replication.lock().unwrap().link, LinkState::Connected));
+        let mut replica_client = Connection::open(&replica);
+        let role = replica_client.command("ROLE");
+        assert!(role.contains("\"role\": \"replica\"") && role.contains("\"state\": \"connected\""), "{role}");
+        assert!(client.command("ROLE").contains("\"replicas\": [{"));
+        assert_eq!(get("a", replica_db), "{\"n\": 1}");
+        assert!(replica_client.command("SCHEDULED LIST").contains("\"command\": \"DEL a\""));
+
+        // writes after the snapshot are propagated, in order
+        assert_eq!(client.command("SET b 1"), "OK");
+        assert_eq!(client.command("SET a.n 2"), "OK");
+        assert_eq!(client.command("DEL b"), "OK");
+        wait_until(|| get("a", replica_db) == "{\"n\": 2}");
+        assert_eq!(get("b", replica_db), "Key not found");
+        assert_eq!(get("a", primary_db), get("a", replica_db));
+
+        // replica only takes writes from its primary
+        assert_eq!(replica_client.command("SET c 1"), "READONLY You can't write against a read only replica");
+        assert_eq!(replica_client.command("GET c"), "Key not found");
+    }
+}
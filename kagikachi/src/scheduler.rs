@@ -0,0 +1,316 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH}
+};
+
+use mini_json::Value;
+use sockets::response::Response;
+
+use crate::{execute, is_write_command, split_command, Database};
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// Snapshot section holding the jobs, next to the entry sections.
+const SECTION: &str = "scheduled";
+
+#[derive(Debug, PartialEq)]
+pub struct Job {
+    /// Unix time in milliseconds
+    at: u64,
+    command: String
+}
+
+/// Commands waiting to be run at a given time.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    last_id: u64,
+    /// Time and id of every job, the earliest on top. Cancelled jobs stay until they come up and are skipped then
+    queue: BinaryHeap<Reverse<(u64, u64)>>,
+    jobs: HashMap<u64, Job>
+}
+
+impl Scheduler {
+    /// Adds the job under the next id, or the given one when recreating jobs of the primary.
+    /// Returns `None` once the ids run out.
+    fn add(&mut self, at: u64, id: Option<u64>, command: &str) -> Option<u64> {
+        let id = match id {
+            Some(id) => id,
+            None => self.last_id.checked_add(1)?
+        };
+        self.last_id = self.last_id.max(id);
+        self.queue.push(Reverse((at, id)));
+        self.jobs.insert(id, Job { at, command: command.to_string() });
+        Some(id)
+    }
+
+    fn cancel(&mut self, id: u64) -> bool {
+        let cancelled = self.jobs.remove(&id).is_some();
+        // drop cancelled entries once they make up most of the queue, so far off jobs don't pile up
+        if self.queue.len() > 2 * self.jobs.len() {
+            let jobs = &self.jobs;
+            self.queue.retain(|Reverse((at, id))| jobs.get(id).is_some_and(|job| job.at == *at));
+        }
+        cancelled
+    }
+
+    /// Removes jobs due at `now`, returning their ids and commands in the order they are to be run.
+    fn due(&mut self, now: u64) -> Vec<(u64, String)> {
+        let mut due = Vec::new();
+        while let Some(Reverse((at, id))) = self.queue.peek().copied() {
+            if at > now {
+                break
+            }
+            self.queue.pop();
+            // skips cancelled jobs, and stale entries of ids reused by the primary
+            if self.jobs.get(&id).is_some_and(|job| job.at == at) {
+                due.push((id, self.jobs.remove(&id).unwrap().command));
+            }
+        }
+        due
+    }
+
+    fn list(&self) -> Value {
+        let mut jobs: Vec<(&u64, &Job)> = self.jobs.iter().collect();
+        jobs.sort_by_key(|(id, job)| (job.at, **id));
+        Value::Array(jobs.into_iter().map(|(id, job)| Value::Object(HashMap::from([
+            ("id".to_string(), Value::Integer(*id as isize)),
+            ("at".to_string(), Value::Integer(job.at as isize)),
+            ("command".to_string(), Value::from_text(&job.command))
+        ]))).collect())
+    }
+
+    pub fn clear(&mut self) {
+        *self = Scheduler::default();
+    }
+
+    /// Adds the `scheduled` section to the snapshot, unless there are no jobs.
+    pub fn save(&self, snapshot: &mut HashMap<String, Value>) {
+        if !self.jobs.is_empty() {
+            snapshot.insert(SECTION.to_string(), self.list());
+        }
+    }
+
+    /// Recreates jobs of a snapshot with their ids, so cancelling and finishing them applies to the same ones.
+    pub fn restore(&mut self, jobs: Vec<(u64, Job)>) {
+        for (id, job) in jobs {
+            self.add(job.at, Some(id), &job.command);
+        }
+    }
+
+    /// Adds jobs of a snapshot under new ids, next to the existing ones.
+    pub fn append(&mut self, jobs: Vec<(u64, Job)>) {
+        for (_, job) in jobs {
+            self.add(job.at, None, &job.command);
+        }
+    }
+}
+
+/// Takes jobs out of the `scheduled` section of a snapshot, rejecting commands `SCHEDULE` would.
+pub fn take_jobs(snapshot: &mut HashMap<String, Value>) -> Result<Vec<(u64, Job)>, String> {
+    let jobs = match snapshot.remove(SECTION) {
+        Some(Value::Array(jobs)) => jobs,
+        Some(_) => return Err(format!("Invalid section {SECTION}")),
+        None => return Ok(Vec::new())
+    };
+    jobs.iter().map(|job| {
+        let field = |name: &str| job.object().ok().and_then(|job| job.get(name));
+        let number = |name: &str| field(name).and_then(|value| value.integer().ok()).and_then(|value| u64::try_from(value).ok());
+        let (Some(id), Some(at), Some(command)) = (number("id"), number("at"), field("command").and_then(|command| command.text().ok())) else {
+            return Err(format!("Invalid job {}", job.serialize()))
+        };
+        let (name, args) = split_command(&command);
+        if !is_schedulable(&name, args) {
+            return Err(format!("Command {name} can't be scheduled"))
+        }
+        Ok((id, Job { at, command }))
+    }).collect()
+}
+
+/// Commands a job may run, writes handled by `execute`. Connection bound commands and scheduling itself are left out.
+fn is_schedulable(command: &str, args: &str) -> bool {
+    is_write_command(command, args) && !matches!(command, "rollback" | "lock" | "unlock" | "extend" | "bpop" | "import" | "schedule" | "scheduled")
+}
+
+/// Splits `AT timestamp [ID id] command` or `IN delay command` into the time, id and command.
+fn parse_schedule(args: &str, now: u64) -> Result<(u64, Option<u64>, &str), String> {
+    let invalid = || "Invalid arguments".to_string();
+    let (when, args) = args.trim_start().split_once(char::is_whitespace).ok_or_else(invalid)?;
+    let (time, args) = args.trim_start().split_once(char::is_whitespace).ok_or_else(invalid)?;
+    let relative = match when.to_ascii_lowercase().as_str() {
+        "at" => false,
+        "in" => true,
+        _ => return Err(invalid())
+    };
+    let time: u64 = time.parse().map_err(|_| format!("Invalid time {time}"))?;
+    let at = match relative {
+        true => now.saturating_add(time),
+        false => time
+    };
+    let (id, command) = match args.trim_start().split_once(char::is_whitespace) {
+        Some((option, rest)) if option.eq_ignore_ascii_case("id") => {
+            let (id, command) = rest.trim_start().split_once(char::is_whitespace).ok_or_else(invalid)?;
+            (Some(id.parse().map_err(|_| format!("Invalid id {id}"))?), command)
+        },
+        _ => (None, args)
+    };
+    Ok((at, id, command.trim()))
+}
+
+/// `SCHEDULE AT timestamp command` or `SCHEDULE IN delay command`, runs the write command at unix time
+/// or after delay, both in milliseconds. Returns id of the job.
+///
+/// Replicas keep the jobs too, but only the primary runs them, replicas receive the writes they make.
+pub fn schedule_cmd(args: &str, db: &Database) -> Response {
+    let (at, id, command) = match parse_schedule(args, now()) {
+        Ok(parsed) => parsed,
+        Err(e) => return Response::builder().set_body(e)
+    };
+    let (name, command_args) = split_command(command);
+    if !is_schedulable(&name, command_args) {
+        return Response::builder().set_body(format!("Command {name} can't be scheduled"))
+    }
+    // replication is locked first, same as when syncing a replica, so the job is either in its snapshot or propagated
+    let mut replication = db.replication.lock().unwrap();
+    // clients can't write to replicas, so an id only comes from the primary recreating its job
+    if id.is_some() && !replication.is_replica() {
+        return Response::builder().set_body("ID is only accepted from the primary")
+    }
+    let Some(id) = db.scheduler.lock().unwrap().add(at, id, command) else {
+        return Response::builder().set_body("No job ids left")
+    };
+    // replicas get the absolute time and the id, so their copy of the job matches
+    replication.propagate(&format!("SCHEDULE AT {at} ID {id} {command}"));
+    Response::builder().set_body(id.to_string())
+}
+
+/// `SCHEDULED LIST` returns jobs from the earliest one, `SCHEDULED CANCEL id` removes the job before it runs.
+pub fn scheduled_cmd(args: &str, message: &str, db: &Database) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    match args.as_slice() {
+        [subcommand] if subcommand.eq_ignore_ascii_case("list") => {
+            Response::builder().set_body(db.scheduler.lock().unwrap().list().serialize())
+        },
+        [subcommand, id] if subcommand.eq_ignore_ascii_case("cancel") => {
+            let Ok(id) = id.parse() else {
+                return Response::builder().set_body(format!("Invalid id {id}"))
+            };
+            let mut replication = db.replication.lock().unwrap();
+            let cancelled = db.scheduler.lock().unwrap().cancel(id);
+            match cancelled {
+                true => {
+                    replication.propagate(message);
+                    Response::builder().set_body("OK")
+                },
+                false => Response::builder().set_body("Job not found")
+            }
+        },
+        _ => Response::builder().set_body("Invalid arguments")
+    }
+}
+
+/// Subcommands which don't change jobs, allowed on replicas.
+pub fn is_read_only(args: &str) -> bool {
+    args.trim().eq_ignore_ascii_case("list")
+}
+
+/// Runs jobs once they're due, for the lifetime of the server. Finished jobs are cancelled on replicas.
+pub fn run_jobs(db: &Database) {
+    loop {
+        thread::sleep(Duration::from_millis(10));
+        if db.replication.lock().unwrap().is_replica() {
+            continue
+        }
+        let due = db.scheduler.lock().unwrap().due(now());
+        for (id, command) in due {
+            execute(&command, db);
+            db.replication.lock().unwrap().propagate(&format!("SCHEDULED CANCEL {id}"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    #[test]
+    fn test_parse_schedule() {
+        assert_eq!(parse_schedule("AT 2000 DEL session:1", 1000), Ok((2000, None, "DEL session:1")));
+        assert_eq!(parse_schedule("in 500 SET q.- 1", 1000), Ok((1500, None, "SET q.- 1")));
+        assert_eq!(parse_schedule("AT 2000 ID 7 DEL a", 1000), Ok((2000, Some(7), "DEL a")));
+        assert!(parse_schedule("AT soon DEL a", 1000).is_err());
+        assert!(parse_schedule("EVERY 10 DEL a", 1000).is_err());
+        assert!(parse_schedule("IN 10", 1000).is_err());
+        assert!(is_schedulable("del", "a"));
+        assert!(!is_schedulable("get", "a"));
+        assert!(!is_schedulable("bpop", "a 0"));
+        assert!(!is_schedulable("import", "dump.jsonl"));
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let mut scheduler = Scheduler::default();
+        assert_eq!(scheduler.add(300, None, "DEL a"), Some(1));
+        assert_eq!(scheduler.add(100, None, "DEL b"), Some(2));
+        assert_eq!(scheduler.add(200, None, "DEL c"), Some(3));
+        assert!(scheduler.cancel(3));
+        assert!(!scheduler.cancel(3));
+        assert_eq!(scheduler.due(50), vec![]);
+        assert_eq!(scheduler.due(250), vec![(2, "DEL b".to_string())]);
+        assert_eq!(scheduler.jobs, HashMap::from([(1, Job { at: 300, command: "DEL a".to_string() })]));
+        // replica recreating jobs keeps their ids
+        assert_eq!(scheduler.add(400, Some(9), "DEL d"), Some(9));
+        assert_eq!(scheduler.add(400, None, "DEL e"), Some(10));
+        assert_eq!(scheduler.due(1000).len(), 3);
+        assert!(scheduler.jobs.is_empty() && scheduler.queue.is_empty());
+        // ids don't wrap around once they run out
+        assert_eq!(scheduler.add(400, Some(u64::MAX), "DEL f"), Some(u64::MAX));
+        assert_eq!(scheduler.add(400, None, "DEL g"), None);
+    }
+
+    #[test]
+    fn test_schedule_cmd() {
+        let db = Database::new(&Config::default());
+        let body = |command: &str| execute(command, &db).payload.string().unwrap();
+        assert_eq!(body("SCHEDULE AT 100 ID 5 DEL a"), "ID is only accepted from the primary");
+        assert_eq!(body("SCHEDULE AT 100 ID 18446744073709551615 DEL a"), "ID is only accepted from the primary");
+        assert_eq!(body("SCHEDULE AT 100 DEL a"), "1");
+        assert_eq!(db.scheduler.lock().unwrap().jobs.len(), 1);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut scheduler = Scheduler::default();
+        let mut snapshot = HashMap::new();
+        scheduler.save(&mut snapshot);
+        assert!(snapshot.is_empty());
+        scheduler.add(300, Some(4), r#"SET a {"b": "c\nd"}"#);
+        scheduler.add(200, None, "DEL b");
+        scheduler.save(&mut snapshot);
+
+        let mut snapshot = match Value::deserialize(&Value::Object(snapshot).serialize()) {
+            Ok(Value::Object(snapshot)) => snapshot,
+            other => panic!("Invalid snapshot {other:?}")
+        };
+        let mut restored = Scheduler::default();
+        restored.restore(take_jobs(&mut snapshot).unwrap());
+        assert!(snapshot.is_empty());
+        assert_eq!(restored.jobs, scheduler.jobs);
+        assert_eq!(restored.add(100, None, "DEL c"), Some(6));
+
+        let mut snapshot = HashMap::from([(SECTION.to_string(), scheduler.list())]);
+        restored.append(take_jobs(&mut snapshot).unwrap());
+        assert_eq!(restored.due(300).iter().map(|(id, _)| *id).collect::<Vec<u64>>(), vec![6, 5, 7, 4, 8]);
+
+        let job = |command: &str| Value::deserialize(&format!(r#"{{"scheduled": [{{"id": 1, "at": 1, "command": "{command}"}}]}}"#)).unwrap();
+        let take = |snapshot: Value| take_jobs(&mut snapshot.object().unwrap().clone());
+        assert_eq!(take(job("IMPORT dump.jsonl")), Err("Command import can't be scheduled".to_string()));
+        assert_eq!(take(job("GET a")), Err("Command get can't be scheduled".to_string()));
+        assert!(take(Value::deserialize(r#"{"scheduled": [{"id": -1, "at": 1, "command": "DEL a"}]}"#).unwrap()).is_err());
+        assert!(take(Value::deserialize(r#"{"scheduled": {}}"#).unwrap()).is_err());
+    }
+}
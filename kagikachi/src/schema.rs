@@ -0,0 +1,404 @@
+use std::collections::{BTreeMap, HashMap};
+
+use mini_json::{Path, Segment, Value};
+use sockets::response::Response;
+
+use crate::{pattern::Pattern, storage::Entry, Database};
+
+const TYPES: [&str; 7] = ["object", "array", "string", "number", "integer", "boolean", "null"];
+
+/// Document not conforming to the schema, `path` points at the offending value.
+#[derive(Debug, PartialEq)]
+pub struct Violation {
+    pub path: String,
+    pub keyword: &'static str,
+    pub message: String
+}
+
+impl Violation {
+    fn new(path: &Path, keyword: &'static str, message: String) -> Self {
+        Self { path: path.to_string(), keyword, message }
+    }
+
+    pub fn to_value(&self) -> Value {
+        Value::Object(HashMap::from([
+            ("error".to_string(), Value::String("Schema violation".to_string())),
+            ("path".to_string(), Value::String(self.path.clone())),
+            ("keyword".to_string(), Value::String(self.keyword.to_string())),
+            ("message".to_string(), Value::String(self.message.clone()))
+        ]))
+    }
+}
+
+impl From<Violation> for Response {
+    fn from(violation: Violation) -> Self {
+        Response::builder().set_body(violation.to_value().serialize())
+    }
+}
+
+fn type_of(value: &Value) -> &'static str {
+    match value {
+        Value::Object(_) => "object",
+        Value::Array(_) => "array",
+        Value::String(_) => "string",
+        Value::Integer(_) => "integer",
+        Value::Float(_) => "number",
+        Value::Boolean(_) => "boolean",
+        Value::Null => "null"
+    }
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match (name, value) {
+        ("number", Value::Integer(_)) => true,
+        ("integer", Value::Float(number)) => number.fract() == 0.0,
+        _ => type_of(value) == name
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Integer(number) => Some(*number as f64),
+        Value::Float(number) => Some(*number),
+        _ => None
+    }
+}
+
+/// Subset of JSON Schema: `type`, `enum`, `required`, `properties`, `additionalProperties` (boolean only),
+/// `items`, `minimum`, `maximum`, `exclusiveMinimum`, `exclusiveMaximum`, `minLength`, `maxLength`,
+/// `minItems`, `maxItems` and `pattern`. Other keywords are ignored, same as unknown keywords in JSON Schema.
+#[derive(Debug, Default)]
+pub struct Schema {
+    types: Option<Vec<String>>,
+    allowed: Option<Vec<Value>>,
+    required: Vec<String>,
+    properties: HashMap<String, Schema>,
+    additional_properties: bool,
+    items: Option<Box<Schema>>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    exclusive_minimum: Option<f64>,
+    exclusive_maximum: Option<f64>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    min_items: Option<usize>,
+    max_items: Option<usize>,
+    pattern: Option<Pattern>
+}
+
+impl Schema {
+    pub fn parse(value: &Value) -> Result<Self, String> {
+        let object = value.object().map_err(|_| "Invalid schema: expected object".to_string())?;
+        let invalid = |keyword: &str, expected: &str| format!("Invalid schema: {keyword} must be {expected}");
+        let number = |keyword: &str| match object.get(keyword) {
+            None => Ok(None),
+            Some(value) => number(value).map(Some).ok_or_else(|| invalid(keyword, "a number"))
+        };
+        let count = |keyword: &str| match object.get(keyword) {
+            None => Ok(None),
+            Some(value) => value.integer().ok().and_then(|n| usize::try_from(n).ok()).map(Some)
+                .ok_or_else(|| invalid(keyword, "a non-negative integer"))
+        };
+        let mut schema = Schema {
+            additional_properties: true,
+            minimum: number("minimum")?,
+            maximum: number("maximum")?,
+            exclusive_minimum: number("exclusiveMinimum")?,
+            exclusive_maximum: number("exclusiveMaximum")?,
+            min_length: count("minLength")?,
+            max_length: count("maxLength")?,
+            min_items: count("minItems")?,
+            max_items: count("maxItems")?,
+            ..Schema::default()
+        };
+        if let Some(types) = object.get("type") {
+            let types = match types {
+                Value::String(name) => vec![name.clone()],
+                Value::Array(names) => names.iter().map(|name| name.string().cloned())
+                    .collect::<Result<_, _>>().map_err(|_| invalid("type", "a string or array of strings"))?,
+                _ => return Err(invalid("type", "a string or array of strings"))
+            };
+            if let Some(unknown) = types.iter().find(|name| !TYPES.contains(&name.as_str())) {
+                return Err(format!("Invalid schema: unknown type {unknown}"))
+            }
+            schema.types = Some(types);
+        }
+        if let Some(allowed) = object.get("enum") {
+            schema.allowed = Some(allowed.array().map_err(|_| invalid("enum", "an array"))?.clone());
+        }
+        if let Some(required) = object.get("required") {
+            schema.required = required.array().ok()
+                .and_then(|names| names.iter().map(|name| name.string().ok().cloned()).collect())
+                .ok_or_else(|| invalid("required", "an array of strings"))?;
+        }
+        if let Some(properties) = object.get("properties") {
+            let properties = properties.object().map_err(|_| invalid("properties", "an object"))?;
+            for (name, property) in properties {
+                schema.properties.insert(name.clone(), Schema::parse(property)?);
+            }
+        }
+        if let Some(additional) = object.get("additionalProperties") {
+            schema.additional_properties = match additional {
+                Value::Boolean(additional) => *additional,
+                _ => return Err(invalid("additionalProperties", "a boolean"))
+            };
+        }
+        if let Some(items) = object.get("items") {
+            schema.items = Some(Box::new(Schema::parse(items)?));
+        }
+        if let Some(pattern) = object.get("pattern") {
+            let pattern = pattern.string().map_err(|_| invalid("pattern", "a string"))?;
+            schema.pattern = Some(Pattern::new(pattern).map_err(|e| format!("Invalid schema: pattern {e}"))?);
+        }
+        Ok(schema)
+    }
+
+    /// Checks `value` found at `path`, reporting the first violation found.
+    pub fn validate(&self, value: &Value, path: &Path) -> Result<(), Violation> {
+        if let Some(types) = &self.types {
+            if !types.iter().any(|name| has_type(value, name)) {
+                let message = format!("expected {}, got {}", types.join(" or "), type_of(value));
+                return Err(Violation::new(path, "type", message))
+            }
+        }
+        if let Some(allowed) = &self.allowed {
+            if !allowed.contains(value) {
+                let allowed: Vec<String> = allowed.iter().map(Value::serialize).collect();
+                return Err(Violation::new(path, "enum", format!("{} is not one of {}", value.serialize(), allowed.join(", "))))
+            }
+        }
+        match value {
+            Value::Object(fields) => self.validate_object(fields, path),
+            Value::Array(items) => self.validate_array(items, path),
+            Value::String(string) => self.validate_string(string, path),
+            Value::Integer(_) | Value::Float(_) => self.validate_number(value, path),
+            Value::Boolean(_) | Value::Null => Ok(())
+        }
+    }
+
+    fn validate_object(&self, fields: &HashMap<String, Value>, path: &Path) -> Result<(), Violation> {
+        if let Some(missing) = self.required.iter().find(|name| !fields.contains_key(*name)) {
+            return Err(Violation::new(path, "required", format!("missing required field {missing}")))
+        }
+        // sorted, so the reported violation doesn't depend on hashing order
+        let fields: BTreeMap<&String, &Value> = fields.iter().collect();
+        for (name, value) in fields {
+            match self.properties.get(name) {
+                Some(schema) => schema.validate(value, &path.child(Segment::Key(name.clone())))?,
+                None if !self.additional_properties => {
+                    return Err(Violation::new(path, "additionalProperties", format!("unexpected field {name}")))
+                },
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_array(&self, items: &[Value], path: &Path) -> Result<(), Violation> {
+        if let Some(min) = self.min_items.filter(|min| items.len() < *min) {
+            return Err(Violation::new(path, "minItems", format!("array has fewer than {min} items")))
+        }
+        if let Some(max) = self.max_items.filter(|max| items.len() > *max) {
+            return Err(Violation::new(path, "maxItems", format!("array has more than {max} items")))
+        }
+        if let Some(schema) = &self.items {
+            for (i, item) in items.iter().enumerate() {
+                schema.validate(item, &path.child(Segment::Index(i as isize)))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_string(&self, string: &str, path: &Path) -> Result<(), Violation> {
+        let len = string.chars().count();
+        if let Some(min) = self.min_length.filter(|min| len < *min) {
+            return Err(Violation::new(path, "minLength", format!("string is shorter than {min}")))
+        }
+        if let Some(max) = self.max_length.filter(|max| len > *max) {
+            return Err(Violation::new(path, "maxLength", format!("string is longer than {max}")))
+        }
+        if let Some(pattern) = &self.pattern {
+            if !pattern.is_match(string) {
+                return Err(Violation::new(path, "pattern", format!("string does not match {}", pattern.source())))
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_number(&self, value: &Value, path: &Path) -> Result<(), Violation> {
+        let n = number(value).unwrap_or_default();
+        let value = value.serialize();
+        if let Some(min) = self.minimum.filter(|min| n < *min) {
+            return Err(Violation::new(path, "minimum", format!("{value} is less than {min}")))
+        }
+        if let Some(max) = self.maximum.filter(|max| n > *max) {
+            return Err(Violation::new(path, "maximum", format!("{value} is greater than {max}")))
+        }
+        if let Some(min) = self.exclusive_minimum.filter(|min| n <= *min) {
+            return Err(Violation::new(path, "exclusiveMinimum", format!("{value} is not greater than {min}")))
+        }
+        if let Some(max) = self.exclusive_maximum.filter(|max| n >= *max) {
+            return Err(Violation::new(path, "exclusiveMaximum", format!("{value} is not less than {max}")))
+        }
+        Ok(())
+    }
+}
+
+/// Schemas bound to key prefixes. Only JSON documents are validated, other entry types are left alone.
+#[derive(Default)]
+pub struct Schemas {
+    schemas: BTreeMap<String, (Schema, Value)>
+}
+
+impl Schemas {
+    pub fn applies(&self, key: &str) -> bool {
+        self.schemas.keys().any(|prefix| key.starts_with(prefix.as_str()))
+    }
+
+    /// Validates document about to be stored under `key` against every schema whose prefix matches.
+    /// Reported path starts with the key, same as paths used by commands.
+    pub fn check(&self, key: &str, value: &Value) -> Result<(), Violation> {
+        let path = Path::from(vec![Segment::Key(key.to_string())]);
+        for (prefix, (schema, _)) in &self.schemas {
+            if key.starts_with(prefix.as_str()) {
+                schema.validate(value, &path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Same as `check`, for any storage entry.
+    pub fn check_entry(&self, key: &str, entry: &Entry) -> Result<(), Violation> {
+        match entry.value() {
+            Some(value) => self.check(key, value),
+            None => Ok(())
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.schemas.clear();
+    }
+
+    /// `SCHEMA SET` commands recreating every schema, sent to replicas after the snapshot.
+    pub fn commands(&self) -> Vec<String> {
+        self.schemas.iter().map(|(prefix, (_, source))| format!("SCHEMA SET {prefix}* {}", source.serialize())).collect()
+    }
+}
+
+fn parse_prefix(pattern: &str) -> Result<&str, Response> {
+    pattern.strip_suffix('*').ok_or_else(|| Response::builder().set_body("Key pattern must end with *"))
+}
+
+/// Subcommands which don't change schemas, allowed on replicas.
+pub fn is_read_only(args: &str) -> bool {
+    let subcommand = args.split_whitespace().next().unwrap_or_default();
+    subcommand.eq_ignore_ascii_case("get") || subcommand.eq_ignore_ascii_case("list")
+}
+
+/// `SCHEMA SET prefix* {schema}`, `SCHEMA GET prefix*`, `SCHEMA DEL prefix*` or `SCHEMA LIST`
+///
+/// Schema is only set if every document already stored under the prefix conforms to it.
+pub fn schema_cmd(args: &str, message: &str, db: &Database) -> Response {
+    let mut parts = args.trim_start().splitn(3, ' ');
+    let (subcommand, pattern, rest) = (parts.next().unwrap_or_default(), parts.next(), parts.next());
+    match (subcommand.to_ascii_lowercase().as_str(), pattern, rest) {
+        ("set", Some(pattern), Some(source)) => {
+            let prefix = match parse_prefix(pattern) {
+                Ok(prefix) => prefix,
+                Err(response) => return response
+            };
+            let source = match Value::deserialize(source) {
+                Ok(source) => source,
+                Err(e) => return Response::builder().set_body(format!("Invalid value: {e:?}"))
+            };
+            let schema = match Schema::parse(&source) {
+                Ok(schema) => schema,
+                Err(e) => return Response::builder().set_body(e)
+            };
+            // writers are blocked while existing documents are checked, so none can slip through
+            db.write_all(message, |shards| {
+                let mut documents: Vec<(&String, &Value)> = shards.documents().filter(|(key, _)| key.starts_with(prefix)).collect();
+                documents.sort_by_key(|(key, _)| *key);
+                for (key, value) in documents {
+                    if let Err(violation) = schema.validate(value, &Path::from(vec![Segment::Key(key.clone())])) {
+                        return violation.into()
+                    }
+                }
+                db.schemas.write().unwrap().schemas.insert(prefix.to_string(), (schema, source));
+                Response::builder().set_body("OK")
+            })
+        },
+        ("get", Some(pattern), None) => {
+            let prefix = match parse_prefix(pattern) {
+                Ok(prefix) => prefix,
+                Err(response) => return response
+            };
+            match db.schemas.read().unwrap().schemas.get(prefix) {
+                Some((_, source)) => Response::builder().set_body(source.serialize()),
+                None => Response::builder().set_body("Schema not found")
+            }
+        },
+        ("del", Some(pattern), None) => {
+            let prefix = match parse_prefix(pattern) {
+                Ok(prefix) => prefix,
+                Err(response) => return response
+            };
+            db.write_all(message, |_| match db.schemas.write().unwrap().schemas.remove(prefix) {
+                Some(_) => Response::builder().set_body("OK"),
+                None => Response::builder().set_body("Schema not found")
+            })
+        },
+        ("list", None, None) => {
+            let schemas = db.schemas.read().unwrap();
+            let list = schemas.schemas.iter().map(|(prefix, (_, source))| (format!("{prefix}*"), source.clone()));
+            Response::builder().set_body(Value::Object(list.collect()).serialize())
+        },
+        _ => Response::builder().set_body("Invalid arguments")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema() -> Schema {
+        Schema::parse(&Value::deserialize(r#"{
+            "type": "object",
+            "required": ["name", "age"],
+            "properties": {
+                "name": {"type": "string", "minLength": 1, "pattern": "^[A-Z]"},
+                "age": {"type": "integer", "minimum": 0, "maximum": 150},
+                "role": {"enum": ["admin", "user"]},
+                "tags": {"type": "array", "maxItems": 2, "items": {"type": "string"}}
+            },
+            "additionalProperties": false
+        }"#).unwrap()).unwrap()
+    }
+
+    fn violation(document: &str) -> Option<(String, &'static str)> {
+        schema().validate(&Value::deserialize(document).unwrap(), &Path::parse("user:1").unwrap()).err().map(|v| (v.path, v.keyword))
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(violation(r#"{"name": "Ann", "age": 30, "role": "admin", "tags": ["a"]}"#), None);
+        assert_eq!(violation(r#"{"name": "Ann", "age": 30.0}"#), None);
+        assert_eq!(violation(r#"[]"#), Some(("user:1".to_string(), "type")));
+        assert_eq!(violation(r#"{"name": "Ann"}"#), Some(("user:1".to_string(), "required")));
+        assert_eq!(violation(r#"{"name": "ann", "age": 30}"#), Some(("user:1.name".to_string(), "pattern")));
+        assert_eq!(violation(r#"{"name": "Ann", "age": -1}"#), Some(("user:1.age".to_string(), "minimum")));
+        assert_eq!(violation(r#"{"name": "Ann", "age": 1.5}"#), Some(("user:1.age".to_string(), "type")));
+        assert_eq!(violation(r#"{"name": "Ann", "age": 1, "role": "root"}"#), Some(("user:1.role".to_string(), "enum")));
+        assert_eq!(violation(r#"{"name": "Ann", "age": 1, "tags": ["a", 1]}"#), Some(("user:1.tags[1]".to_string(), "type")));
+        assert_eq!(violation(r#"{"name": "Ann", "age": 1, "tags": ["a", "b", "c"]}"#), Some(("user:1.tags".to_string(), "maxItems")));
+        assert_eq!(violation(r#"{"name": "Ann", "age": 1, "extra": 1}"#), Some(("user:1".to_string(), "additionalProperties")));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for schema in [r#"[]"#, r#"{"type": "text"}"#, r#"{"minimum": "1"}"#, r#"{"minLength": -1}"#, r#"{"pattern": "("}"#,
+            r#"{"properties": {"a": {"required": "a"}}}"#] {
+            assert!(Schema::parse(&Value::deserialize(schema).unwrap()).is_err(), "{schema}");
+        }
+    }
+}
@@ -0,0 +1,1008 @@
+use std::{cmp::Ordering, collections::HashMap};
+
+use mini_json::{escape, unescape, Value};
+use sockets::response::Response;
+
+use crate::{execute_locked, Database};
+
+/// Number of evaluated statements and expressions after which script is stopped.
+/// Scripts hold every shard locked, so this also bounds how long they can stall other clients.
+const INSTRUCTION_BUDGET: usize = 100_000;
+/// Maximum nesting of blocks and expressions, keeps the recursive parser from overflowing the stack.
+const MAX_DEPTH: usize = 64;
+/// Bytes of values a script may create over its run, every copy counts. Like the instruction budget,
+/// it bounds time spent under the locks as well as memory.
+const ALLOCATION_BUDGET: usize = 64 * 1024 * 1024;
+/// Longest string, in bytes, and largest array or object a script may build.
+const MAX_STRING_LENGTH: usize = 16 * 1024 * 1024;
+const MAX_ITEMS: usize = 1024 * 1024;
+/// Maximum nesting of values, copying and dropping them is recursive, same as parsing.
+const MAX_VALUE_DEPTH: usize = 128;
+
+const SYMBOLS: [&str; 25] = [
+    "==", "!=", "<=", ">=", "&&", "||",
+    "=", "<", ">", "+", "-", "*", "/", "%", "!", "(", ")", "[", "]", "{", "}", ",", ";", ":", "."
+];
+/// Binary operators from the lowest to the highest precedence.
+const BINARY_OPERATORS: [&[&str]; 6] = [&["||"], &["&&"], &["==", "!="], &["<", "<=", ">", ">="], &["+", "-"], &["*", "/", "%"]];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(Value),
+    String(String),
+    Identifier(String),
+    Symbol(&'static str)
+}
+
+#[derive(Debug)]
+enum Expr {
+    Literal(Value),
+    Variable(String),
+    Array(Vec<Expr>),
+    Object(Vec<(String, Expr)>),
+    Index(Box<Expr>, Box<Expr>),
+    Unary(&'static str, Box<Expr>),
+    Binary(Box<Expr>, &'static str, Box<Expr>),
+    Call(String, Vec<Expr>)
+}
+
+#[derive(Debug)]
+enum Stmt {
+    Expr(Expr),
+    /// `name[index]...[index] = value`
+    Assign(String, Vec<Expr>, Expr),
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    While(Expr, Vec<Stmt>),
+    For(String, Expr, Vec<Stmt>),
+    Return(Option<Expr>),
+    Break,
+    Continue
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '#' {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            if i + 1 < chars.len() && chars[i] == '.' && chars[i + 1].is_ascii_digit() {
+                i += 1;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+            let text: String = chars[start..i].iter().collect();
+            let number = match text.parse::<isize>() {
+                Ok(number) => Value::Integer(number),
+                Err(_) => Value::Float(text.parse().map_err(|_| format!("Invalid number {text}"))?)
+            };
+            tokens.push(Token::Number(number));
+        } else if c == '"' || c == '\'' {
+            let mut string = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err("Unterminated string".to_string()),
+                    Some(end) if *end == c => break,
+                    Some('\\') => {
+                        i += 1;
+                        match chars.get(i) {
+                            Some('n') => string.push('\n'),
+                            Some('t') => string.push('\t'),
+                            Some(escaped) => string.push(*escaped),
+                            None => return Err("Unterminated string".to_string())
+                        }
+                    },
+                    Some(c) => string.push(*c)
+                }
+                i += 1;
+            }
+            i += 1;
+            tokens.push(Token::String(string));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Identifier(chars[start..i].iter().collect()));
+        } else {
+            let symbol = SYMBOLS.iter().find(|symbol| {
+                symbol.chars().enumerate().all(|(offset, s)| chars.get(i + offset) == Some(&s))
+            });
+            match symbol {
+                Some(symbol) => {
+                    tokens.push(Token::Symbol(symbol));
+                    i += symbol.len();
+                },
+                None => return Err(format!("Unexpected character {c}"))
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn is_symbol(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Identifier(word)) if word == keyword)
+    }
+
+    /// Consumes the symbol if it's next.
+    fn eat(&mut self, symbol: &str) -> bool {
+        let found = self.is_symbol(symbol);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        match self.eat(symbol) {
+            true => Ok(()),
+            false => Err(format!("Expected {symbol}, got {}", self.describe_next()))
+        }
+    }
+
+    fn describe_next(&self) -> String {
+        match self.peek() {
+            Some(Token::Number(number)) => number.serialize(),
+            Some(Token::String(string)) => format!("\"{string}\""),
+            Some(Token::Identifier(word)) => word.clone(),
+            Some(Token::Symbol(symbol)) => symbol.to_string(),
+            None => "end of script".to_string()
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Some(Token::Identifier(word)) => {
+                let word = word.clone();
+                self.position += 1;
+                Ok(word)
+            },
+            _ => Err(format!("Expected name, got {}", self.describe_next()))
+        }
+    }
+
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, String>) -> Result<T, String> {
+        if self.depth == MAX_DEPTH {
+            return Err("Script is nested too deeply".to_string())
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn statements(&mut self) -> Result<Vec<Stmt>, String> {
+        let mut statements = Vec::new();
+        while self.peek().is_some() && !self.is_symbol("}") {
+            statements.push(self.statement()?);
+        }
+        Ok(statements)
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, String> {
+        self.expect("{")?;
+        let statements = self.nested(Self::statements)?;
+        self.expect("}")?;
+        Ok(statements)
+    }
+
+    fn statement(&mut self) -> Result<Stmt, String> {
+        let keyword = match self.peek() {
+            Some(Token::Identifier(word)) => word.clone(),
+            _ => String::new()
+        };
+        let statement = match keyword.as_str() {
+            "if" => {
+                self.position += 1;
+                return self.if_statement()
+            },
+            "while" => {
+                self.position += 1;
+                let condition = self.expression()?;
+                return Ok(Stmt::While(condition, self.block()?))
+            },
+            "for" => {
+                self.position += 1;
+                let name = self.identifier()?;
+                if !self.is_keyword("in") {
+                    return Err(format!("Expected in, got {}", self.describe_next()))
+                }
+                self.position += 1;
+                let iterable = self.expression()?;
+                return Ok(Stmt::For(name, iterable, self.block()?))
+            },
+            "return" => {
+                self.position += 1;
+                match self.peek().is_none() || self.is_symbol(";") || self.is_symbol("}") {
+                    true => Stmt::Return(None),
+                    false => Stmt::Return(Some(self.expression()?))
+                }
+            },
+            "break" => {
+                self.position += 1;
+                Stmt::Break
+            },
+            "continue" => {
+                self.position += 1;
+                Stmt::Continue
+            },
+            _ => {
+                let target = self.expression()?;
+                match self.eat("=") {
+                    true => {
+                        let (name, path) = assignment_target(target)?;
+                        Stmt::Assign(name, path, self.expression()?)
+                    },
+                    false => Stmt::Expr(target)
+                }
+            }
+        };
+        self.eat(";");
+        Ok(statement)
+    }
+
+    fn if_statement(&mut self) -> Result<Stmt, String> {
+        let condition = self.expression()?;
+        let then = self.block()?;
+        if !self.is_keyword("else") {
+            return Ok(Stmt::If(condition, then, Vec::new()))
+        }
+        self.position += 1;
+        let otherwise = match self.is_keyword("if") {
+            true => {
+                self.position += 1;
+                vec![self.nested(Self::if_statement)?]
+            },
+            false => self.block()?
+        };
+        Ok(Stmt::If(condition, then, otherwise))
+    }
+
+    fn expression(&mut self) -> Result<Expr, String> {
+        self.nested(|parser| parser.binary(0))
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        let Some(operators) = BINARY_OPERATORS.get(level) else {
+            return self.unary()
+        };
+        let mut left = self.binary(level + 1)?;
+        while let Some(operator) = operators.iter().find(|operator| self.is_symbol(operator)) {
+            self.position += 1;
+            let right = self.binary(level + 1)?;
+            left = Expr::Binary(Box::new(left), operator, Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        for operator in ["!", "-"] {
+            if self.eat(operator) {
+                return Ok(Expr::Unary(operator, Box::new(self.nested(Self::unary)?)))
+            }
+        }
+        self.postfix()
+    }
+
+    fn postfix(&mut self) -> Result<Expr, String> {
+        let mut expr = self.primary()?;
+        loop {
+            if self.eat("[") {
+                let index = self.expression()?;
+                self.expect("]")?;
+                expr = Expr::Index(Box::new(expr), Box::new(index));
+            } else if self.eat(".") {
+                let field = self.identifier()?;
+                expr = Expr::Index(Box::new(expr), Box::new(Expr::Literal(Value::from_text(&field))));
+            } else {
+                return Ok(expr)
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let token = self.peek().cloned().ok_or("Unexpected end of script")?;
+        self.position += 1;
+        match token {
+            Token::Number(number) => Ok(Expr::Literal(number)),
+            Token::String(string) => Ok(Expr::Literal(Value::from_text(&string))),
+            Token::Identifier(word) => match word.as_str() {
+                "true" => Ok(Expr::Literal(Value::Boolean(true))),
+                "false" => Ok(Expr::Literal(Value::Boolean(false))),
+                "null" => Ok(Expr::Literal(Value::Null)),
+                _ if self.eat("(") => Ok(Expr::Call(word, self.list(")")?)),
+                _ => Ok(Expr::Variable(word))
+            },
+            Token::Symbol("(") => {
+                let expr = self.expression()?;
+                self.expect(")")?;
+                Ok(expr)
+            },
+            Token::Symbol("[") => Ok(Expr::Array(self.list("]")?)),
+            Token::Symbol("{") => {
+                let mut fields = Vec::new();
+                while !self.eat("}") {
+                    let name = match self.peek().cloned() {
+                        Some(Token::String(name) | Token::Identifier(name)) => name,
+                        _ => return Err(format!("Expected field name, got {}", self.describe_next()))
+                    };
+                    self.position += 1;
+                    self.expect(":")?;
+                    fields.push((escape(&name), self.expression()?));
+                    if !self.is_symbol("}") {
+                        self.expect(",")?;
+                    }
+                }
+                Ok(Expr::Object(fields))
+            },
+            _ => {
+                self.position -= 1;
+                Err(format!("Unexpected {}", self.describe_next()))
+            }
+        }
+    }
+
+    /// Comma separated expressions up to the closing symbol.
+    fn list(&mut self, end: &str) -> Result<Vec<Expr>, String> {
+        let mut items = Vec::new();
+        while !self.eat(end) {
+            items.push(self.expression()?);
+            if !self.is_symbol(end) {
+                self.expect(",")?;
+            }
+        }
+        Ok(items)
+    }
+}
+
+fn assignment_target(expr: Expr) -> Result<(String, Vec<Expr>), String> {
+    match expr {
+        Expr::Variable(name) => Ok((name, Vec::new())),
+        Expr::Index(target, index) => {
+            let (name, mut path) = assignment_target(*target)?;
+            path.push(*index);
+            Ok((name, path))
+        },
+        _ => Err("Invalid assignment target".to_string())
+    }
+}
+
+fn typename(value: &Value) -> &'static str {
+    match value {
+        Value::Object(_) => "object",
+        Value::Array(_) => "array",
+        Value::String(_) => "string",
+        Value::Integer(_) => "integer",
+        Value::Float(_) => "float",
+        Value::Boolean(_) => "boolean",
+        Value::Null => "null"
+    }
+}
+
+/// `null`, `false`, zero and empty strings, arrays and objects are false, everything else is true.
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Boolean(boolean) => *boolean,
+        Value::Integer(number) => *number != 0,
+        Value::Float(number) => *number != 0.0,
+        Value::String(string) => !string.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        Value::Object(fields) => !fields.is_empty()
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Integer(number) => Some(*number as f64),
+        Value::Float(number) => Some(*number),
+        _ => None
+    }
+}
+
+/// Negative indexes count from the end of the array.
+fn array_index(len: usize, index: isize) -> Option<usize> {
+    let index = match index < 0 {
+        true => len.checked_sub(index.unsigned_abs())?,
+        false => index as usize
+    };
+    (index < len).then_some(index)
+}
+
+/// Strings hold their JSON escaped form, the same text may be escaped differently.
+fn text(string: &str) -> Result<String, String> {
+    unescape(string).map_err(|e| e.to_string())
+}
+
+fn text_len(string: &str) -> usize {
+    unescape(string).map_or(string.len(), |text| text.len())
+}
+
+fn equals(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::String(a), Value::String(b)) => unescape(a) == unescape(b),
+        (Value::Integer(_), Value::Float(_)) | (Value::Float(_), Value::Integer(_)) => number(left) == number(right),
+        _ => left == right
+    }
+}
+
+fn compare(left: &Value, right: &Value) -> Result<Ordering, String> {
+    let ordering = match (left, right) {
+        (Value::Integer(a), Value::Integer(b)) => Some(a.cmp(b)),
+        (Value::String(a), Value::String(b)) => Some(unescape(a).cmp(&unescape(b))),
+        _ => number(left).zip(number(right)).and_then(|(a, b)| a.partial_cmp(&b))
+    };
+    ordering.ok_or_else(|| format!("Cannot compare {} and {}", typename(left), typename(right)))
+}
+
+fn arithmetic(operator: &str, left: Value, right: Value) -> Result<Value, String> {
+    if let (Value::Integer(a), Value::Integer(b)) = (&left, &right) {
+        let result = match operator {
+            "+" => a.checked_add(*b),
+            "-" => a.checked_sub(*b),
+            "*" => a.checked_mul(*b),
+            "/" => a.checked_div(*b),
+            _ => a.checked_rem(*b)
+        };
+        return result.map(Value::Integer).ok_or_else(|| format!("Integer overflow or division by zero in {a} {operator} {b}"))
+    }
+    let (Some(a), Some(b)) = (number(&left), number(&right)) else {
+        return Err(format!("Cannot apply {operator} to {} and {}", typename(&left), typename(&right)))
+    };
+    let result = match operator {
+        "+" => a + b,
+        "-" => a - b,
+        "*" => a * b,
+        "/" => a / b,
+        _ => a % b
+    };
+    match result.is_finite() {
+        true => Ok(Value::Float(result)),
+        false => Err(format!("Invalid result of {a} {operator} {b}"))
+    }
+}
+
+/// Size in bytes and nesting depth of a value built by a script, failing if it's over the limits.
+fn measure(value: &Value) -> Result<(usize, usize), String> {
+    let node = std::mem::size_of::<Value>();
+    let (size, depth) = match value {
+        Value::String(string) if text_len(string) > MAX_STRING_LENGTH => return Err("String too long".to_string()),
+        Value::String(string) => (node + string.len(), 0),
+        Value::Array(items) if items.len() > MAX_ITEMS => return Err("Array too long".to_string()),
+        Value::Object(fields) if fields.len() > MAX_ITEMS => return Err("Object too large".to_string()),
+        Value::Array(items) => items.iter().try_fold((node, 1), |(size, depth), item| {
+            let (item_size, item_depth) = measure(item)?;
+            Ok::<_, String>((size + item_size, depth.max(item_depth + 1)))
+        })?,
+        Value::Object(fields) => fields.iter().try_fold((node, 1), |(size, depth), (name, field)| {
+            let (field_size, field_depth) = measure(field)?;
+            Ok::<_, String>((size + name.len() + field_size, depth.max(field_depth + 1)))
+        })?,
+        _ => (node, 0)
+    };
+    match depth > MAX_VALUE_DEPTH {
+        true => Err("Maximum nesting depth exceeded".to_string()),
+        false => Ok((size, depth))
+    }
+}
+
+fn binary(operator: &str, left: Value, right: Value) -> Result<Value, String> {
+    match operator {
+        "==" => Ok(Value::Boolean(equals(&left, &right))),
+        "!=" => Ok(Value::Boolean(!equals(&left, &right))),
+        "<" => Ok(Value::Boolean(compare(&left, &right)?.is_lt())),
+        "<=" => Ok(Value::Boolean(compare(&left, &right)?.is_le())),
+        ">" => Ok(Value::Boolean(compare(&left, &right)?.is_gt())),
+        ">=" => Ok(Value::Boolean(compare(&left, &right)?.is_ge())),
+        "+" => match (left, right) {
+            // checked before anything is allocated for the result
+            (Value::String(a), Value::String(b)) if text_len(&a) + text_len(&b) > MAX_STRING_LENGTH => Err("String too long".to_string()),
+            (Value::Array(a), Value::Array(b)) if a.len() + b.len() > MAX_ITEMS => Err("Array too long".to_string()),
+            (Value::String(a), Value::String(b)) => Ok(Value::String(a + &b)),
+            (Value::Array(mut a), Value::Array(b)) => {
+                a.extend(b);
+                Ok(Value::Array(a))
+            },
+            (left, right) => arithmetic(operator, left, right)
+        },
+        _ => arithmetic(operator, left, right)
+    }
+}
+
+/// Missing fields and indexes out of range read as `null`.
+fn index(target: &Value, index: &Value) -> Result<Value, String> {
+    match (target, index) {
+        (Value::Object(fields), Value::String(field)) => Ok(fields.get(field).cloned().unwrap_or(Value::Null)),
+        (Value::Array(items), Value::Integer(i)) => Ok(array_index(items.len(), *i).map_or(Value::Null, |i| items[i].clone())),
+        _ => Err(format!("Cannot index {} with {}", typename(target), typename(index)))
+    }
+}
+
+fn index_mut<'a>(target: &'a mut Value, index: &Value) -> Result<&'a mut Value, String> {
+    let target_type = typename(target);
+    match (target, index) {
+        (Value::Object(fields), Value::String(field)) => fields.get_mut(field).ok_or_else(|| format!("Field {field} not found")),
+        (Value::Array(items), Value::Integer(i)) => match array_index(items.len(), *i) {
+            Some(i) => Ok(&mut items[i]),
+            None => Err(format!("Index {i} out of range"))
+        },
+        _ => Err(format!("Cannot index {target_type} with {}", typename(index)))
+    }
+}
+
+enum Flow {
+    Next,
+    Break,
+    Continue,
+    Return(Value)
+}
+
+struct Interpreter<'a> {
+    variables: HashMap<String, Value>,
+    budget: usize,
+    /// Bytes left of `ALLOCATION_BUDGET`
+    allocation: usize,
+    call: &'a mut dyn FnMut(&str) -> String
+}
+
+impl Interpreter<'_> {
+    fn step(&mut self) -> Result<(), String> {
+        self.budget = self.budget.checked_sub(1).ok_or("Instruction budget exceeded")?;
+        Ok(())
+    }
+
+    /// Accounts value produced by an expression, which is a new copy.
+    fn allocate(&mut self, value: &Value) -> Result<(), String> {
+        let (size, _) = measure(value)?;
+        self.allocation = self.allocation.checked_sub(size).ok_or("Allocation budget exceeded")?;
+        Ok(())
+    }
+
+    fn block(&mut self, statements: &[Stmt]) -> Result<Flow, String> {
+        for statement in statements {
+            match self.statement(statement)? {
+                Flow::Next => {},
+                flow => return Ok(flow)
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Runs loop body, returns `Some` if the loop should stop.
+    fn iteration(&mut self, body: &[Stmt]) -> Result<Option<Flow>, String> {
+        match self.block(body)? {
+            Flow::Next | Flow::Continue => Ok(None),
+            Flow::Break => Ok(Some(Flow::Next)),
+            flow => Ok(Some(flow))
+        }
+    }
+
+    fn statement(&mut self, statement: &Stmt) -> Result<Flow, String> {
+        self.step()?;
+        match statement {
+            Stmt::Expr(expr) => {
+                self.eval(expr)?;
+            },
+            Stmt::Assign(name, path, value) => {
+                let value = self.eval(value)?;
+                let path = path.iter().map(|index| self.eval(index)).collect::<Result<Vec<_>, _>>()?;
+                self.assign(name, &path, value)?;
+            },
+            Stmt::If(condition, then, otherwise) => {
+                return match truthy(&self.eval(condition)?) {
+                    true => self.block(then),
+                    false => self.block(otherwise)
+                }
+            },
+            Stmt::While(condition, body) => {
+                while truthy(&self.eval(condition)?) {
+                    if let Some(flow) = self.iteration(body)? {
+                        return Ok(flow)
+                    }
+                }
+            },
+            Stmt::For(name, iterable, body) => {
+                let items = match self.eval(iterable)? {
+                    Value::Array(items) => items,
+                    Value::Object(fields) => {
+                        let mut names: Vec<String> = fields.into_keys().collect();
+                        names.sort();
+                        names.into_iter().map(Value::String).collect()
+                    },
+                    value => return Err(format!("Cannot iterate over {}", typename(&value)))
+                };
+                for item in items {
+                    self.variables.insert(name.clone(), item);
+                    if let Some(flow) = self.iteration(body)? {
+                        return Ok(flow)
+                    }
+                }
+            },
+            Stmt::Return(value) => {
+                let value = match value {
+                    Some(value) => self.eval(value)?,
+                    None => Value::Null
+                };
+                return Ok(Flow::Return(value))
+            },
+            Stmt::Break => return Ok(Flow::Break),
+            Stmt::Continue => return Ok(Flow::Continue)
+        }
+        Ok(Flow::Next)
+    }
+
+    fn assign(&mut self, name: &str, path: &[Value], value: Value) -> Result<(), String> {
+        let Some((last, parents)) = path.split_last() else {
+            self.variables.insert(name.to_string(), value);
+            return Ok(())
+        };
+        // value was measured when evaluated, only the levels above it are new
+        if path.len() + value.depth() > MAX_VALUE_DEPTH {
+            return Err("Maximum nesting depth exceeded".to_string())
+        }
+        let mut target = self.variables.get_mut(name).ok_or_else(|| format!("Undefined variable {name}"))?;
+        for index in parents {
+            target = index_mut(target, index)?;
+        }
+        match (target, last) {
+            (Value::Object(fields), Value::String(field)) => {
+                fields.insert(field.clone(), value);
+            },
+            (target, index) => *index_mut(target, index)? = value
+        }
+        Ok(())
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Value, String> {
+        self.step()?;
+        let value = self.evaluate(expr)?;
+        self.allocate(&value)?;
+        Ok(value)
+    }
+
+    fn evaluate(&mut self, expr: &Expr) -> Result<Value, String> {
+        match expr {
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::Variable(name) => self.variables.get(name).cloned().ok_or_else(|| format!("Undefined variable {name}")),
+            Expr::Array(items) => Ok(Value::Array(items.iter().map(|item| self.eval(item)).collect::<Result<_, _>>()?)),
+            Expr::Object(fields) => {
+                let fields = fields.iter().map(|(name, value)| Ok((name.clone(), self.eval(value)?)));
+                Ok(Value::Object(fields.collect::<Result<_, String>>()?))
+            },
+            Expr::Index(target, position) => {
+                let target = self.eval(target)?;
+                index(&target, &self.eval(position)?)
+            },
+            Expr::Unary(operator, operand) => match (*operator, self.eval(operand)?) {
+                ("!", value) => Ok(Value::Boolean(!truthy(&value))),
+                (_, Value::Integer(number)) => number.checked_neg().map(Value::Integer).ok_or_else(|| "Integer overflow".to_string()),
+                (_, Value::Float(number)) => Ok(Value::Float(-number)),
+                (_, value) => Err(format!("Cannot negate {}", typename(&value)))
+            },
+            Expr::Binary(left, "&&", right) => Ok(Value::Boolean(truthy(&self.eval(left)?) && truthy(&self.eval(right)?))),
+            Expr::Binary(left, "||", right) => Ok(Value::Boolean(truthy(&self.eval(left)?) || truthy(&self.eval(right)?))),
+            Expr::Binary(left, operator, right) => {
+                let left = self.eval(left)?;
+                binary(operator, left, self.eval(right)?)
+            },
+            Expr::Call(name, args) => {
+                let args = args.iter().map(|arg| self.eval(arg)).collect::<Result<Vec<_>, _>>()?;
+                self.builtin(name, args)
+            }
+        }
+    }
+
+    fn builtin(&mut self, name: &str, args: Vec<Value>) -> Result<Value, String> {
+        match (name, args.as_slice()) {
+            ("call", [Value::String(command), args @ ..]) => {
+                let mut message = text(command)?;
+                for arg in args {
+                    message.push(' ');
+                    match arg {
+                        Value::String(string) => message.push_str(&text(string)?),
+                        value => message.push_str(&value.serialize())
+                    }
+                }
+                let reply = (self.call)(&message);
+                Ok(Value::deserialize(reply.trim()).unwrap_or(Value::from_text(&reply)))
+            },
+            ("len", [Value::String(string)]) => Ok(Value::Integer(text(string)?.chars().count() as isize)),
+            ("len", [Value::Array(items)]) => Ok(Value::Integer(items.len() as isize)),
+            ("len", [Value::Object(fields)]) => Ok(Value::Integer(fields.len() as isize)),
+            ("str", [Value::String(string)]) => Ok(Value::String(string.clone())),
+            ("str", [value]) => Ok(Value::from_text(&value.serialize())),
+            ("num", [Value::String(string)]) => match text(string)?.trim().parse::<isize>() {
+                Ok(number) => Ok(Value::Integer(number)),
+                Err(_) => match text(string)?.trim().parse::<f64>() {
+                    Ok(number) if number.is_finite() => Ok(Value::Float(number)),
+                    _ => Err(format!("Invalid number {string}"))
+                }
+            },
+            ("num", [value @ (Value::Integer(_) | Value::Float(_))]) => Ok(value.clone()),
+            ("json", [value]) => Ok(Value::from_text(&value.serialize())),
+            ("parse", [Value::String(string)]) => Value::deserialize(text(string)?.trim()).map_err(|e| format!("Invalid JSON: {e}")),
+            ("keys", [Value::Object(fields)]) => {
+                let mut names: Vec<String> = fields.keys().cloned().collect();
+                names.sort();
+                Ok(Value::Array(names.into_iter().map(Value::String).collect()))
+            },
+            ("push", [Value::Array(items), _]) if items.len() >= MAX_ITEMS => Err("Array too long".to_string()),
+            ("push", [Value::Array(items), value]) => {
+                let mut items = items.clone();
+                items.push(value.clone());
+                Ok(Value::Array(items))
+            },
+            ("error", [Value::String(message)]) => Err(text(message)?),
+            ("call" | "len" | "str" | "num" | "json" | "parse" | "keys" | "push" | "error", _) => Err(format!("Invalid arguments for {name}")),
+            _ => Err(format!("Unknown function {name}"))
+        }
+    }
+}
+
+/// Parsed script of the embedded language.
+///
+/// Statements are assignments (`x = 1`, `x.field[0] = 2`), `if`/`else`, `while`, `for item in array`,
+/// `break`, `continue` and `return`, separated by optional semicolons. Values are JSON values,
+/// store commands are run with `call("SET", key, value)`, which returns the reply parsed as JSON if possible.
+/// Other functions are `len`, `str`, `num`, `json`, `parse`, `keys`, `push` and `error`.
+pub struct Script {
+    statements: Vec<Stmt>
+}
+
+impl Script {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut parser = Parser { tokens: tokenize(source)?, position: 0, depth: 0 };
+        let statements = parser.statements()?;
+        match parser.peek() {
+            Some(_) => Err(format!("Unexpected {}", parser.describe_next())),
+            None => Ok(Script { statements })
+        }
+    }
+
+    /// Runs the script with `KEYS` and `ARGV` variables set, returns value of the `return` statement.
+    pub fn run(&self, keys: &[&str], argv: &[&str], budget: usize, call: &mut dyn FnMut(&str) -> String) -> Result<Value, String> {
+        let strings = |items: &[&str]| Value::Array(items.iter().map(|item| Value::from_text(item)).collect());
+        let mut interpreter = Interpreter {
+            variables: HashMap::from([("KEYS".to_string(), strings(keys)), ("ARGV".to_string(), strings(argv))]),
+            budget,
+            allocation: ALLOCATION_BUDGET,
+            call
+        };
+        match interpreter.block(&self.statements)? {
+            Flow::Return(value) => Ok(value),
+            Flow::Next => Ok(Value::Null),
+            Flow::Break | Flow::Continue => Err("break or continue outside of loop".to_string())
+        }
+    }
+}
+
+/// Hex encoded SHA1 of the script, used by `EVALSHA` to address cached scripts.
+fn digest(source: &str) -> String {
+    utils::sha1(source.as_bytes()).iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Splits off double quoted argument, `\"` and `\\` are escapes for the quote and the backslash.
+fn parse_quoted(args: &str) -> Result<(String, &str), Response> {
+    let invalid = || Response::builder().set_body("Invalid arguments: script must be double quoted");
+    let args = args.trim_start();
+    let mut chars = args.char_indices();
+    if !matches!(chars.next(), Some((_, '"'))) {
+        return Err(invalid())
+    }
+    let mut quoted = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((quoted, &args[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, escaped)) => quoted.push(escaped),
+                None => return Err(invalid())
+            },
+            c => quoted.push(c)
+        }
+    }
+    Err(invalid())
+}
+
+/// Inverse of `parse_quoted`
+fn quote(source: &str) -> String {
+    format!("\"{}\"", source.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Runs script holding every shard locked, so it sees and leaves the storage in a consistent state.
+/// Commands issued by the script are not propagated on their own, `message` replicating the whole script is.
+fn run(source: &str, args: &str, message: &str, db: &Database) -> Response {
+    let script = match Script::parse(source) {
+        Ok(script) => script,
+        Err(e) => return Response::builder().set_body(format!("Script error: {e}"))
+    };
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let (keys, argv) = match args.split_first() {
+        None => (&[][..], &[][..]),
+        Some((numkeys, rest)) => match numkeys.parse::<usize>() {
+            Ok(numkeys) if numkeys <= rest.len() => rest.split_at(numkeys),
+            _ => return Response::builder().set_body("Invalid number of keys")
+        }
+    };
+    db.scripts.lock().unwrap().insert(digest(source), source.to_string());
+    db.write_all(message, |shards| {
+        let schemas = db.schemas.read().unwrap();
+        let mut call = |command: &str| execute_locked(command, shards, &schemas, db).payload.string().unwrap_or_default();
+        match script.run(keys, argv, INSTRUCTION_BUDGET, &mut call) {
+            Ok(value) => Response::builder().set_body(value.serialize()),
+            Err(e) => Response::builder().set_body(format!("Script error: {e}"))
+        }
+    })
+}
+
+/// `EVAL "script" [numkeys key... arg...]`
+pub fn eval_cmd(args: &str, message: &str, db: &Database) -> Response {
+    match parse_quoted(args) {
+        Ok((source, args)) => run(&source, args, message, db),
+        Err(response) => response
+    }
+}
+
+/// `EVALSHA sha1 [numkeys key... arg...]`, propagated as `EVAL` so replicas don't need the script cached.
+pub fn evalsha_cmd(args: &str, db: &Database) -> Response {
+    let (sha, args) = args.trim_start().split_once(' ').unwrap_or((args.trim(), ""));
+    let source = db.scripts.lock().unwrap().get(&sha.to_ascii_lowercase()).cloned();
+    match source {
+        Some(source) => run(&source, args, &format!("EVAL {} {args}", quote(&source)), db),
+        None => Response::builder().set_body("No matching script")
+    }
+}
+
+/// `SCRIPT LOAD "script"`, `SCRIPT EXISTS sha1 [sha1 ...]` or `SCRIPT FLUSH`
+pub fn script_cmd(args: &str, db: &Database) -> Response {
+    let (subcommand, args) = args.trim_start().split_once(' ').unwrap_or((args.trim(), ""));
+    match subcommand.to_ascii_lowercase().as_str() {
+        "load" => {
+            let source = match parse_quoted(args) {
+                Ok((source, rest)) if rest.trim().is_empty() => source,
+                Ok(_) => return Response::builder().set_body("Invalid arguments"),
+                Err(response) => return response
+            };
+            if let Err(e) = Script::parse(&source) {
+                return Response::builder().set_body(format!("Script error: {e}"))
+            }
+            let sha = digest(&source);
+            db.scripts.lock().unwrap().insert(sha.clone(), source);
+            Response::builder().set_body(sha)
+        },
+        "exists" => {
+            let scripts = db.scripts.lock().unwrap();
+            let exists = args.split_whitespace().map(|sha| Value::Boolean(scripts.contains_key(&sha.to_ascii_lowercase())));
+            Response::builder().set_body(Value::Array(exists.collect()).serialize())
+        },
+        "flush" => {
+            db.scripts.lock().unwrap().clear();
+            Response::builder().set_body("OK")
+        },
+        _ => Response::builder().set_body("Unknown subcommand")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(source: &str, call: &mut dyn FnMut(&str) -> String) -> Result<Value, String> {
+        Script::parse(source)?.run(&["key"], &["3"], INSTRUCTION_BUDGET, call)
+    }
+
+    fn eval(source: &str) -> Result<Value, String> {
+        run(source, &mut |_| String::new())
+    }
+
+    #[test]
+    fn test_expressions() {
+        assert_eq!(eval("return 1 + 2 * 3 - 4 / 2"), Ok(Value::Integer(5)));
+        assert_eq!(eval("return 'a' + \"b\" == 'ab' && !(1 > 2) || false"), Ok(Value::Boolean(true)));
+        assert_eq!(eval("return [1, 2, 3][-1] + 0.5"), Ok(Value::Float(3.5)));
+        assert_eq!(eval("x = {a: {b: [1, 2]}}; x.a.b[0] = 5; x.a['c'] = null; return x"),
+            Value::deserialize(r#"{"a": {"b": [5, 2], "c": null}}"#).map_err(String::from));
+        assert_eq!(eval("return num(ARGV[0]) + len(KEYS)"), Ok(Value::Integer(4)));
+        assert!(eval("return 1 / 0").is_err());
+        assert!(eval("return missing").is_err());
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let source = "
+            total = 0
+            i = 0
+            while true {
+                i = i + 1
+                if i % 2 == 0 { continue } else if i > 9 { break }
+                total = total + i
+            }
+            for x in [10, 20] { total = total + x }
+            return total";
+        assert_eq!(eval(source), Ok(Value::Integer(1 + 3 + 5 + 7 + 9 + 30)));
+    }
+
+    #[test]
+    fn test_budget_and_errors() {
+        assert_eq!(eval("while true {}"), Err("Instruction budget exceeded".to_string()));
+        assert!(Script::parse("x = ").is_err());
+        assert!(Script::parse("if x { ").is_err());
+        assert!(Script::parse(&"(".repeat(1000)).is_err());
+        assert_eq!(eval("error('stop')"), Err("stop".to_string()));
+    }
+
+    #[test]
+    fn test_value_limits() {
+        // both used to take the server down well within the instruction budget
+        assert_eq!(eval("x = 1; while true { x = [x] }"), Err("Maximum nesting depth exceeded".to_string()));
+        assert!(eval("s = 'a'; i = 0; while i < 40 { s = s + s; i = i + 1 }").is_err());
+        assert_eq!(eval("x = [1]; while true { x[0] = x }"), Err("Maximum nesting depth exceeded".to_string()));
+        assert_eq!(eval("s = 'a'; while true { s = s + s }"), Err("Allocation budget exceeded".to_string()));
+        assert_eq!(eval("x = [1]; i = 0; while i < 10 { x = [x]; i = i + 1 } return len(json(x))"), Ok(Value::Integer(23)));
+    }
+
+    #[test]
+    fn test_call() {
+        let mut calls = Vec::new();
+        let result = run("v = call('GET', KEYS[0]); call('SET', KEYS[0], {n: v.n + 1}); return call('PING')", &mut |command| {
+            calls.push(command.to_string());
+            match command {
+                "GET key" => r#"{"n": 1}"#.to_string(),
+                _ => "PONG".to_string()
+            }
+        });
+        assert_eq!(result, Ok(Value::String("PONG".to_string())));
+        assert_eq!(calls, vec!["GET key", r#"SET key {"n": 2}"#, "PING"]);
+    }
+
+    #[test]
+    fn test_escaping() {
+        // strings stay escaped in values, while calls, functions and comparisons see the text
+        let result = eval(r#"s = "a\"b\\c\n"; return [s, len(s), str(s) == 'a"b\\c' + "\n", json({"k\"": s}), parse('{"q": "\\""}').q]"#);
+        let result = Value::deserialize(&result.unwrap().serialize()).unwrap();
+        assert_eq!(result.serialize(), r#"["a\"b\\c\n", 6, true, "{\"k\\\"\": \"a\\\"b\\\\c\\n\"}", "\""]"#);
+        assert_eq!(eval(r#"error("bad \"x\"")"#), Err(r#"bad "x""#.to_string()));
+
+        let mut calls = Vec::new();
+        let result = Script::parse(r#"return call("SET", KEYS[0], ARGV[0])"#).unwrap().run(&[r#"k"\"#], &[r#"v"1"#], INSTRUCTION_BUDGET, &mut |command| {
+            calls.push(command.to_string());
+            r#"say "hi""#.to_string()
+        });
+        assert_eq!(calls, vec![r#"SET k"\ v"1"#]);
+        assert_eq!(result.map(|reply| reply.serialize()), Ok(r#""say \"hi\"""#.to_string()));
+    }
+
+    #[test]
+    fn test_quoting() {
+        let source = r#"x = "a\"b\\c""#;
+        let args = format!("{} 1 key", quote(source));
+        let (parsed, rest) = parse_quoted(&args).ok().unwrap();
+        assert_eq!((parsed.as_str(), rest), (source, " 1 key"));
+    }
+}
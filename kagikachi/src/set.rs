@@ -0,0 +1,209 @@
+use std::collections::{HashMap, HashSet};
+
+use mini_json::Value;
+use sockets::response::Response;
+
+use crate::storage::{Entry, Shards};
+
+#[derive(Clone, Copy)]
+pub enum Operation {
+    Inter,
+    Union,
+    Diff
+}
+
+fn wrong_type(entry: &Entry) -> Response {
+    Response::builder().set_body(format!("Invalid type: expected Set, got {}", entry.typename()))
+}
+
+/// Sorted JSON array of members, so replies don't depend on hashing order.
+/// Members are kept as they were sent, so they're escaped here.
+fn members_value<'a>(members: impl Iterator<Item = &'a String>) -> String {
+    let mut members: Vec<&String> = members.collect();
+    members.sort();
+    Value::Array(members.into_iter().map(|member| Value::from_text(member)).collect()).serialize()
+}
+
+/// `SADD key member [member ...]`, returns number of new members
+pub fn sadd_cmd(args: &str, storage: &mut HashMap<String, Entry>) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let (key, members) = match args.split_first() {
+        Some((key, members)) if !members.is_empty() => (key, members),
+        _ => return Response::builder().set_body("Invalid arguments")
+    };
+    let entry = storage.entry(key.to_string()).or_insert_with(|| Entry::Set(HashSet::new()));
+    let Entry::Set(set) = entry else {
+        return wrong_type(entry)
+    };
+    let added = members.iter().filter(|member| set.insert(member.to_string())).count();
+    Response::builder().set_body(added.to_string())
+}
+
+/// `SREM key member [member ...]`, returns number of removed members
+pub fn srem_cmd(args: &str, storage: &mut HashMap<String, Entry>) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let (key, members) = match args.split_first() {
+        Some((key, members)) if !members.is_empty() => (*key, members),
+        _ => return Response::builder().set_body("Invalid arguments")
+    };
+    let set = match storage.get_mut(key) {
+        Some(Entry::Set(set)) => set,
+        Some(entry) => return wrong_type(entry),
+        None => return Response::builder().set_body("0")
+    };
+    let removed = members.iter().filter(|member| set.remove(**member)).count();
+    if set.is_empty() {
+        storage.remove(key);
+    }
+    Response::builder().set_body(removed.to_string())
+}
+
+/// Missing key behaves like an empty set.
+fn get_set(entry: Option<&Entry>) -> Result<Option<&HashSet<String>>, Response> {
+    match entry {
+        Some(Entry::Set(set)) => Ok(Some(set)),
+        Some(entry) => Err(wrong_type(entry)),
+        None => Ok(None)
+    }
+}
+
+/// `SISMEMBER key member`
+pub fn sismember_cmd(args: &str, storage: &HashMap<String, Entry>) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let [key, member] = args.as_slice() else {
+        return Response::builder().set_body("Invalid arguments")
+    };
+    match get_set(storage.get(*key)) {
+        Ok(set) => Response::builder().set_body(set.is_some_and(|set| set.contains(*member)).to_string()),
+        Err(response) => response
+    }
+}
+
+/// `SMEMBERS key`
+pub fn smembers_cmd(args: &str, storage: &HashMap<String, Entry>) -> Response {
+    let key = args.trim();
+    match get_set(storage.get(key)) {
+        Ok(set) => Response::builder().set_body(members_value(set.into_iter().flatten())),
+        Err(response) => response
+    }
+}
+
+/// `SCARD key`
+pub fn scard_cmd(args: &str, storage: &HashMap<String, Entry>) -> Response {
+    let key = args.trim();
+    match get_set(storage.get(key)) {
+        Ok(set) => Response::builder().set_body(set.map_or(0, HashSet::len).to_string()),
+        Err(response) => response
+    }
+}
+
+fn compute(operation: Operation, keys: &[&str], shards: &Shards) -> Result<HashSet<String>, Response> {
+    let mut sets = Vec::new();
+    for key in keys {
+        sets.push(get_set(shards.get(key))?);
+    }
+    let Some((first, rest)) = sets.split_first() else {
+        return Err(Response::builder().set_body("Invalid arguments"))
+    };
+    let mut result: HashSet<String> = first.cloned().unwrap_or_default();
+    for set in rest {
+        match (operation, set) {
+            (Operation::Inter, Some(set)) => result.retain(|member| set.contains(member)),
+            (Operation::Inter, None) => result.clear(),
+            (Operation::Union, Some(set)) => result.extend(set.iter().cloned()),
+            (Operation::Diff, Some(set)) => result.retain(|member| !set.contains(member)),
+            (Operation::Union | Operation::Diff, None) => {}
+        }
+    }
+    Ok(result)
+}
+
+/// `SINTER key [key ...]`, `SUNION key [key ...]` and `SDIFF key [key ...]`
+pub fn operation_cmd(operation: Operation, args: &str, shards: &Shards) -> Response {
+    let keys: Vec<&str> = args.split_whitespace().collect();
+    match compute(operation, &keys, shards) {
+        Ok(result) => Response::builder().set_body(members_value(result.iter())),
+        Err(response) => response
+    }
+}
+
+/// `SINTERSTORE destination key [key ...]` and the other `*STORE` variants, returns size of the stored set.
+/// Empty result removes the destination, same as removing the last member would.
+pub fn operation_store_cmd(operation: Operation, args: &str, shards: &mut Shards) -> Response {
+    let keys: Vec<&str> = args.split_whitespace().collect();
+    let Some((destination, keys)) = keys.split_first() else {
+        return Response::builder().set_body("Invalid arguments")
+    };
+    let result = match compute(operation, keys, shards) {
+        Ok(result) => result,
+        Err(response) => return response
+    };
+    let len = result.len();
+    if result.is_empty() {
+        shards.remove(destination);
+    } else {
+        shards.insert(destination.to_string(), Entry::Set(result));
+    }
+    Response::builder().set_body(len.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+
+    fn storage() -> Storage {
+        let storage = Storage::new(4);
+        let mut shards = storage.write_all();
+        for (key, members) in [("a", vec!["1", "2", "3"]), ("b", vec!["2", "3", "4"]), ("c", vec!["3", "5"])] {
+            shards.insert(key.to_string(), Entry::Set(members.into_iter().map(str::to_string).collect()));
+        }
+        drop(shards);
+        storage
+    }
+
+    #[test]
+    fn test_operations() {
+        let storage = storage();
+        let shards = storage.write_all();
+        let result = |operation, keys: &[&str]| {
+            let mut members: Vec<String> = compute(operation, keys, &shards).ok().unwrap().into_iter().collect();
+            members.sort();
+            members
+        };
+        assert_eq!(result(Operation::Inter, &["a", "b", "c"]), vec!["3"]);
+        assert_eq!(result(Operation::Union, &["a", "c"]), vec!["1", "2", "3", "5"]);
+        assert_eq!(result(Operation::Diff, &["a", "b"]), vec!["1"]);
+        assert!(result(Operation::Inter, &["a", "missing"]).is_empty());
+        assert_eq!(result(Operation::Diff, &["c", "missing"]), vec!["3", "5"]);
+    }
+
+    #[test]
+    fn test_store() {
+        let storage = storage();
+        let mut shards = storage.write_all();
+        assert_eq!(operation_store_cmd(Operation::Union, "d a b", &mut shards).payload.string(), Some("4".to_string()));
+        assert!(matches!(shards.get("d"), Some(Entry::Set(set)) if set.len() == 4));
+        operation_store_cmd(Operation::Inter, "d a missing", &mut shards);
+        assert!(shards.get("d").is_none());
+    }
+
+    #[test]
+    fn test_escaping() {
+        let storage = Storage::new(4);
+        let mut shards = storage.write_all();
+        assert_eq!(sadd_cmd(r#"s a"b c\d"#, shards.shard_for_write("s")).payload.string(), Some("2".to_string()));
+        let members = smembers_cmd("s", shards.shard("s")).payload.string().unwrap();
+        let members = Value::deserialize(&members).unwrap();
+        let Value::Array(members) = members else {
+            panic!("Invalid reply {members:?}")
+        };
+        let members: Vec<String> = members.iter().map(|member| member.text().unwrap()).collect();
+        assert_eq!(members, vec![r#"a"b"#, r#"c\d"#]);
+
+        // snapshot of the set reads back to the same members
+        let entry = shards.get("s").unwrap();
+        let value = Value::deserialize(&entry.to_value().serialize()).unwrap();
+        assert!(matches!(Entry::from_value("sets", value), Ok(Entry::Set(set)) if set == HashSet::from([r#"a"b"#.to_string(), r#"c\d"#.to_string()])));
+    }
+}
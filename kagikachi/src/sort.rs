@@ -0,0 +1,219 @@
+use std::cmp::Ordering;
+
+use mini_json::{ParseLimits, Path, Value};
+use sockets::response::Response;
+
+use crate::{check_limits, parse_target, schema::Schemas, set_value, storage::{Entry, Shards}, Database};
+
+#[derive(Debug, PartialEq)]
+enum Source {
+    /// Items of the array at the path of the document
+    Array(String, Path),
+    /// Keys starting with the prefix
+    Keys(String)
+}
+
+#[derive(Debug, PartialEq)]
+struct Sort {
+    source: Source,
+    by: Option<Path>,
+    descending: bool,
+    limit: Option<(usize, usize)>,
+    store: Option<(String, Path)>
+}
+
+impl Sort {
+    fn parse(args: &str) -> Result<Self, String> {
+        let invalid = || "Invalid arguments".to_string();
+        let (source, mut rest) = match args.trim_start().split_once(char::is_whitespace) {
+            Some((keys, rest)) if keys.eq_ignore_ascii_case("keys") => {
+                let (pattern, rest) = rest.trim_start().split_once(char::is_whitespace).unwrap_or((rest.trim(), ""));
+                let prefix = pattern.strip_suffix('*').ok_or("Key pattern must end with *")?;
+                (Source::Keys(prefix.to_string()), rest)
+            },
+            _ => {
+                let (key, path, rest) = parse_target(args).map_err(|_| invalid())?;
+                (Source::Array(key, path), rest)
+            }
+        };
+        let mut sort = Sort { source, by: None, descending: false, limit: None, store: None };
+        while let Some((option, args)) = next_word(rest) {
+            rest = match option.to_ascii_lowercase().as_str() {
+                "by" => {
+                    let (by, rest) = Path::parse_prefix(args.trim_start())?;
+                    sort.by = Some(by);
+                    rest
+                },
+                "asc" | "desc" => {
+                    sort.descending = option.eq_ignore_ascii_case("desc");
+                    args
+                },
+                "limit" => {
+                    let (offset, args) = next_word(args).ok_or_else(invalid)?;
+                    let (count, args) = next_word(args).ok_or_else(invalid)?;
+                    sort.limit = Some((offset.parse().map_err(|_| invalid())?, count.parse().map_err(|_| invalid())?));
+                    args
+                },
+                "store" => {
+                    let (key, path, rest) = parse_target(args).map_err(|_| invalid())?;
+                    sort.store = Some((key, path));
+                    rest
+                },
+                _ => return Err(invalid())
+            };
+        }
+        Ok(sort)
+    }
+
+    /// Keys which have to be locked, `None` if every shard has to be.
+    fn keys(&self) -> Option<Vec<&str>> {
+        let Source::Array(key, _) = &self.source else {
+            return None
+        };
+        Some(std::iter::once(key).chain(self.store.as_ref().map(|(key, _)| key)).map(String::as_str).collect())
+    }
+
+    /// Orders items by their sort value, items without one come last in either direction.
+    fn sort(&self, items: &mut [(Value, Option<Value>)]) {
+        items.sort_by(|(_, a), (_, b)| match (a, b) {
+            (Some(a), Some(b)) if self.descending => b.total_cmp(a),
+            (Some(a), Some(b)) => a.total_cmp(b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal
+        });
+    }
+
+    fn items(&self, shards: &Shards) -> Result<Vec<(Value, Option<Value>)>, String> {
+        let by = |value: &Value| match &self.by {
+            Some(by) => value.get_element(by).ok().cloned(),
+            None => Some(value.clone())
+        };
+        match &self.source {
+            Source::Array(key, path) => match shards.get(key) {
+                Some(Entry::Value(document)) => match document.get_element(path)? {
+                    Value::Array(items) => Ok(items.iter().map(|item| (item.clone(), by(item))).collect()),
+                    value => Err(format!("Invalid type: expected Array, got {}", value.typename()))
+                },
+                Some(entry) => Err(format!("Invalid type: expected Value, got {}", entry.typename())),
+                None => Err("Key not found".to_string())
+            },
+            // keys are sorted by name, or by the field of their documents
+            Source::Keys(prefix) => Ok(shards.iter().filter(|(key, _)| key.starts_with(prefix.as_str())).map(|(key, entry)| {
+                let value = match (&self.by, entry) {
+                    (None, _) => Some(Value::from_text(key)),
+                    (Some(_), Entry::Value(document)) => by(document),
+                    (Some(_), _) => None
+                };
+                (Value::from_text(key), value)
+            }).collect())
+        }
+    }
+}
+
+fn next_word(args: &str) -> Option<(&str, &str)> {
+    let args = args.trim_start();
+    match args.split_once(char::is_whitespace) {
+        Some((word, rest)) => Some((word, rest)),
+        None if args.is_empty() => None,
+        None => Some((args, ""))
+    }
+}
+
+/// Whether `SORT` stores its result, making it a write command.
+pub fn stores(args: &str) -> bool {
+    Sort::parse(args).is_ok_and(|sort| sort.store.is_some())
+}
+
+/// `SORT key.path|KEYS prefix* [BY field] [ASC|DESC] [LIMIT offset count] [STORE key.path]`
+///
+/// Sorts items of the array, or keys matching the pattern, by their value or value of `field`, using total ordering of values.
+/// Returns sorted items, with `STORE` their count, as the array is written to the destination instead.
+pub fn sort_cmd(args: &str, shards: &mut Shards, schemas: &Schemas, limits: &ParseLimits) -> Response {
+    let sort = match Sort::parse(args) {
+        Ok(sort) => sort,
+        Err(e) => return Response::builder().set_body(e)
+    };
+    let mut items = match sort.items(shards) {
+        Ok(items) => items,
+        Err(e) => return Response::builder().set_body(e)
+    };
+    sort.sort(&mut items);
+    let (offset, count) = sort.limit.unwrap_or((0, items.len()));
+    let sorted: Vec<Value> = items.into_iter().skip(offset).take(count).map(|(item, _)| item).collect();
+    let Some((key, path)) = sort.store else {
+        return Response::builder().set_body(Value::Array(sorted).serialize())
+    };
+    let count = sorted.len();
+    let sorted = Value::Array(sorted);
+    if let Err(e) = check_limits(&path, &sorted, limits) {
+        return Response::builder().set_body(e)
+    }
+    match set_value(key.clone(), &path, sorted, true, shards.shard_for_write(&key), schemas) {
+        Ok(()) => Response::builder().set_body(count.to_string()),
+        Err(response) => response
+    }
+}
+
+/// Runs `SORT` with locks it needs, key scans lock every shard. Only `STORE` is propagated as a write,
+/// without it shards are only read locked.
+pub fn sort(args: &str, message: &str, db: &Database) -> Response {
+    let sort = match Sort::parse(args) {
+        Ok(sort) => sort,
+        Err(e) => return Response::builder().set_body(e)
+    };
+    match (sort.keys(), sort.store.is_some()) {
+        (Some(keys), true) => db.write_keys(&keys, message, |shards| sort_cmd(args, shards, &db.schemas.read().unwrap(), &db.parse_limits)),
+        (Some(keys), false) => sort_cmd(args, &mut db.storage.read_keys(&keys), &db.schemas.read().unwrap(), &db.parse_limits),
+        (None, true) => db.write_all(message, |shards| sort_cmd(args, shards, &db.schemas.read().unwrap(), &db.parse_limits)),
+        (None, false) => sort_cmd(args, &mut db.storage.read_all(), &db.schemas.read().unwrap(), &db.parse_limits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mini_json::Segment;
+    use crate::storage::Storage;
+
+    #[test]
+    fn test_parse() {
+        let sort = Sort::parse("cart.items BY price DESC LIMIT 0 10 STORE cart.sorted").unwrap();
+        assert_eq!(sort, Sort {
+            source: Source::Array("cart".to_string(), Path::parse("items").unwrap()),
+            by: Some(Path::parse("price").unwrap()),
+            descending: true,
+            limit: Some((0, 10)),
+            store: Some(("cart".to_string(), Path::parse("sorted").unwrap()))
+        });
+        assert_eq!(Sort::parse("KEYS user:* BY age").unwrap().source, Source::Keys("user:".to_string()));
+        assert!(Sort::parse("KEYS user").is_err());
+        assert!(Sort::parse("a LIMIT 1").is_err());
+        assert!(Sort::parse("a UP").is_err());
+    }
+
+    #[test]
+    fn test_sort() {
+        let storage = Storage::new(4);
+        let mut shards = storage.write_all();
+        let schemas = Schemas::default();
+        let limits = ParseLimits::default();
+        let items = r#"{"items": [{"price": 3}, {"price": 1.5}, {}, {"price": "x"}, {"price": 2}]}"#;
+        shards.insert("cart".to_string(), Entry::Value(Value::deserialize(items).unwrap()));
+        for (key, age) in [("user:1", 30), ("user:2", 20), ("user:3", 25), (r#"user:"4\"#, 10)] {
+            shards.insert(key.to_string(), Entry::Value(Value::Object([("age".to_string(), Value::Integer(age))].into())));
+        }
+        let body = |response: Response| response.payload.string().unwrap();
+
+        assert_eq!(body(sort_cmd("cart.items BY price", &mut shards, &schemas, &limits)), r#"[{"price": 1.5}, {"price": 2}, {"price": 3}, {"price": "x"}, {}]"#);
+        assert_eq!(body(sort_cmd("cart.items BY price DESC LIMIT 1 2", &mut shards, &schemas, &limits)), r#"[{"price": 3}, {"price": 2}]"#);
+        assert_eq!(body(sort_cmd("KEYS user:* BY age DESC", &mut shards, &schemas, &limits)), r#"["user:1", "user:3", "user:2", "user:\"4\\"]"#);
+        assert_eq!(body(sort_cmd("KEYS user:* STORE users", &mut shards, &schemas, &limits)), "4");
+        let users = shards.get("users").and_then(Entry::value).and_then(|users| users.get_element(&Path::from(vec![Segment::Index(-1)])).ok());
+        assert_eq!(users, Some(&Value::from_text(r#"user:"4\"#)));
+        // storing sorted items nests them one level deeper each time
+        let limits = ParseLimits { max_depth: 3, ..limits };
+        assert_eq!(body(sort_cmd("cart.items STORE cart.items", &mut shards, &schemas, &limits)), "5");
+        assert_eq!(body(sort_cmd("cart.items STORE cart.items.0", &mut shards, &schemas, &limits)), "Maximum nesting depth exceeded");
+    }
+}
@@ -0,0 +1,321 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash as _, Hasher as _},
+    ops::Deref,
+    sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard}
+};
+
+use mini_json::{escape, unescape, Value};
+
+use crate::{stream::Stream, zset::SortedSet};
+
+/// Sections of a snapshot, one for each entry type. Documents are kept apart from the other types,
+/// so there's nothing a document could hold to be taken for one of them.
+const SECTIONS: [&str; 4] = ["values", "sorted_sets", "sets", "streams"];
+
+/// Anything that can be stored under a key.
+#[derive(Clone, Debug)]
+pub enum Entry {
+    Value(Value),
+    SortedSet(SortedSet),
+    Set(HashSet<String>),
+    Stream(Stream)
+}
+
+impl Entry {
+    pub fn typename(&self) -> &'static str {
+        match self {
+            Entry::Value(_) => "Value",
+            Entry::SortedSet(_) => "SortedSet",
+            Entry::Set(_) => "Set",
+            Entry::Stream(_) => "Stream"
+        }
+    }
+
+    pub fn value(&self) -> Option<&Value> {
+        match self {
+            Entry::Value(value) => Some(value),
+            _ => None
+        }
+    }
+
+    pub fn value_mut(&mut self) -> Result<&mut Value, String> {
+        match self {
+            Entry::Value(value) => Ok(value),
+            _ => Err(format!("Invalid type: expected Value, got {}", self.typename()))
+        }
+    }
+
+    /// Name of the snapshot section holding entries of this type.
+    pub fn section(&self) -> &'static str {
+        match self {
+            Entry::Value(_) => "values",
+            Entry::SortedSet(_) => "sorted_sets",
+            Entry::Set(_) => "sets",
+            Entry::Stream(_) => "streams"
+        }
+    }
+
+    /// JSON representation used by DUMP, LOAD and snapshots, the type is told by the section the entry is in.
+    pub fn to_value(&self) -> Value {
+        match self {
+            Entry::Value(value) => value.clone(),
+            Entry::SortedSet(set) => set.to_value(),
+            Entry::Set(set) => {
+                let mut members: Vec<&String> = set.iter().collect();
+                members.sort();
+                Value::Array(members.into_iter().map(|member| Value::from_text(member)).collect())
+            },
+            Entry::Stream(stream) => stream.to_value()
+        }
+    }
+
+    /// Inverse of `to_value`, for entry of the given section.
+    pub fn from_value(section: &str, value: Value) -> Result<Self, String> {
+        let invalid = || format!("Invalid entry in {section}");
+        match section {
+            "values" => Ok(Entry::Value(value)),
+            "sorted_sets" => SortedSet::from_value(&value).map(Entry::SortedSet).ok_or_else(invalid),
+            "sets" => match value {
+                Value::Array(members) => members.iter().map(|member| member.text().ok()).collect::<Option<_>>().map(Entry::Set).ok_or_else(invalid),
+                _ => Err(invalid())
+            },
+            "streams" => Stream::from_value(&value).map(Entry::Stream).ok_or_else(invalid),
+            _ => Err(format!("Unknown section {section}"))
+        }
+    }
+}
+
+/// Groups entries into snapshot sections, `{"values": {key: document}, "sets": {key: [member]}, ...}`.
+/// Sections without entries are left out.
+pub fn snapshot<'a>(entries: impl Iterator<Item = (&'a String, &'a Entry)>) -> HashMap<String, Value> {
+    let mut sections: HashMap<String, HashMap<String, Value>> = HashMap::new();
+    for (key, entry) in entries {
+        sections.entry(entry.section().to_string()).or_default().insert(escape(key), entry.to_value());
+    }
+    sections.into_iter().map(|(section, entries)| (section, Value::Object(entries))).collect()
+}
+
+/// Takes entries out of their snapshot sections, leaving other sections in place.
+pub fn take_entries(snapshot: &mut HashMap<String, Value>) -> Result<Vec<(String, Entry)>, String> {
+    let mut entries = Vec::new();
+    for section in SECTIONS {
+        match snapshot.remove(section) {
+            Some(Value::Object(map)) => for (key, value) in map {
+                let key = unescape(&key).map_err(|_| format!("Invalid key in {section}"))?;
+                entries.push((key, Entry::from_value(section, value)?));
+            },
+            Some(_) => return Err(format!("Invalid section {section}")),
+            None => ()
+        }
+    }
+    Ok(entries)
+}
+
+pub type Shard = HashMap<String, Entry>;
+
+/// Key-value storage split into hash partitions, each behind its own lock.
+/// Readers never block each other and writers only block their own shard.
+pub struct Storage {
+    shards: Vec<RwLock<Shard>>,
+    /// One for each shard, taken by writers before they unlock it and held while indexes and replicas catch up,
+    /// so that work is done outside the shard lock, yet in the order the writes were applied.
+    after_write: Vec<Mutex<()>>
+}
+
+impl Storage {
+    pub fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1)).map(|_| RwLock::new(HashMap::new())).collect(),
+            after_write: (0..shards.max(1)).map(|_| Mutex::new(())).collect()
+        }
+    }
+
+    fn index(&self, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    pub fn read(&self, key: &str) -> RwLockReadGuard<'_, Shard> {
+        self.shards[self.index(key)].read().unwrap()
+    }
+
+    pub fn write(&self, key: &str) -> RwLockWriteGuard<'_, Shard> {
+        self.shards[self.index(key)].write().unwrap()
+    }
+
+    /// Orders what follows a write to the shard owning `key`, must be taken while the shard is still locked.
+    pub fn after_write(&self, key: &str) -> MutexGuard<'_, ()> {
+        self.after_write[self.index(key)].lock().unwrap()
+    }
+
+    /// Locks every shard for writing. Shards are always locked in the same order, so concurrent callers can't deadlock.
+    pub fn write_all(&self) -> Shards<'_> {
+        self.lock(|_| true, |shard| Guard::Write(shard.write().unwrap()))
+    }
+
+    /// Locks shards owning given keys for writing, in the same order as `write_all`.
+    pub fn write_keys(&self, keys: &[impl AsRef<str>]) -> Shards<'_> {
+        let indexes: HashSet<usize> = keys.iter().map(|key| self.index(key.as_ref())).collect();
+        self.lock(|i| indexes.contains(&i), |shard| Guard::Write(shard.write().unwrap()))
+    }
+
+    /// Same as `write_all`, for commands that only read, so they don't block each other.
+    pub fn read_all(&self) -> Shards<'_> {
+        self.lock(|_| true, |shard| Guard::Read(shard.read().unwrap()))
+    }
+
+    /// Same as `write_keys`, for commands that only read.
+    pub fn read_keys(&self, keys: &[impl AsRef<str>]) -> Shards<'_> {
+        let indexes: HashSet<usize> = keys.iter().map(|key| self.index(key.as_ref())).collect();
+        self.lock(|i| indexes.contains(&i), |shard| Guard::Read(shard.read().unwrap()))
+    }
+
+    fn lock<'a>(&'a self, selected: impl Fn(usize) -> bool, lock: impl Fn(&'a RwLock<Shard>) -> Guard<'a>) -> Shards<'a> {
+        Shards {
+            storage: self,
+            guards: self.shards.iter().enumerate().map(|(i, shard)| selected(i).then(|| lock(shard))).collect(),
+            touched: HashSet::new()
+        }
+    }
+
+    /// Snapshot of the whole storage, all read locks are held so the copy is consistent.
+    pub fn dump(&self) -> HashMap<String, Value> {
+        let guards: Vec<_> = self.shards.iter().map(|shard| shard.read().unwrap()).collect();
+        snapshot(guards.iter().flat_map(|shard| shard.iter()))
+    }
+
+    /// Same as `dump`, but hands entries over one by one instead of copying them.
+    pub fn scan<E>(&self, mut f: impl FnMut(&str, &Entry) -> Result<(), E>) -> Result<(), E> {
+        let guards: Vec<_> = self.shards.iter().map(|shard| shard.read().unwrap()).collect();
+        guards.iter().flat_map(|shard| shard.iter()).try_for_each(|(key, entry)| f(key, entry))
+    }
+}
+
+enum Guard<'a> {
+    Read(RwLockReadGuard<'a, Shard>),
+    Write(RwLockWriteGuard<'a, Shard>)
+}
+
+impl Deref for Guard<'_> {
+    type Target = Shard;
+
+    fn deref(&self) -> &Shard {
+        match self {
+            Guard::Read(shard) => shard,
+            Guard::Write(shard) => shard
+        }
+    }
+}
+
+/// Group of shards locked for reading or writing, used by commands touching many keys at once.
+/// Accessing key whose shard was not locked, or changing one locked for reading, is a bug and panics.
+pub struct Shards<'a> {
+    storage: &'a Storage,
+    guards: Vec<Option<Guard<'a>>>,
+    touched: HashSet<String>
+}
+
+impl<'a> Shards<'a> {
+    /// Same as `Storage::after_write`, for every locked shard.
+    pub fn after_write(&self) -> Vec<MutexGuard<'a, ()>> {
+        let storage = self.storage;
+        self.guards.iter().enumerate().filter(|(_, guard)| guard.is_some()).map(|(i, _)| storage.after_write[i].lock().unwrap()).collect()
+    }
+
+    /// Whole shard owning `key`, lets single key commands run on already locked shards.
+    pub fn shard(&self, key: &str) -> &Shard {
+        self.guards[self.storage.index(key)].as_deref().expect("Shard of the key is not locked")
+    }
+
+    /// Same as `shard`, `key` is considered touched.
+    pub fn shard_for_write(&mut self, key: &str) -> &mut Shard {
+        self.touched.insert(key.to_string());
+        self.shard_mut(key)
+    }
+
+    fn shard_mut(&mut self, key: &str) -> &mut Shard {
+        let index = self.storage.index(key);
+        match self.guards[index].as_mut().expect("Shard of the key is not locked") {
+            Guard::Write(shard) => shard,
+            Guard::Read(_) => panic!("Shard of the key is locked for reading")
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Entry> {
+        self.shard(key).get(key)
+    }
+
+    #[allow(unused)]
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Entry> {
+        self.touched.insert(key.to_string());
+        self.shard_mut(key).get_mut(key)
+    }
+
+    pub fn insert(&mut self, key: String, value: Entry) -> Option<Entry> {
+        self.touched.insert(key.clone());
+        self.shard_mut(&key).insert(key, value)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Entry> {
+        self.touched.insert(key.to_string());
+        self.shard_mut(key).remove(key)
+    }
+
+    /// Keys that might have been modified since shards were locked.
+    pub fn touched(&self) -> &HashSet<String> {
+        &self.touched
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Entry)> {
+        self.guards.iter().flatten().flat_map(|shard| shard.iter())
+    }
+
+    /// JSON documents only, skipping other entry types.
+    pub fn documents(&self) -> impl Iterator<Item = (&String, &Value)> {
+        self.iter().filter_map(|(key, entry)| Some((key, entry.value()?)))
+    }
+
+    pub fn dump(&self) -> HashMap<String, Value> {
+        snapshot(self.iter())
+    }
+
+    pub fn clear(&mut self) {
+        for guard in self.guards.iter_mut().flatten() {
+            match guard {
+                Guard::Write(shard) => shard.clear(),
+                Guard::Read(_) => panic!("Shard is locked for reading")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let storage = Storage::new(4);
+        let mut shards = storage.write_all();
+        // documents shaped like the other types stay documents
+        let document = Value::deserialize(r#"{"$set": ["a", "b"]}"#).unwrap();
+        shards.insert("document".to_string(), Entry::Value(document.clone()));
+        shards.insert("set".to_string(), Entry::Set(HashSet::from(["a".to_string(), "b".to_string()])));
+        let mut scores = SortedSet::default();
+        scores.insert(r#"a"b"#, 1.0);
+        shards.insert(r#"scores ""#.to_string(), Entry::SortedSet(scores.clone()));
+        let mut snapshot = Value::deserialize(&Value::Object(shards.dump()).serialize()).unwrap().object().unwrap().clone();
+        assert_eq!(snapshot.len(), 3);
+        let mut entries = take_entries(&mut snapshot).unwrap();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        assert!(matches!(&entries[0], (key, Entry::Value(value)) if key == "document" && *value == document));
+        assert!(matches!(&entries[1], (key, Entry::SortedSet(set)) if key == r#"scores ""# && *set == scores));
+        assert!(matches!(&entries[2], (key, Entry::Set(set)) if key == "set" && set.len() == 2));
+        assert!(snapshot.is_empty());
+
+        let mut invalid = HashMap::from([("sets".to_string(), Value::deserialize(r#"{"a": [1]}"#).unwrap())]);
+        assert!(take_entries(&mut invalid).is_err());
+    }
+}
@@ -0,0 +1,447 @@
+use std::{collections::{BTreeMap, HashMap}, ops::Bound};
+
+use mini_json::{escape, unescape, ParseLimits, Value};
+use sockets::response::Response;
+
+use crate::storage::Entry;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Pending {
+    consumer: String,
+    deliveries: usize
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Group {
+    last_delivered: u64,
+    pending: BTreeMap<u64, Pending>
+}
+
+/// Append-only log of JSON entries. Ids are assigned from a counter, so replicas replaying
+/// the same commands end up with the same ids.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stream {
+    entries: BTreeMap<u64, Value>,
+    last_id: u64,
+    groups: HashMap<String, Group>
+}
+
+impl Stream {
+    pub fn add(&mut self, value: Value) -> u64 {
+        self.last_id += 1;
+        self.entries.insert(self.last_id, value);
+        self.last_id
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Drops oldest entries until at most `max_len` remain, returns number of dropped entries.
+    pub fn trim(&mut self, max_len: usize) -> usize {
+        let mut trimmed = 0;
+        while self.entries.len() > max_len {
+            self.entries.pop_first();
+            trimmed += 1;
+        }
+        trimmed
+    }
+
+    pub fn range(&self, start: u64, end: u64, count: usize) -> Vec<(u64, &Value)> {
+        if start > end {
+            return Vec::new()
+        }
+        self.entries.range(start..=end).take(count).map(|(id, value)| (*id, value)).collect()
+    }
+
+    /// New group starts after `start`, so `0` delivers the whole stream and `last_id` only new entries.
+    pub fn create_group(&mut self, name: &str, start: u64) -> bool {
+        if self.groups.contains_key(name) {
+            return false
+        }
+        self.groups.insert(name.to_string(), Group { last_delivered: start, pending: BTreeMap::new() });
+        true
+    }
+
+    pub fn destroy_group(&mut self, name: &str) -> bool {
+        self.groups.remove(name).is_some()
+    }
+
+    /// Delivers up to `count` entries never delivered to the group and marks them pending for `consumer`.
+    pub fn read_new(&mut self, group: &str, consumer: &str, count: usize) -> Option<Vec<(u64, Value)>> {
+        let group = self.groups.get_mut(group)?;
+        let entries: Vec<(u64, Value)> = self.entries.range((Bound::Excluded(group.last_delivered), Bound::Unbounded))
+            .take(count)
+            .map(|(id, value)| (*id, value.clone()))
+            .collect();
+        for (id, _) in &entries {
+            group.pending.insert(*id, Pending { consumer: consumer.to_string(), deliveries: 1 });
+            group.last_delivered = *id;
+        }
+        Some(entries)
+    }
+
+    /// Delivers again entries pending for `consumer` with id greater than `after`.
+    /// Entries trimmed in the meantime are delivered as `null`, so they can still be acknowledged.
+    pub fn read_pending(&mut self, group: &str, consumer: &str, after: u64, count: usize) -> Option<Vec<(u64, Value)>> {
+        let group = self.groups.get_mut(group)?;
+        let mut entries = Vec::new();
+        for (id, pending) in group.pending.range_mut((Bound::Excluded(after), Bound::Unbounded)) {
+            if entries.len() == count {
+                break
+            }
+            if pending.consumer != consumer {
+                continue
+            }
+            pending.deliveries += 1;
+            entries.push((*id, self.entries.get(id).cloned().unwrap_or(Value::Null)));
+        }
+        Some(entries)
+    }
+
+    pub fn ack(&mut self, group: &str, ids: &[u64]) -> Option<usize> {
+        let group = self.groups.get_mut(group)?;
+        Some(ids.iter().filter(|id| group.pending.remove(id).is_some()).count())
+    }
+
+    fn pending_value(&self, group: &str) -> Option<Value> {
+        let group = self.groups.get(group)?;
+        Some(Value::Array(group.pending.iter().map(|(id, pending)| Value::Array(vec![
+            Value::Integer(*id as isize),
+            Value::from_text(&pending.consumer),
+            Value::Integer(pending.deliveries as isize)
+        ])).collect()))
+    }
+
+    pub fn to_value(&self) -> Value {
+        let entries = self.entries.iter().map(|(id, value)| entry_value(*id, value.clone())).collect();
+        let groups = self.groups.iter().map(|(name, group)| {
+            let group_value = Value::Object(HashMap::from([
+                ("last_delivered".to_string(), Value::Integer(group.last_delivered as isize)),
+                ("pending".to_string(), self.pending_value(name).unwrap_or(Value::Array(vec![])))
+            ]));
+            (escape(name), group_value)
+        }).collect();
+        Value::Object(HashMap::from([
+            ("last_id".to_string(), Value::Integer(self.last_id as isize)),
+            ("entries".to_string(), Value::Array(entries)),
+            ("groups".to_string(), Value::Object(groups))
+        ]))
+    }
+
+    /// Inverse of `to_value`
+    pub fn from_value(value: &Value) -> Option<Self> {
+        let id = |value: &Value| value.integer().ok().and_then(|i| u64::try_from(i).ok());
+        let object = value.object().ok()?;
+        let mut stream = Stream {
+            last_id: id(object.get("last_id")?)?,
+            ..Stream::default()
+        };
+        for entry in object.get("entries")?.array().ok()? {
+            match entry.array().ok()?.as_slice() {
+                [entry_id, value] => stream.entries.insert(id(entry_id)?, value.clone()),
+                _ => return None
+            };
+        }
+        for (name, group) in object.get("groups")?.object().ok()? {
+            let group = group.object().ok()?;
+            let mut pending = BTreeMap::new();
+            for entry in group.get("pending")?.array().ok()? {
+                match entry.array().ok()?.as_slice() {
+                    [entry_id, consumer, deliveries] => pending.insert(id(entry_id)?, Pending {
+                        consumer: consumer.text().ok()?,
+                        deliveries: id(deliveries)? as usize
+                    }),
+                    _ => return None
+                };
+            }
+            stream.groups.insert(unescape(name).ok()?, Group { last_delivered: id(group.get("last_delivered")?)?, pending });
+        }
+        Some(stream)
+    }
+}
+
+fn entry_value(id: u64, value: Value) -> Value {
+    Value::Array(vec![Value::Integer(id as isize), value])
+}
+
+fn entries_value(entries: Vec<(u64, Value)>) -> String {
+    Value::Array(entries.into_iter().map(|(id, value)| entry_value(id, value)).collect()).serialize()
+}
+
+fn wrong_type(entry: &Entry) -> Response {
+    Response::builder().set_body(format!("Invalid type: expected Stream, got {}", entry.typename()))
+}
+
+fn get_stream_mut<'a>(key: &str, storage: &'a mut HashMap<String, Entry>) -> Result<&'a mut Stream, Response> {
+    match storage.get_mut(key) {
+        Some(Entry::Stream(stream)) => Ok(stream),
+        Some(entry) => Err(wrong_type(entry)),
+        None => Err(Response::builder().set_body("Key not found"))
+    }
+}
+
+fn parse_id(id: &str) -> Result<u64, Response> {
+    id.parse().map_err(|_| Response::builder().set_body(format!("Invalid id {id}")))
+}
+
+fn parse_count(count: &str) -> Result<usize, Response> {
+    count.parse().map_err(|_| Response::builder().set_body(format!("Invalid count {count}")))
+}
+
+/// Splits off leading `NAME value` option, eg. `MAXLEN 100 rest of args`.
+fn option<'a>(args: &'a str, name: &str) -> (Option<&'a str>, &'a str) {
+    let mut parts = args.trim_start().splitn(3, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(option), Some(value)) if option.eq_ignore_ascii_case(name) => (Some(value), parts.next().unwrap_or_default()),
+        _ => (None, args)
+    }
+}
+
+/// `XADD key [MAXLEN n] value`, returns id of the new entry
+pub fn xadd_cmd(args: &str, storage: &mut HashMap<String, Entry>, limits: &ParseLimits) -> Response {
+    let (key, args) = match args.split_once(" ") {
+        Some((key, args)) => (key, args),
+        None => return Response::builder().set_body("Invalid arguments")
+    };
+    let (max_len, value) = match option(args, "maxlen") {
+        (Some(max_len), value) => match parse_count(max_len) {
+            Ok(max_len) => (Some(max_len), value),
+            Err(response) => return response
+        },
+        (None, value) => (None, value)
+    };
+    let value = match Value::deserialize_with(value, limits) {
+        Ok(v) => v,
+        Err(e) => return Response::builder().set_body(format!("Invalid value: {e:?}"))
+    };
+    let entry = storage.entry(key.to_string()).or_insert_with(|| Entry::Stream(Stream::default()));
+    let Entry::Stream(stream) = entry else {
+        return wrong_type(entry)
+    };
+    let id = stream.add(value);
+    if let Some(max_len) = max_len {
+        stream.trim(max_len);
+    }
+    Response::builder().set_body(id.to_string())
+}
+
+/// `XTRIM key MAXLEN n`, returns number of removed entries
+pub fn xtrim_cmd(args: &str, storage: &mut HashMap<String, Entry>) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let [key, maxlen, max_len] = args.as_slice() else {
+        return Response::builder().set_body("Invalid arguments")
+    };
+    if !maxlen.eq_ignore_ascii_case("maxlen") {
+        return Response::builder().set_body("Invalid arguments")
+    }
+    let max_len = match parse_count(max_len) {
+        Ok(max_len) => max_len,
+        Err(response) => return response
+    };
+    match get_stream_mut(key, storage) {
+        Ok(stream) => Response::builder().set_body(stream.trim(max_len).to_string()),
+        Err(response) => response
+    }
+}
+
+/// `XLEN key`
+pub fn xlen_cmd(args: &str, storage: &HashMap<String, Entry>) -> Response {
+    match storage.get(args.trim()) {
+        Some(Entry::Stream(stream)) => Response::builder().set_body(stream.len().to_string()),
+        Some(entry) => wrong_type(entry),
+        None => Response::builder().set_body("0")
+    }
+}
+
+/// `XRANGE key start end [COUNT n]`, `-` and `+` stand for the first and the last id
+pub fn xrange_cmd(args: &str, storage: &HashMap<String, Entry>) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let (key, start, end, count) = match args.as_slice() {
+        [key, start, end] => (*key, *start, *end, None),
+        [key, start, end, option, count] if option.eq_ignore_ascii_case("count") => (*key, *start, *end, Some(*count)),
+        _ => return Response::builder().set_body("Invalid arguments")
+    };
+    let parse = |id: &str, default: u64| match id {
+        "-" | "+" => Ok(default),
+        id => parse_id(id)
+    };
+    let (start, end, count) = match (parse(start, 0), parse(end, u64::MAX), count.map(parse_count).unwrap_or(Ok(usize::MAX))) {
+        (Ok(start), Ok(end), Ok(count)) => (start, end, count),
+        (Err(response), _, _) | (_, Err(response), _) | (_, _, Err(response)) => return response
+    };
+    let stream = match storage.get(key) {
+        Some(Entry::Stream(stream)) => stream,
+        Some(entry) => return wrong_type(entry),
+        None => return Response::builder().set_body("[]")
+    };
+    let entries = stream.range(start, end, count).into_iter().map(|(id, value)| (id, value.clone())).collect();
+    Response::builder().set_body(entries_value(entries))
+}
+
+/// `XGROUP CREATE key group [$|0|id]` or `XGROUP DESTROY key group`
+///
+/// New group reads only entries added after its creation (`$`) unless told where to start.
+/// Creating group creates empty stream as well.
+pub fn xgroup_cmd(args: &str, storage: &mut HashMap<String, Entry>) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    match args.as_slice() {
+        [subcommand, key, group, start @ ..] if subcommand.eq_ignore_ascii_case("create") && start.len() <= 1 => {
+            let entry = storage.entry(key.to_string()).or_insert_with(|| Entry::Stream(Stream::default()));
+            let Entry::Stream(stream) = entry else {
+                return wrong_type(entry)
+            };
+            let start = match start.first() {
+                None | Some(&"$") => stream.last_id,
+                Some(id) => match parse_id(id) {
+                    Ok(id) => id,
+                    Err(response) => return response
+                }
+            };
+            match stream.create_group(group, start) {
+                true => Response::builder().set_body("OK"),
+                false => Response::builder().set_body("Group already exists")
+            }
+        },
+        [subcommand, key, group] if subcommand.eq_ignore_ascii_case("destroy") => {
+            match get_stream_mut(key, storage).map(|stream| stream.destroy_group(group)) {
+                Ok(true) => Response::builder().set_body("OK"),
+                Ok(false) => Response::builder().set_body("Group not found"),
+                Err(response) => response
+            }
+        },
+        _ => Response::builder().set_body("Invalid arguments")
+    }
+}
+
+/// `XREADGROUP group consumer key [COUNT n] [>|id]`
+///
+/// `>` (default) delivers entries not yet delivered to the group, any other id
+/// delivers again entries pending for the consumer after that id.
+pub fn xreadgroup_cmd(args: &str, storage: &mut HashMap<String, Entry>) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let (group, consumer, key, rest) = match args.as_slice() {
+        [group, consumer, key, rest @ ..] => (*group, *consumer, *key, rest),
+        _ => return Response::builder().set_body("Invalid arguments")
+    };
+    let (count, id) = match rest {
+        [] => (Ok(usize::MAX), ">"),
+        [id] => (Ok(usize::MAX), *id),
+        [option, count] if option.eq_ignore_ascii_case("count") => (parse_count(count), ">"),
+        [option, count, id] if option.eq_ignore_ascii_case("count") => (parse_count(count), *id),
+        _ => return Response::builder().set_body("Invalid arguments")
+    };
+    let count = match count {
+        Ok(count) => count,
+        Err(response) => return response
+    };
+    let stream = match get_stream_mut(key, storage) {
+        Ok(stream) => stream,
+        Err(response) => return response
+    };
+    let entries = match id {
+        ">" => stream.read_new(group, consumer, count),
+        id => match parse_id(id) {
+            Ok(id) => stream.read_pending(group, consumer, id, count),
+            Err(response) => return response
+        }
+    };
+    match entries {
+        Some(entries) => Response::builder().set_body(entries_value(entries)),
+        None => Response::builder().set_body("Group not found")
+    }
+}
+
+/// `XACK key group id [id ...]`, returns number of acknowledged entries
+pub fn xack_cmd(args: &str, storage: &mut HashMap<String, Entry>) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let (key, group, ids) = match args.as_slice() {
+        [key, group, ids @ ..] if !ids.is_empty() => (*key, *group, ids),
+        _ => return Response::builder().set_body("Invalid arguments")
+    };
+    let ids: Result<Vec<u64>, Response> = ids.iter().map(|id| parse_id(id)).collect();
+    let ids = match ids {
+        Ok(ids) => ids,
+        Err(response) => return response
+    };
+    match get_stream_mut(key, storage).map(|stream| stream.ack(group, &ids)) {
+        Ok(Some(acked)) => Response::builder().set_body(acked.to_string()),
+        Ok(None) => Response::builder().set_body("Group not found"),
+        Err(response) => response
+    }
+}
+
+/// `XPENDING key group`, returns `[id, consumer, deliveries]` of every unacknowledged entry
+pub fn xpending_cmd(args: &str, storage: &HashMap<String, Entry>) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let [key, group] = args.as_slice() else {
+        return Response::builder().set_body("Invalid arguments")
+    };
+    match storage.get(*key) {
+        Some(Entry::Stream(stream)) => match stream.pending_value(group) {
+            Some(pending) => Response::builder().set_body(pending.serialize()),
+            None => Response::builder().set_body("Group not found")
+        },
+        Some(entry) => wrong_type(entry),
+        None => Response::builder().set_body("Key not found")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream() -> Stream {
+        let mut stream = Stream::default();
+        for i in 0..5 {
+            stream.add(Value::Integer(i));
+        }
+        stream
+    }
+
+    #[test]
+    fn test_ids_and_trim() {
+        let mut stream = stream();
+        assert_eq!(stream.trim(3), 2);
+        assert_eq!(stream.add(Value::Null), 6);
+        let ids: Vec<u64> = stream.range(0, u64::MAX, usize::MAX).into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![3, 4, 5, 6]);
+        assert_eq!(stream.range(4, 5, 1).len(), 1);
+    }
+
+    #[test]
+    fn test_consumer_group() {
+        let mut stream = stream();
+        assert!(stream.create_group("workers", 0));
+        assert!(!stream.create_group("workers", 0));
+        let first = stream.read_new("workers", "a", 2).unwrap();
+        let second = stream.read_new("workers", "b", 10).unwrap();
+        assert_eq!(first.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(second.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![3, 4, 5]);
+        assert!(stream.read_new("workers", "a", 10).unwrap().is_empty());
+
+        assert_eq!(stream.ack("workers", &[1, 3, 42]), Some(2));
+        let pending = stream.read_pending("workers", "a", 0, 10).unwrap();
+        assert_eq!(pending, vec![(2, Value::Integer(1))]);
+        assert_eq!(stream.ack("missing", &[2]), None);
+
+        // ids at the end of the range don't overflow
+        assert!(stream.create_group("last", u64::MAX));
+        assert!(stream.read_new("last", "a", 10).unwrap().is_empty());
+        assert!(stream.read_pending("workers", "a", u64::MAX, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_value_roundtrip() {
+        let mut stream = stream();
+        stream.create_group("workers", 2);
+        stream.read_new("workers", "a", 1);
+        stream.create_group(r#"q"\"#, 0);
+        stream.read_new(r#"q"\"#, r#"c"\"#, 2);
+        let value = Value::deserialize(&stream.to_value().serialize()).unwrap();
+        assert_eq!(Stream::from_value(&value), Some(stream.clone()));
+        let pending = Value::deserialize(&stream.pending_value(r#"q"\"#).unwrap().serialize()).unwrap();
+        let consumer = pending.array().unwrap()[0].array().unwrap()[1].text().unwrap();
+        assert_eq!(consumer, r#"c"\"#);
+    }
+}
@@ -0,0 +1,54 @@
+use std::{net::TcpListener, thread, time::{Duration, Instant}};
+
+use sockets::{response::Response, SocketClient};
+
+use crate::{config::Config, run, server, Database};
+
+/// Starts server with the config on a free port, for tests going through real connections.
+/// The server runs until the test process exits, its data is returned to check state no command shows.
+pub fn start(config: Config) -> (&'static Database, String) {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let config: &'static Config = Box::leak(Box::new(Config { port, ..config }));
+    let server: &'static _ = Box::leak(Box::new(server(config)));
+    let db = server.data();
+    thread::spawn(move || run(server, config));
+    (db, format!("127.0.0.1:{port}"))
+}
+
+/// Polls the condition until it holds, failing the test if it doesn't within a few seconds.
+pub fn wait_until(condition: impl Fn() -> bool) {
+    let start = Instant::now();
+    while !condition() {
+        assert!(start.elapsed() < Duration::from_secs(5), "Condition not met in time");
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+pub struct Connection(SocketClient);
+
+impl Connection {
+    /// Connects once the server started listening.
+    pub fn open(address: &str) -> Self {
+        let start = Instant::now();
+        loop {
+            match SocketClient::connect(address) {
+                Ok(client) => return Self(client),
+                Err(e) if start.elapsed() > Duration::from_secs(5) => panic!("Cannot connect to {address}: {e}"),
+                Err(_) => thread::sleep(Duration::from_millis(10))
+            }
+        }
+    }
+
+    pub fn send(&mut self, message: &str) {
+        self.0.send(Response::builder().set_body(message)).unwrap();
+    }
+
+    pub fn read(&mut self) -> String {
+        self.0.read().unwrap().payload.string().unwrap()
+    }
+
+    pub fn command(&mut self, message: &str) -> String {
+        self.send(message);
+        self.read()
+    }
+}
@@ -0,0 +1,393 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap},
+    ops::Bound
+};
+
+use mini_json::{escape, unescape, Value};
+use sockets::response::Response;
+
+use crate::storage::Entry;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Score(f64);
+
+impl Eq for Score {}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Members ordered by score, ties are broken by member name.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SortedSet {
+    ordered: BTreeSet<(Score, String)>,
+    scores: HashMap<String, f64>
+}
+
+impl SortedSet {
+    /// Adds member or updates its score, returns true if member is new.
+    pub fn insert(&mut self, member: &str, score: f64) -> bool {
+        let is_new = match self.scores.insert(member.to_string(), score) {
+            Some(old) => {
+                self.ordered.remove(&(Score(old), member.to_string()));
+                false
+            },
+            None => true
+        };
+        self.ordered.insert((Score(score), member.to_string()));
+        is_new
+    }
+
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => self.ordered.remove(&(Score(score), member.to_string())),
+            None => false
+        }
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    pub fn rank(&self, member: &str) -> Option<usize> {
+        let score = self.score(member)?;
+        Some(self.ordered.range(..(Score(score), member.to_string())).count())
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    /// Members between `start` and `stop` ranks, both inclusive. Negative ranks count from the end.
+    pub fn range_by_rank(&self, start: isize, stop: isize) -> Vec<(&str, f64)> {
+        let len = self.len() as isize;
+        let start = if start < 0 { (len + start).max(0) } else { start };
+        let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+        if start > stop {
+            return Vec::new()
+        }
+        self.ordered.iter()
+            .skip(start as usize)
+            .take((stop - start + 1) as usize)
+            .map(|(score, member)| (member.as_str(), score.0))
+            .collect()
+    }
+
+    pub fn range_by_score(&self, min: Bound<f64>, max: Bound<f64>) -> Vec<(&str, f64)> {
+        self.ordered.iter()
+            .skip_while(|(score, _)| match min {
+                Bound::Included(min) => score.0 < min,
+                Bound::Excluded(min) => score.0 <= min,
+                Bound::Unbounded => false
+            })
+            .take_while(|(score, _)| match max {
+                Bound::Included(max) => score.0 <= max,
+                Bound::Excluded(max) => score.0 < max,
+                Bound::Unbounded => true
+            })
+            .map(|(score, member)| (member.as_str(), score.0))
+            .collect()
+    }
+
+    /// Object of scores by member, members are kept as they were sent, so they're escaped to serve as keys.
+    pub fn to_value(&self) -> Value {
+        Value::Object(self.scores.iter().map(|(member, score)| (escape(member), Value::Float(*score))).collect())
+    }
+
+    /// Inverse of `to_value`, all members must have numeric scores.
+    pub fn from_value(value: &Value) -> Option<Self> {
+        let mut set = SortedSet::default();
+        for (member, score) in value.object().ok()? {
+            let score = match score {
+                Value::Integer(i) => *i as f64,
+                Value::Float(f) => *f,
+                _ => return None
+            };
+            set.insert(&unescape(member).ok()?, score);
+        }
+        Some(set)
+    }
+}
+
+fn parse_score(score: &str) -> Result<f64, String> {
+    let score = match score.to_ascii_lowercase().as_str() {
+        "-inf" => f64::NEG_INFINITY,
+        "+inf" | "inf" => f64::INFINITY,
+        score => score.parse().map_err(|_| format!("Invalid score {score}"))?
+    };
+    if score.is_nan() {
+        return Err("Score can't be NaN".to_string())
+    }
+    Ok(score)
+}
+
+/// Score bound of ZRANGE BYSCORE, `(` prefix makes it exclusive.
+fn parse_bound(bound: &str) -> Result<Bound<f64>, String> {
+    match bound.strip_prefix('(') {
+        Some(bound) => Ok(Bound::Excluded(parse_score(bound)?)),
+        None => match parse_score(bound)? {
+            score if score.is_infinite() => Ok(Bound::Unbounded),
+            score => Ok(Bound::Included(score))
+        }
+    }
+}
+
+fn wrong_type(entry: &Entry) -> Response {
+    Response::builder().set_body(format!("Invalid type: expected SortedSet, got {}", entry.typename()))
+}
+
+fn score_value(score: f64) -> String {
+    Value::Float(score).serialize()
+}
+
+/// `ZADD key score member [score member ...]`, returns number of new members
+pub fn zadd_cmd(args: &str, storage: &mut HashMap<String, Entry>) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let (key, pairs) = match args.split_first() {
+        Some((key, pairs)) if !pairs.is_empty() && pairs.len() % 2 == 0 => (key, pairs),
+        _ => return Response::builder().set_body("Invalid arguments")
+    };
+    let mut members = Vec::new();
+    for pair in pairs.chunks(2) {
+        match parse_score(pair[0]) {
+            // infinite scores are fine for range bounds, but can't be stored as JSON
+            Ok(score) if score.is_infinite() => return Response::builder().set_body("Score must be a finite number"),
+            Ok(score) => members.push((score, pair[1])),
+            Err(e) => return Response::builder().set_body(e)
+        }
+    }
+    let entry = storage.entry(key.to_string()).or_insert_with(|| Entry::SortedSet(SortedSet::default()));
+    let Entry::SortedSet(set) = entry else {
+        return wrong_type(entry)
+    };
+    let added = members.into_iter().filter(|(score, member)| set.insert(member, *score)).count();
+    Response::builder().set_body(added.to_string())
+}
+
+/// `ZINCRBY key increment member`, returns new score
+pub fn zincrby_cmd(args: &str, storage: &mut HashMap<String, Entry>) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let [key, increment, member] = args.as_slice() else {
+        return Response::builder().set_body("Invalid arguments")
+    };
+    let increment = match parse_score(increment) {
+        Ok(increment) => increment,
+        Err(e) => return Response::builder().set_body(e)
+    };
+    let current = match storage.get(*key) {
+        Some(Entry::SortedSet(set)) => set.score(member).unwrap_or(0.0),
+        Some(entry) => return wrong_type(entry),
+        None => 0.0
+    };
+    let score = current + increment;
+    if !score.is_finite() {
+        return Response::builder().set_body("Score must be a finite number")
+    }
+    if let Entry::SortedSet(set) = storage.entry(key.to_string()).or_insert_with(|| Entry::SortedSet(SortedSet::default())) {
+        set.insert(member, score);
+    }
+    Response::builder().set_body(score_value(score))
+}
+
+/// `ZREM key member [member ...]`, returns number of removed members
+pub fn zrem_cmd(args: &str, storage: &mut HashMap<String, Entry>) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let (key, members) = match args.split_first() {
+        Some((key, members)) if !members.is_empty() => (*key, members),
+        _ => return Response::builder().set_body("Invalid arguments")
+    };
+    let set = match storage.get_mut(key) {
+        Some(Entry::SortedSet(set)) => set,
+        Some(entry) => return wrong_type(entry),
+        None => return Response::builder().set_body("0")
+    };
+    let removed = members.iter().filter(|member| set.remove(member)).count();
+    if set.is_empty() {
+        storage.remove(key);
+    }
+    Response::builder().set_body(removed.to_string())
+}
+
+fn get_set<'a>(key: &str, storage: &'a HashMap<String, Entry>) -> Result<&'a SortedSet, Response> {
+    match storage.get(key) {
+        Some(Entry::SortedSet(set)) => Ok(set),
+        Some(entry) => Err(wrong_type(entry)),
+        None => Err(Response::builder().set_body("Key not found"))
+    }
+}
+
+/// `ZSCORE key member`
+pub fn zscore_cmd(args: &str, storage: &HashMap<String, Entry>) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let [key, member] = args.as_slice() else {
+        return Response::builder().set_body("Invalid arguments")
+    };
+    match get_set(key, storage) {
+        Ok(set) => match set.score(member) {
+            Some(score) => Response::builder().set_body(score_value(score)),
+            None => Response::builder().set_body("Member not found")
+        },
+        Err(response) => response
+    }
+}
+
+/// `ZRANK key member [REV]`, rank is counted from 0
+pub fn zrank_cmd(args: &str, storage: &HashMap<String, Entry>) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let (key, member, rev) = match args.as_slice() {
+        [key, member] => (key, member, false),
+        [key, member, rev] if rev.eq_ignore_ascii_case("rev") => (key, member, true),
+        _ => return Response::builder().set_body("Invalid arguments")
+    };
+    match get_set(key, storage) {
+        Ok(set) => match set.rank(member) {
+            Some(rank) if rev => Response::builder().set_body((set.len() - rank - 1).to_string()),
+            Some(rank) => Response::builder().set_body(rank.to_string()),
+            None => Response::builder().set_body("Member not found")
+        },
+        Err(response) => response
+    }
+}
+
+/// `ZCARD key`
+pub fn zcard_cmd(args: &str, storage: &HashMap<String, Entry>) -> Response {
+    match get_set(args.trim(), storage) {
+        Ok(set) => Response::builder().set_body(set.len().to_string()),
+        Err(response) => response
+    }
+}
+
+/// `ZRANGE key start stop [BYSCORE] [REV] [WITHSCORES]`
+///
+/// Without BYSCORE `start` and `stop` are ranks, with it they are scores (`-inf`, `+inf` and `(` for exclusive bounds).
+/// With REV ranks are counted from the highest score and score bounds are given as `max min`.
+pub fn zrange_cmd(args: &str, storage: &HashMap<String, Entry>) -> Response {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let (key, start, stop, options) = match args.as_slice() {
+        [key, start, stop, options @ ..] => (*key, *start, *stop, options),
+        _ => return Response::builder().set_body("Invalid arguments")
+    };
+    let (mut by_score, mut rev, mut with_scores) = (false, false, false);
+    for option in options {
+        match option.to_ascii_lowercase().as_str() {
+            "byscore" => by_score = true,
+            "rev" => rev = true,
+            "withscores" => with_scores = true,
+            _ => return Response::builder().set_body(format!("Unknown option {option}"))
+        }
+    }
+    let set = match get_set(key, storage) {
+        Ok(set) => set,
+        Err(response) => return response
+    };
+
+    let members = if by_score {
+        let (min, max) = if rev { (stop, start) } else { (start, stop) };
+        let (min, max) = match (parse_bound(min), parse_bound(max)) {
+            (Ok(min), Ok(max)) => (min, max),
+            (Err(e), _) | (_, Err(e)) => return Response::builder().set_body(e)
+        };
+        let mut members = set.range_by_score(min, max);
+        if rev {
+            members.reverse();
+        }
+        members
+    } else {
+        let (start, stop) = match (start.parse::<isize>(), stop.parse::<isize>()) {
+            (Ok(start), Ok(stop)) => (start, stop),
+            _ => return Response::builder().set_body("Invalid rank")
+        };
+        if rev {
+            // reversed ranks map onto the ascending order from the other end
+            let len = set.len() as isize;
+            let flip = |rank: isize| if rank < 0 { -rank - 1 } else { len - rank - 1 };
+            match flip(start) {
+                last if last < 0 => Vec::new(),
+                last => {
+                    let mut members = set.range_by_rank(flip(stop).max(0), last);
+                    members.reverse();
+                    members
+                }
+            }
+        } else {
+            set.range_by_rank(start, stop)
+        }
+    };
+
+    let members = members.into_iter().map(|(member, score)| match with_scores {
+        true => Value::Array(vec![Value::from_text(member), Value::Float(score)]),
+        false => Value::from_text(member)
+    }).collect();
+    Response::builder().set_body(Value::Array(members).serialize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaderboard() -> SortedSet {
+        let mut set = SortedSet::default();
+        set.insert("alice", 30.0);
+        set.insert("bob", 10.0);
+        set.insert("carol", 20.0);
+        set.insert("dave", 20.0);
+        set
+    }
+
+    #[test]
+    fn test_rank_and_update() {
+        let mut set = leaderboard();
+        assert_eq!(set.rank("bob"), Some(0));
+        assert_eq!(set.rank("dave"), Some(2));
+        assert!(!set.insert("bob", 40.0));
+        assert_eq!(set.rank("bob"), Some(3));
+        assert_eq!(set.len(), 4);
+        assert!(set.remove("bob"));
+        assert_eq!(set.rank("bob"), None);
+    }
+
+    #[test]
+    fn test_range_by_rank() {
+        let set = leaderboard();
+        let members: Vec<&str> = set.range_by_rank(0, -1).into_iter().map(|(m, _)| m).collect();
+        assert_eq!(members, vec!["bob", "carol", "dave", "alice"]);
+        let members: Vec<&str> = set.range_by_rank(-2, 10).into_iter().map(|(m, _)| m).collect();
+        assert_eq!(members, vec!["dave", "alice"]);
+        assert!(set.range_by_rank(3, 1).is_empty());
+    }
+
+    #[test]
+    fn test_range_by_score() {
+        let set = leaderboard();
+        let members: Vec<&str> = set.range_by_score(Bound::Excluded(10.0), Bound::Included(20.0)).into_iter().map(|(m, _)| m).collect();
+        assert_eq!(members, vec!["carol", "dave"]);
+        assert_eq!(parse_bound("-inf"), Ok(Bound::Unbounded));
+        assert_eq!(parse_bound("(5"), Ok(Bound::Excluded(5.0)));
+    }
+
+    #[test]
+    fn test_value_roundtrip() {
+        let mut set = leaderboard();
+        set.insert(r#"quoted "name" \ slash"#, 1.0);
+        let value = Value::deserialize(&set.to_value().serialize()).unwrap();
+        assert_eq!(SortedSet::from_value(&value), Some(set));
+
+        let mut storage = HashMap::new();
+        zadd_cmd(r#"z 1 a"b 2 c\d"#, &mut storage);
+        let range = Value::deserialize(&zrange_cmd("z 0 -1 WITHSCORES", &storage).payload.string().unwrap()).unwrap();
+        assert_eq!(range.get_element(&mini_json::Path::parse("[1][0]").unwrap()).unwrap().text(), Ok(r#"c\d"#.to_string()));
+    }
+}
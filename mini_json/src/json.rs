@@ -1,4 +1,6 @@
-use std::collections::HashMap;
+use std::{cmp::Ordering, collections::HashMap};
+
+use crate::path::{Path, Segment};
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum Value {
@@ -48,55 +50,241 @@ impl Value {
     }
 
 
-    pub fn get_element(&self, path: &str) -> Result<&Value, String> {
-        if path.is_empty() {
-            return Ok(self);
-        }
-        let (key, path) = match path.split_once(".") {
-            Some((key, path)) => (key, path),
-            None => (path, "")
+    pub fn get_element(&self, path: &Path) -> Result<&Value, String> {
+        path.segments().iter().try_fold(self, |value, segment| value.child(segment))
+    }
+
+    pub fn get_mut_element(&mut self, path: &Path) -> Result<&mut Value, String> {
+        path.segments().iter().try_fold(self, |value, segment| value.child_mut(segment))
+    }
+
+    /// Removes node at `path` from its parent and returns it.
+    pub fn remove_element(&mut self, path: &Path) -> Result<Value, String> {
+        let (last, parent) = match path.segments().split_last() {
+            Some(split) => split,
+            None => return Err("Cannot remove root".to_string())
         };
-        match self {
-            Value::Object(map) => match map.get(key) {
-                Some(value) => value.get_element(path),
-                None => Err(format!("Key {} not found", key))
+        let parent = parent.iter().try_fold(self, |value, segment| value.child_mut(segment))?;
+        match (parent, last) {
+            (value, Segment::Wildcard) => Err(value.type_error(last)),
+            (Value::Object(map), Segment::Key(key)) => map.remove(key).ok_or_else(|| format!("Key {} not found", key)),
+            (Value::Array(arr), segment) => match segment.index(arr.len())? {
+                i if i < arr.len() => Ok(arr.remove(i)),
+                i => Err(format!("Index {} out of range", i))
             },
-            Value::Array(arr) => match key.parse::<usize>() {
-                Ok(i) => match arr.get(i) {
-                    Some(value) => value.get_element(path),
-                    None => Err(format!("Index {} out of range", i))
-                },
-                Err(_) => Err(format!("{} is not a valid index for array", key))
+            (value, segment) => Err(value.type_error(segment))
+        }
+    }
+
+    /// Sets node at `path` to `value`, creating missing parents. Missing parent is an array if it's
+    /// indexed with `[0]` or `-`, otherwise an object. `-` also appends to existing arrays.
+    /// Nothing changes if the path can't be set.
+    pub fn set_element(&mut self, path: &Path, value: Value) -> Result<(), String> {
+        let (last, parents) = match path.segments().split_last() {
+            Some(split) => split,
+            None => {
+                *self = value;
+                return Ok(())
             }
-            _ => Err(format!("Invalid type: expected Object, got {}", self.typename()))
+        };
+        let mut node = self;
+        for (i, segment) in parents.iter().enumerate() {
+            if node.child(segment).is_err() {
+                let missing = Value::nest(&path.segments()[i + 1..], value)?;
+                return node.place(segment, missing)
+            }
+            node = node.child_mut(segment)?;
         }
+        node.place(last, value)
+    }
+
+    /// Wraps `value` in containers, so that it's found at `path` of the result.
+    pub fn from_path(path: &Path, value: Value) -> Result<Value, String> {
+        Value::nest(path.segments(), value)
     }
 
-    pub fn get_mut_element(&mut self, path: &str) -> Result<&mut Value, String> {
-        if path.is_empty() {
-            return Ok(self);
+    fn nest(segments: &[Segment], value: Value) -> Result<Value, String> {
+        segments.iter().rev().try_fold(value, |value, segment| match segment {
+            Segment::Index(0) => Ok(Value::Array(vec![value])),
+            Segment::Key(key) if key == "-" => Ok(Value::Array(vec![value])),
+            Segment::Key(key) => Ok(Value::Object(HashMap::from([(key.clone(), value)]))),
+            Segment::Index(i) => Err(format!("Index {} out of range", i)),
+            Segment::Wildcard => Err("Invalid path: wildcard is not allowed here".to_string())
+        })
+    }
+
+    /// Inserts `value` as a child of this object or array, replacing existing one.
+    fn place(&mut self, segment: &Segment, value: Value) -> Result<(), String> {
+        match (self, segment) {
+            (node, Segment::Wildcard) => return Err(node.type_error(segment)),
+            (Value::Object(map), Segment::Key(key)) => {
+                map.insert(key.clone(), value);
+            },
+            (Value::Array(arr), Segment::Key(key)) if key == "-" => arr.push(value),
+            (Value::Array(arr), segment) => match segment.index(arr.len())? {
+                i if i < arr.len() => arr[i] = value,
+                i if i == arr.len() => arr.push(value),
+                i => return Err(format!("Index {} out of range", i))
+            },
+            (value, segment) => return Err(value.type_error(segment))
         }
-        let (key, path) = match path.split_once(".") {
-            Some((key, path)) => (key, path),
-            None => (path, "")
+        Ok(())
+    }
+
+    /// Copy of the value with only nodes at `paths` and their parents. Selected array items keep their order,
+    /// but not their indexes. Nothing selected leaves an empty object or array, or null.
+    pub fn project(&self, paths: &[Path]) -> Value {
+        let paths: Vec<&[Segment]> = paths.iter().map(Path::segments).collect();
+        self.select(&paths).unwrap_or_else(|| match self {
+            Value::Object(_) => Value::Object(HashMap::new()),
+            Value::Array(_) => Value::Array(Vec::new()),
+            _ => Value::Null
+        })
+    }
+
+    fn select(&self, paths: &[&[Segment]]) -> Option<Value> {
+        if paths.iter().any(|path| path.is_empty()) {
+            return Some(self.clone())
+        }
+        // rests of paths going through the child
+        let through = |matches: &dyn Fn(&Segment) -> bool| -> Vec<&[Segment]> {
+            paths.iter().filter(|path| matches(&path[0])).map(|path| &path[1..]).collect()
         };
-        match self {
-            Value::Object(map) => match map.get_mut(key) {
-                Some(value) => value.get_mut_element(path),
-                None => Err(format!("Key {} not found", key))
+        let selected = match self {
+            Value::Object(map) => Value::Object(map.iter().filter_map(|(key, value)| {
+                let paths = through(&|segment| match segment {
+                    Segment::Wildcard => true,
+                    Segment::Key(name) => name == key,
+                    Segment::Index(_) => false
+                });
+                Some((key.clone(), value.select(&paths)?))
+            }).collect()),
+            Value::Array(arr) => Value::Array(arr.iter().enumerate().filter_map(|(i, value)| {
+                let paths = through(&|segment| matches!(segment, Segment::Wildcard) || segment.index(arr.len()) == Ok(i));
+                value.select(&paths)
+            }).collect()),
+            _ => return None
+        };
+        match &selected {
+            Value::Object(map) if map.is_empty() => None,
+            Value::Array(arr) if arr.is_empty() => None,
+            _ => Some(selected)
+        }
+    }
+
+    /// Concrete paths `path` stands for, each wildcard replaced by every child of the node it's applied to.
+    /// Branches missing a node before the last wildcard are left out, the rest of the path is kept as is.
+    /// Array items come in ascending order, so removing in reverse keeps remaining indexes valid.
+    pub fn expand(&self, path: &Path) -> Vec<Path> {
+        let last = match path.segments().iter().rposition(|segment| *segment == Segment::Wildcard) {
+            Some(last) => last,
+            None => return vec![path.clone()]
+        };
+        let (head, tail) = path.segments().split_at(last + 1);
+        let mut paths = Vec::new();
+        self.expand_into(head, &mut Vec::new(), &mut paths);
+        paths.into_iter().map(|mut segments| {
+            segments.extend_from_slice(tail);
+            Path::from(segments)
+        }).collect()
+    }
+
+    fn expand_into(&self, segments: &[Segment], prefix: &mut Vec<Segment>, paths: &mut Vec<Vec<Segment>>) {
+        let (segment, rest) = match segments.split_first() {
+            Some(split) => split,
+            None => return paths.push(prefix.clone())
+        };
+        let children: Vec<(Segment, &Value)> = match (self, segment) {
+            (Value::Object(map), Segment::Wildcard) => sorted_members(map).into_iter()
+                .map(|(key, value)| (Segment::Key(key.clone()), value))
+                .collect(),
+            (Value::Array(arr), Segment::Wildcard) => arr.iter().enumerate().map(|(i, value)| (Segment::Index(i as isize), value)).collect(),
+            (value, segment) => value.child(segment).map(|child| vec![(segment.clone(), child)]).unwrap_or_default()
+        };
+        for (segment, child) in children {
+            prefix.push(segment);
+            child.expand_into(rest, prefix, paths);
+            prefix.pop();
+        }
+    }
+
+    fn child(&self, segment: &Segment) -> Result<&Value, String> {
+        match (self, segment) {
+            (value, Segment::Wildcard) => Err(value.type_error(segment)),
+            (Value::Object(map), Segment::Key(key)) => map.get(key).ok_or_else(|| format!("Key {} not found", key)),
+            (Value::Array(arr), segment) => {
+                let i = segment.index(arr.len())?;
+                arr.get(i).ok_or_else(|| format!("Index {} out of range", i))
             },
-            Value::Array(arr) => match key.parse::<usize>() {
-                Ok(i) => match arr.get_mut(i) {
-                    Some(value) => value.get_mut_element(path),
-                    None => Err(format!("Index {} out of range", i))
-                },
-                Err(_) => Err(format!("{} is not a valid index for array", key))
-            }
-            _ => Err(format!("Invalid type: expected Object, got {}", self.typename()))
+            (value, segment) => Err(value.type_error(segment))
+        }
+    }
+
+    fn child_mut(&mut self, segment: &Segment) -> Result<&mut Value, String> {
+        match (self, segment) {
+            (value, Segment::Wildcard) => Err(value.type_error(segment)),
+            (Value::Object(map), Segment::Key(key)) => map.get_mut(key).ok_or_else(|| format!("Key {} not found", key)),
+            (Value::Array(arr), segment) => {
+                let i = segment.index(arr.len())?;
+                arr.get_mut(i).ok_or_else(|| format!("Index {} out of range", i))
+            },
+            (value, segment) => Err(value.type_error(segment))
+        }
+    }
+
+    fn type_error(&self, segment: &Segment) -> String {
+        let expected = match segment {
+            Segment::Key(_) => "Object",
+            Segment::Index(_) => "Array",
+            Segment::Wildcard => return "Invalid path: wildcard is not allowed here".to_string()
+        };
+        format!("Invalid type: expected {}, got {}", expected, self.typename())
+    }
+
+    /// Total ordering of values: null, booleans, numbers, strings, arrays and objects. Integers and floats
+    /// compare by their numeric value, arrays item by item and objects by their members sorted by key.
+    pub fn total_cmp(&self, other: &Value) -> Ordering {
+        match (self, other) {
+            (Value::Boolean(a), Value::Boolean(b)) => a.cmp(b),
+            (Value::Integer(a), Value::Integer(b)) => a.cmp(b),
+            (Value::Integer(_) | Value::Float(_), Value::Integer(_) | Value::Float(_)) => {
+                let number = |value: &Value| match value {
+                    Value::Integer(i) => *i as f64,
+                    Value::Float(f) => *f,
+                    _ => 0.0
+                };
+                // equal integer goes first, since it's not equal to the float
+                number(self).total_cmp(&number(other)).then(self.rank().cmp(&other.rank()))
+            },
+            (Value::String(a), Value::String(b)) => a.cmp(b),
+            (Value::Array(a), Value::Array(b)) => a.iter().zip(b)
+                .map(|(a, b)| a.total_cmp(b))
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or_else(|| a.len().cmp(&b.len())),
+            (Value::Object(a), Value::Object(b)) => {
+                let (a, b) = (sorted_members(a), sorted_members(b));
+                a.iter().zip(&b)
+                    .map(|((a_key, a), (b_key, b))| a_key.cmp(b_key).then_with(|| a.total_cmp(b)))
+                    .find(|ordering| ordering.is_ne())
+                    .unwrap_or_else(|| a.len().cmp(&b.len()))
+            },
+            _ => self.rank().cmp(&other.rank())
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            Value::Null => 0,
+            Value::Boolean(_) => 1,
+            Value::Integer(_) => 2,
+            Value::Float(_) => 3,
+            Value::String(_) => 4,
+            Value::Array(_) => 5,
+            Value::Object(_) => 6
         }
     }
 
-    fn typename(&self) -> String {
+    pub fn typename(&self) -> String {
         match self {
             Value::String(_) => "String",
             // Value::Binary(_) => "Binary",
@@ -110,8 +298,39 @@ impl Value {
     }
 }
 
+fn sorted_members(map: &HashMap<String, Value>) -> Vec<(&String, &Value)> {
+    let mut members: Vec<(&String, &Value)> = map.iter().collect();
+    members.sort_by_key(|(key, _)| *key);
+    members
+}
+
+/// Bounds on what `Value::deserialize_with` accepts, so hostile input is rejected instead of exhausting memory or the stack.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ParseLimits {
+    /// Length of the input in bytes
+    pub max_size: usize,
+    /// Nesting of arrays and objects, the parser recurses once per level
+    pub max_depth: usize,
+    /// Members of a single object
+    pub max_keys: usize
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        Self { max_size: usize::MAX, max_depth: 128, max_keys: usize::MAX }
+    }
+}
+
 impl Value {
+    /// Parses with default limits, which only bound the nesting depth.
     pub fn deserialize(value: &str) -> Result<Value, &'static str> {
+        Value::deserialize_with(value, &ParseLimits::default())
+    }
+
+    pub fn deserialize_with(value: &str, limits: &ParseLimits) -> Result<Value, &'static str> {
+        if value.len() > limits.max_size {
+            return Err("Value too large")
+        }
         if value.is_empty() {
             return Ok(Value::Null)
         }
@@ -119,32 +338,98 @@ impl Value {
         let mut position: usize = 0;
         skip_whitespace(bytes, &mut position);
 
-        parse_value(bytes, &mut position)
+        parse_value(bytes, &mut position, limits, 0)
     }
 
     pub fn serialize(&self) -> String {
         self.into()
     }
+
+    /// String holding `text`, escaped the way parsed strings are kept, so it serializes to valid JSON.
+    pub fn from_text(text: &str) -> Value {
+        Value::String(escape(text))
+    }
+
+    /// Content of a string with its escapes resolved, the inverse of `from_text`.
+    pub fn text(&self) -> Result<String, &'static str> {
+        unescape(self.string()?)
+    }
+
+    /// Levels of arrays and objects, zero for scalars.
+    pub fn depth(&self) -> usize {
+        match self {
+            Value::Array(items) => 1 + items.iter().map(Value::depth).max().unwrap_or(0),
+            Value::Object(map) => 1 + map.values().map(Value::depth).max().unwrap_or(0),
+            _ => 0
+        }
+    }
 }
 
-fn parse_value(bytes: &[u8], position: &mut usize) -> Result<Value, &'static str> {
-    match bytes[*position] {
-        b'{' => {
-            return parse_object(bytes, position)
+/// Escapes `text` the way strings and object keys are kept in values.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c)
         }
-        b'[' => {
-            return parse_array(bytes, position)
+    }
+    escaped
+}
+
+/// Resolves escapes of a string or object key as kept in values, the inverse of `escape`.
+pub fn unescape(escaped: &str) -> Result<String, &'static str> {
+    let mut text = String::new();
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue
+        }
+        match chars.next() {
+            Some('n') => text.push('\n'),
+            Some('r') => text.push('\r'),
+            Some('t') => text.push('\t'),
+            Some('b') => text.push('\u{8}'),
+            Some('f') => text.push('\u{c}'),
+            Some('u') => {
+                let code: String = chars.by_ref().take(4).collect();
+                let code = u32::from_str_radix(&code, 16).map_err(|_| "Invalid escape")?;
+                text.push(char::from_u32(code).ok_or("Invalid escape")?);
+            },
+            Some(c @ ('"' | '\\' | '/')) => text.push(c),
+            _ => return Err("Invalid escape")
         }
-        b'"' => {
+    }
+    Ok(text)
+}
+
+fn parse_value(bytes: &[u8], position: &mut usize, limits: &ParseLimits, depth: usize) -> Result<Value, &'static str> {
+    if depth >= limits.max_depth && matches!(bytes.get(*position), Some(b'{' | b'[')) {
+        return Err("Maximum nesting depth exceeded")
+    }
+    match bytes.get(*position) {
+        Some(b'{') => {
+            return parse_object(bytes, position, limits, depth + 1)
+        }
+        Some(b'[') => {
+            return parse_array(bytes, position, limits, depth + 1)
+        }
+        Some(b'"') => {
             return parse_string(bytes, position)
         }
-        b't' | b'f' => {
+        Some(b't' | b'f') => {
             return parse_boolean(bytes, position)
         }
-        b'n' => {
+        Some(b'n') => {
             return parse_null(bytes, position)
         }
-        b'-' | b'0'..=b'9' => {
+        Some(b'-' | b'0'..=b'9') => {
             return parse_number(bytes, position)
         }
         _ => {
@@ -163,7 +448,7 @@ fn skip_whitespace(bytes: &[u8], position: &mut usize) {
     *position = bytes.len()
 }
 
-fn parse_object(bytes: &[u8], position: &mut usize) -> Result<Value, &'static str> {
+fn parse_object(bytes: &[u8], position: &mut usize, limits: &ParseLimits, depth: usize) -> Result<Value, &'static str> {
     let mut map = HashMap::new();
     *position += 1;
     skip_whitespace(bytes, position);
@@ -193,9 +478,12 @@ fn parse_object(bytes: &[u8], position: &mut usize) -> Result<Value, &'static st
             return Err("Invalid data")
         }
 
-        let value = parse_value(bytes, position)?;
+        let value = parse_value(bytes, position, limits, depth)?;
 
         map.insert(key, value);
+        if map.len() > limits.max_keys {
+            return Err("Too many keys")
+        }
 
         skip_whitespace(bytes, position);
         if *position == bytes.len() {
@@ -247,7 +535,7 @@ fn parse_string(bytes: &[u8], position: &mut usize) -> Result<Value, &'static st
 
 }
 
-fn parse_array(bytes: &[u8], position: &mut usize) -> Result<Value, &'static str> {
+fn parse_array(bytes: &[u8], position: &mut usize, limits: &ParseLimits, depth: usize) -> Result<Value, &'static str> {
     let mut array = Vec::new();
     *position += 1;
     skip_whitespace(bytes, position);
@@ -259,7 +547,7 @@ fn parse_array(bytes: &[u8], position: &mut usize) -> Result<Value, &'static str
         return Ok(Value::Array(array))
     }
     loop {
-        array.push(parse_value(bytes, position)?);
+        array.push(parse_value(bytes, position, limits, depth)?);
         skip_whitespace(bytes, position);
         if *position == bytes.len() {
             return Err("Invalid data")
@@ -490,4 +778,88 @@ mod tests {
         ]));
         assert_eq!(value.serialize(), r#"{"menu": {"popup": {"menuitem": [{"value": "New"}, {"onclick": "OpenDoc()"}, {"value": "Close"}]}}}"#)
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_limits() {
+        let limits = ParseLimits { max_size: 32, max_depth: 2, max_keys: 2 };
+        assert_eq!(Value::deserialize_with(r#"{"a": [1], "b": {}}"#, &limits).map(|value| value.depth()), Ok(2));
+        assert_eq!(Value::deserialize_with("[[[1]]]", &limits), Err("Maximum nesting depth exceeded"));
+        assert_eq!(Value::deserialize_with(r#"{"a": 1, "b": 2, "c": 3}"#, &limits), Err("Too many keys"));
+        assert_eq!(Value::deserialize_with(&format!("\"{}\"", "x".repeat(40)), &limits), Err("Value too large"));
+        // default limits keep the recursive parser from overflowing the stack
+        assert!(Value::deserialize(&"[".repeat(100000)).is_err());
+        assert!(Value::deserialize("  ").is_err());
+    }
+
+    #[test]
+    fn test_elements() {
+        let mut value = Value::deserialize(r#"{"a.b": {"list": [1, 2, 3]}}"#).unwrap();
+        let path = |path: &str| Path::parse(path).unwrap();
+        assert_eq!(value.get_element(&path(r#"["a.b"].list[-1]"#)), Ok(&Value::Integer(3)));
+        assert_eq!(value.get_element(&path(r"a\.b.list.0")), Ok(&Value::Integer(1)));
+        assert_eq!(value.get_element(&path(r#"["a.b"][0]"#)), Err("Invalid type: expected Array, got Object".to_string()));
+        assert_eq!(value.get_element(&path(r#"["a.b"].list[3]"#)), Err("Index 3 out of range".to_string()));
+        assert_eq!(value.remove_element(&path(r#"["a.b"].list[-3]"#)), Ok(Value::Integer(1)));
+        assert_eq!(value.serialize(), r#"{"a.b": {"list": [2, 3]}}"#);
+    }
+
+    #[test]
+    fn test_set_element() {
+        let mut value = Value::deserialize(r#"{"list": [1]}"#).unwrap();
+        let path = |path: &str| Path::parse(path).unwrap();
+        value.set_element(&path("user.address.city"), Value::Integer(1)).unwrap();
+        value.set_element(&path("list.-"), Value::Integer(2)).unwrap();
+        value.set_element(&path("list[-1]"), Value::Integer(3)).unwrap();
+        value.set_element(&path("tags[0].-"), Value::Integer(4)).unwrap();
+        assert_eq!(value.get_element(&path("user.address.city")), Ok(&Value::Integer(1)));
+        assert_eq!(value.get_element(&path("list")), Ok(&Value::Array(vec![Value::Integer(1), Value::Integer(3)])));
+        assert_eq!(value.get_element(&path("tags[0][0]")), Ok(&Value::Integer(4)));
+
+        let before = value.clone();
+        assert!(value.set_element(&path("new.list[3]"), Value::Null).is_err());
+        assert!(value.set_element(&path("list[0].a"), Value::Null).is_err());
+        assert_eq!(value, before);
+        assert_eq!(Value::from_path(&path("a[0]"), Value::Null).ok(), Value::deserialize(r#"{"a": [null]}"#).ok());
+    }
+
+    #[test]
+    fn test_expand() {
+        let value = Value::deserialize(r#"{"users": [{"a": {"b": 1}}, {"a": 2}, {"c": 3}], "map": {"y": 1, "x": 2}}"#).unwrap();
+        let expand = |path: &str| value.expand(&Path::parse(path).unwrap()).iter().map(Path::to_string).collect::<Vec<String>>();
+        assert_eq!(expand("users.*.a.b"), vec!["users[0].a.b", "users[1].a.b", "users[2].a.b"]);
+        assert_eq!(expand("users.*.a.*"), vec!["users[0].a.b"]);
+        assert_eq!(expand("map.*"), vec!["map.x", "map.y"]);
+        assert_eq!(expand("users[0]"), vec!["users[0]"]);
+        assert!(value.get_element(&Path::parse("users.*").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_total_cmp() {
+        let mut values: Vec<Value> = ["{\"a\": 1}", "[1, 2]", "\"b\"", "2.5", "1", "true", "null", "[1]", "1.0", "\"a\"", "{\"a\": 0}", "-3"]
+            .iter().map(|value| Value::deserialize(value).unwrap()).collect();
+        values.sort_by(Value::total_cmp);
+        let sorted: Vec<String> = values.iter().map(Value::serialize).collect();
+        assert_eq!(sorted, vec!["null", "true", "-3", "1", "1", "2.5", "\"a\"", "\"b\"", "[1]", "[1, 2]", "{\"a\": 0}", "{\"a\": 1}"]);
+        assert_eq!(Value::Integer(1).total_cmp(&Value::Float(1.0)), Ordering::Less);
+    }
+
+    #[test]
+    fn test_text() {
+        let text = "say \"hi\"\n\\ \u{1} é";
+        let value = Value::from_text(text);
+        assert_eq!(value.serialize(), r#""say \"hi\"\n\\ \u0001 é""#);
+        assert_eq!(Value::deserialize(&value.serialize()).unwrap().text(), Ok(text.to_string()));
+        assert_eq!(Value::deserialize(r#""a\/b\u00e9""#).unwrap().text(), Ok("a/bé".to_string()));
+        assert!(Value::String("\\x".to_string()).text().is_err());
+        assert!(Value::Integer(1).text().is_err());
+    }
+
+    #[test]
+    fn test_project() {
+        let value = Value::deserialize(r#"{"a": 1, "b": {"c": 2, "x": 3}, "d": [{"e": 4, "f": 5}, {"e": 6}], "g": 7}"#).unwrap();
+        let project = |paths: &[&str]| value.project(&paths.iter().map(|path| Path::parse(path).unwrap()).collect::<Vec<Path>>());
+        assert_eq!(project(&["a", "b.c", "d[-1]"]), Value::deserialize(r#"{"a": 1, "b": {"c": 2}, "d": [{"e": 6}]}"#).unwrap());
+        assert_eq!(project(&["d.*.f"]), Value::deserialize(r#"{"d": [{"f": 5}]}"#).unwrap());
+        assert_eq!(project(&["missing", "a.b"]), Value::Object(HashMap::new()));
+    }
+}
@@ -1,2 +1,4 @@
 mod json;
-pub use json::Value;
\ No newline at end of file
+mod path;
+pub use json::{escape, unescape, ParseLimits, Value};
+pub use path::{Path, Segment};
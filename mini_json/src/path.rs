@@ -0,0 +1,259 @@
+use std::{fmt, iter::Peekable, str::CharIndices};
+
+/// Single step of a `Path`.
+#[derive(PartialEq, Eq, Debug, Clone, Hash)]
+pub enum Segment {
+    /// Object field. Bare numbers, eg. `list.0`, also index arrays
+    Key(String),
+    /// Array index written in brackets, negative ones count from the end, eg. `list[-1]`
+    Index(isize),
+    /// Bare `*`, every item of an array or member of an object
+    Wildcard
+}
+
+impl Segment {
+    /// Position in an array of `len` items this segment points at, negative indexes resolved from the end.
+    /// Upper bound is left to the caller, since appending may use `len` itself.
+    pub fn index(&self, len: usize) -> Result<usize, String> {
+        let index = match self {
+            Segment::Index(i) => *i,
+            Segment::Key(key) => match key.parse::<isize>() {
+                Ok(i) => i,
+                Err(_) => return Err(format!("{} is not a valid index for array", key))
+            },
+            Segment::Wildcard => return Err("* is not a valid index for array".to_string())
+        };
+        match index {
+            i if i >= 0 => Ok(i as usize),
+            i => len.checked_sub(i.unsigned_abs()).ok_or_else(|| format!("Index {} out of range", i))
+        }
+    }
+}
+
+/// Path to a node inside a document, eg. `user.addresses[0].city`.
+///
+/// Bare segments are separated by `.` and `\` escapes the next character, so `a\.b` is a single segment.
+/// Brackets hold either a quoted segment, `["a.b"]` or `['a.b']`, or an array index, `[0]` or `[-1]`.
+/// Bare `*` is a wildcard matching every child, `["*"]` being the literal key.
+/// Whitespace ends the path unless it's escaped or quoted. Empty path points at the root.
+#[derive(PartialEq, Eq, Debug, Clone, Default, Hash)]
+pub struct Path(Vec<Segment>);
+
+impl Path {
+    pub fn parse(input: &str) -> Result<Path, String> {
+        match Path::parse_prefix(input)? {
+            (path, "") => Ok(path),
+            (_, rest) => Err(format!("Invalid path: unexpected {}", rest.trim_start()))
+        }
+    }
+
+    /// Parses path at the start of `input`, returning it along with the rest of the input, which starts with whitespace if not empty.
+    pub fn parse_prefix(input: &str) -> Result<(Path, &str), String> {
+        let mut segments = Vec::new();
+        let mut chars = input.char_indices().peekable();
+        loop {
+            match chars.peek() {
+                None => return Ok((Path(segments), "")),
+                Some((i, c)) if c.is_whitespace() => return Ok((Path(segments), &input[*i..])),
+                Some((_, '[')) => {
+                    chars.next();
+                    segments.push(parse_bracket(&mut chars)?);
+                },
+                Some((_, '.')) if !segments.is_empty() => {
+                    chars.next();
+                    segments.push(parse_bare(&mut chars)?);
+                },
+                Some(_) if segments.is_empty() => segments.push(parse_bare(&mut chars)?),
+                Some((_, c)) => return Err(format!("Invalid path: unexpected {}", c))
+            }
+        }
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn first(&self) -> Option<&Segment> {
+        self.0.first()
+    }
+
+    pub fn last(&self) -> Option<&Segment> {
+        self.0.last()
+    }
+
+    /// Path without the first segment.
+    pub fn rest(&self) -> Path {
+        Path(self.0.iter().skip(1).cloned().collect())
+    }
+
+    /// Path without the last segment, `None` for the root.
+    pub fn parent(&self) -> Option<Path> {
+        let (_, parent) = self.0.split_last()?;
+        Some(Path(parent.to_vec()))
+    }
+
+    pub fn child(&self, segment: Segment) -> Path {
+        let mut path = self.clone();
+        path.0.push(segment);
+        path
+    }
+
+    pub fn has_wildcard(&self) -> bool {
+        self.0.contains(&Segment::Wildcard)
+    }
+
+    pub fn starts_with(&self, prefix: &Path) -> bool {
+        self.0.starts_with(&prefix.0)
+    }
+}
+
+impl From<Vec<Segment>> for Path {
+    fn from(segments: Vec<Segment>) -> Self {
+        Path(segments)
+    }
+}
+
+impl fmt::Display for Path {
+    /// Canonical form, segments are only quoted when they can't be written bare.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, segment) in self.0.iter().enumerate() {
+            match segment {
+                Segment::Index(index) => write!(f, "[{}]", index)?,
+                Segment::Wildcard => match i {
+                    0 => write!(f, "*")?,
+                    _ => write!(f, ".*")?
+                },
+                Segment::Key(key) if is_bare(key) => match i {
+                    0 => write!(f, "{}", key)?,
+                    _ => write!(f, ".{}", key)?
+                },
+                Segment::Key(key) => write!(f, "[\"{}\"]", key.replace('\\', "\\\\").replace('"', "\\\""))?
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_bare(key: &str) -> bool {
+    !key.is_empty() && key != "*" && !key.chars().any(|c| matches!(c, '.' | '[' | ']' | '\\' | '"' | '\'') || c.is_whitespace())
+}
+
+fn parse_bare(chars: &mut Peekable<CharIndices>) -> Result<Segment, String> {
+    let mut key = String::new();
+    let mut escaped = false;
+    while let Some((_, c)) = chars.peek() {
+        match c {
+            '.' | '[' => break,
+            c if c.is_whitespace() => break,
+            ']' => return Err("Invalid path: unexpected ]".to_string()),
+            '\\' => {
+                chars.next();
+                escaped = true;
+                match chars.next() {
+                    Some((_, c)) => key.push(c),
+                    None => return Err("Invalid path: unterminated escape".to_string())
+                }
+            },
+            c => {
+                key.push(*c);
+                chars.next();
+            }
+        }
+    }
+    match key.as_str() {
+        "" => Err("Invalid path: empty segment".to_string()),
+        "*" if !escaped => Ok(Segment::Wildcard),
+        _ => Ok(Segment::Key(key))
+    }
+}
+
+fn parse_bracket(chars: &mut Peekable<CharIndices>) -> Result<Segment, String> {
+    let segment = match chars.peek() {
+        Some((_, quote @ ('"' | '\''))) => {
+            let quote = *quote;
+            chars.next();
+            let mut key = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, c)) => key.push(c),
+                        None => return Err("Invalid path: unterminated escape".to_string())
+                    },
+                    Some((_, c)) if c == quote => break,
+                    Some((_, c)) => key.push(c),
+                    None => return Err("Invalid path: unterminated quote".to_string())
+                }
+            }
+            Segment::Key(key)
+        },
+        _ => {
+            let mut index = String::new();
+            while let Some((_, c)) = chars.peek().filter(|(_, c)| *c != ']') {
+                index.push(*c);
+                chars.next();
+            }
+            match index.parse() {
+                Ok(i) => Segment::Index(i),
+                Err(_) => return Err(format!("Invalid path: {} is not a valid index", index))
+            }
+        }
+    };
+    match chars.next() {
+        Some((_, ']')) => Ok(segment),
+        _ => Err("Invalid path: missing ]".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: &str) -> Segment {
+        Segment::Key(key.to_string())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Path::parse("").unwrap(), Path::default());
+        assert_eq!(Path::parse("a.b.0").unwrap(), Path::from(vec![key("a"), key("b"), key("0")]));
+        assert_eq!(Path::parse(r#"a["b.c"][0]['d\'e'][-1]"#).unwrap(),
+            Path::from(vec![key("a"), key("b.c"), Segment::Index(0), key("d'e"), Segment::Index(-1)]));
+        assert_eq!(Path::parse(r"a\.b.c\ d").unwrap(), Path::from(vec![key("a.b"), key("c d")]));
+        assert_eq!(Path::parse(r#"["a b"].c"#).unwrap(), Path::from(vec![key("a b"), key("c")]));
+        assert_eq!(Path::parse(r#"a.*.b["*"].\*"#).unwrap(), Path::from(vec![key("a"), Segment::Wildcard, key("b"), key("*"), key("*")]));
+        for invalid in ["a..b", ".a", "a.", "a[x]", "a[\"b\"", "a[0", "a]", "a\\", "a[0]b"] {
+            assert!(Path::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_parse_prefix() {
+        let (path, rest) = Path::parse_prefix(r#"a["b c"].d {"x": 1}"#).unwrap();
+        assert_eq!(path, Path::from(vec![key("a"), key("b c"), key("d")]));
+        assert_eq!(rest, r#" {"x": 1}"#);
+    }
+
+    #[test]
+    fn test_display() {
+        for path in ["a.b[0]", r#"a["b.c"][-1].d"#, r#"["a\"b"]"#, r#"a.*["*"]"#] {
+            assert_eq!(Path::parse(path).unwrap().to_string(), path);
+        }
+        assert_eq!(Path::parse(r"a\.b").unwrap().to_string(), r#"["a.b"]"#);
+    }
+
+    #[test]
+    fn test_index() {
+        assert_eq!(Segment::Index(-1).index(3), Ok(2));
+        assert_eq!(key("1").index(3), Ok(1));
+        assert!(Segment::Index(-4).index(3).is_err());
+        assert!(key("a").index(3).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use std::{collections::HashMap, io::{ErrorKind, Write as _}, net::{Shutdown, SocketAddr, TcpStream}, sync::{Arc, Condvar, Mutex}, time::{Duration, Instant}};
+
+use utils::Rand;
+
+use crate::{errors::SocketError, response::Response};
+
+pub struct Client {
+    pub id: u64,
+    pub addr: SocketAddr,
+    pub connected_at: Instant,
+    name: Mutex<Option<String>>,
+    last_active: Mutex<Instant>,
+    last_command: Mutex<String>,
+    stream: Mutex<TcpStream>,
+    /// Set while reply to the last command is yet to be sent from another thread
+    pending_reply: Mutex<bool>,
+    replied: Condvar,
+    rand: Arc<Rand>
+}
+
+impl Client {
+    pub(crate) fn new(id: u64, conn: &TcpStream, rand: Arc<Rand>) -> Result<Self, SocketError> {
+        let stream = conn.try_clone().map_err(|_| SocketError::ConnectionClosed)?;
+        let addr = conn.peer_addr().map_err(|_| SocketError::ConnectionClosed)?;
+        let now = Instant::now();
+        Ok(Self {
+            id,
+            addr,
+            connected_at: now,
+            name: Mutex::new(None),
+            last_active: Mutex::new(now),
+            last_command: Mutex::new(String::new()),
+            stream: Mutex::new(stream),
+            pending_reply: Mutex::new(false),
+            replied: Condvar::new(),
+            rand
+        })
+    }
+
+    pub fn name(&self) -> Option<String> {
+        self.name.lock().unwrap().clone()
+    }
+
+    pub fn set_name(&self, name: Option<String>) {
+        *self.name.lock().unwrap() = name;
+    }
+
+    pub fn last_command(&self) -> String {
+        self.last_command.lock().unwrap().clone()
+    }
+
+    pub fn set_last_command(&self, command: &str) {
+        *self.last_command.lock().unwrap() = command.to_string();
+    }
+
+    pub fn age(&self) -> Duration {
+        self.connected_at.elapsed()
+    }
+
+    pub fn idle(&self) -> Duration {
+        self.last_active.lock().unwrap().elapsed()
+    }
+
+    pub(crate) fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+
+    /// Sends response to the client. Safe to call from any thread, writes are serialized per connection.
+    pub fn send(&self, response: Response) -> Result<(), SocketError> {
+        let payload = response.set_mask(self.rand.get_mask()).build();
+        self.write(&payload)
+    }
+
+    /// Marks reply to the command being handled as deferred, it's sent later with `reply`.
+    /// Nothing more is read from the connection until then, so replies keep the order of commands.
+    pub fn defer_reply(&self) {
+        *self.pending_reply.lock().unwrap() = true;
+    }
+
+    /// Sends reply deferred by `defer_reply`, letting the connection read the next command.
+    pub fn reply(&self, response: Response) -> Result<(), SocketError> {
+        let result = self.send(response);
+        *self.pending_reply.lock().unwrap() = false;
+        self.replied.notify_all();
+        result
+    }
+
+    /// Blocks until deferred reply is sent, returns `false` if the connection was closed in the meantime.
+    pub(crate) fn wait_for_reply(&self, conn: &TcpStream) -> bool {
+        let mut pending = self.pending_reply.lock().unwrap();
+        while *pending {
+            pending = self.replied.wait_timeout(pending, Duration::from_millis(100)).unwrap().0;
+            if *pending && is_closed(conn) {
+                return false
+            }
+        }
+        true
+    }
+
+    /// Sends close frame with given status code and shuts the connection down.
+    /// Thread serving this client will notice it on next read and unregister it.
+    pub fn close(&self, code: u16, reason: &str) -> Result<(), SocketError> {
+        let result = self.send(Response::close(code, reason));
+        let _ = self.stream.lock().unwrap().shutdown(Shutdown::Both);
+        result
+    }
+
+    pub(crate) fn write(&self, payload: &[u8]) -> Result<(), SocketError> {
+        let mut stream = self.stream.lock().unwrap();
+        stream.write_all(payload).map_err(|_| SocketError::ConnectionClosed)?;
+        stream.flush().map_err(|_| SocketError::ConnectionClosed)
+    }
+}
+
+/// Checks without reading whether the peer closed the connection. Pipelined commands are left in the stream,
+/// read timeout is used instead of non-blocking mode, which would apply to writes of other threads as well.
+fn is_closed(conn: &TcpStream) -> bool {
+    if conn.set_read_timeout(Some(Duration::from_millis(1))).is_err() {
+        return true
+    }
+    let closed = match conn.peek(&mut [0]) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => !matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted)
+    };
+    conn.set_read_timeout(None).is_err() || closed
+}
+
+/// Registry of all currently connected clients.
+#[derive(Default)]
+pub struct Clients {
+    inner: Mutex<HashMap<u64, Arc<Client>>>
+}
+
+impl Clients {
+    pub(crate) fn register(&self, client: Arc<Client>) {
+        self.inner.lock().unwrap().insert(client.id, client);
+    }
+
+    pub(crate) fn unregister(&self, id: u64) {
+        self.inner.lock().unwrap().remove(&id);
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<Client>> {
+        self.inner.lock().unwrap().get(&id).cloned()
+    }
+
+    pub fn find_by_addr(&self, addr: SocketAddr) -> Option<Arc<Client>> {
+        self.inner.lock().unwrap().values().find(|c| c.addr == addr).cloned()
+    }
+
+    /// Returns all connected clients ordered by id.
+    pub fn list(&self) -> Vec<Arc<Client>> {
+        let mut clients: Vec<_> = self.inner.lock().unwrap().values().cloned().collect();
+        clients.sort_by_key(|c| c.id);
+        clients
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read as _, net::TcpListener};
+
+    use super::*;
+
+    /// Client for a loopback connection, with the peer's end of it.
+    fn connect(listener: &TcpListener, id: u64) -> (Arc<Client>, TcpStream) {
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        (Arc::new(Client::new(id, &conn, Arc::new(Rand::new())).unwrap()), peer)
+    }
+
+    #[test]
+    fn test_registry() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let clients = Clients::default();
+        let (first, first_peer) = connect(&listener, 7);
+        let (second, _second_peer) = connect(&listener, 3);
+        clients.register(first.clone());
+        clients.register(second.clone());
+
+        let ids: Vec<u64> = clients.list().iter().map(|client| client.id).collect();
+        assert_eq!(ids, vec![3, 7]);
+        assert_eq!(clients.get(7).map(|client| client.addr), Some(first_peer.local_addr().unwrap()));
+        assert_eq!(clients.find_by_addr(first_peer.local_addr().unwrap()).map(|client| client.id), Some(7));
+        assert!(clients.get(1).is_none());
+
+        clients.unregister(7);
+        assert!(clients.get(7).is_none());
+        assert!(clients.find_by_addr(first_peer.local_addr().unwrap()).is_none());
+        assert_eq!(clients.list().len(), 1);
+    }
+
+    #[test]
+    fn test_name_and_command() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let clients = Clients::default();
+        let (client, _peer) = connect(&listener, 1);
+        clients.register(client.clone());
+        assert_eq!(client.name(), None);
+        client.set_name(Some("worker".to_string()));
+        client.set_last_command("get");
+        // the registry hands out the same client, not a copy
+        assert_eq!(clients.get(1).and_then(|client| client.name()), Some("worker".to_string()));
+        assert_eq!(clients.list()[0].last_command(), "get");
+        client.set_name(None);
+        assert_eq!(clients.get(1).and_then(|client| client.name()), None);
+    }
+
+    #[test]
+    fn test_close() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (client, mut peer) = connect(&listener, 1);
+        client.close(1000, "Killed").unwrap();
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).unwrap();
+        // close frame, then the connection ends
+        assert_eq!(received[0], 0x88);
+        assert!(client.send(Response::builder().set_body("late")).is_err());
+    }
+}
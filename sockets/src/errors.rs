@@ -6,6 +6,8 @@ pub enum SocketError {
     ConnectionClosed,
     InvalidHandshake,
     InvalidFrame,
+    /// Frame claims more payload than the reader accepts
+    FrameTooLarge,
     #[allow(unused)]
     UnknownError
 }
@@ -17,6 +19,7 @@ impl Display for SocketError {
             SocketError::ConnectionClosed => write!(f, "Connection closed"),
             SocketError::InvalidHandshake => write!(f, "Invalid handshake"),
             SocketError::InvalidFrame => write!(f, "Invalid frame"),
+            SocketError::FrameTooLarge => write!(f, "Frame too large"),
             SocketError::UnknownError => write!(f, "Unknown error")
         }
     }
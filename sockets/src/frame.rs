@@ -127,15 +127,23 @@ impl DataFrame {
 }
 
 pub trait ReadDataFrame {
-    fn read_frame(&mut self) -> Result<DataFrame, errors::SocketError>;
+    fn read_frame(&mut self) -> Result<DataFrame, errors::SocketError> {
+        self.read_frame_limited(usize::MAX)
+    }
+
+    /// Reads frame with payload of at most `max_length` bytes. Larger frames are refused before anything is allocated for them,
+    /// their payload is left unread, so the connection should be closed.
+    fn read_frame_limited(&mut self, max_length: usize) -> Result<DataFrame, errors::SocketError>;
 }
 
 impl ReadDataFrame for TcpStream {
-    fn read_frame(&mut self) -> Result<DataFrame, errors::SocketError> {
+    fn read_frame_limited(&mut self, max_length: usize) -> Result<DataFrame, errors::SocketError> {
 
         let mut buff = [0; 2];
-        if let Err(_) = self.read(&mut buff) {
-            return Err(errors::SocketError::CannotReadPayload);
+        match self.read(&mut buff) {
+            Ok(0) => return Err(errors::SocketError::ConnectionClosed),
+            Ok(_) => {},
+            Err(_) => return Err(errors::SocketError::CannotReadPayload)
         }
         let flags = buff[0] & 0xf0;
         let opcode = (buff[0] & 0x0f).into();
@@ -157,6 +165,9 @@ impl ReadDataFrame for TcpStream {
             },
             _ => (buff[1] & 0b0111_1111) as usize
         };
+        if length > max_length {
+            return Err(errors::SocketError::FrameTooLarge);
+        }
 
         let mut mask = [0; 4];
 
@@ -167,13 +178,9 @@ impl ReadDataFrame for TcpStream {
         }
 
         let mut payload = vec![0; length];
-        match self.read(&mut payload) {
-            Ok(i) => {
-                if i != length {
-                    return Err(errors::SocketError::InvalidFrame);
-                }
-            },
-            Err(_) => return Err(errors::SocketError::CannotReadPayload)
+        // large payloads arrive in several TCP segments, so single read is not enough
+        if self.read_exact(&mut payload).is_err() {
+            return Err(errors::SocketError::InvalidFrame);
         }
 
         if is_mask {
@@ -1,6 +1,6 @@
 use std::{collections::HashMap, io::{BufRead as _, BufReader, Write as _}, net::TcpStream};
 
-use utils::{sha1, encode};
+use utils::{sha1, encode, Rand};
 
 use super::errors::SocketError;
 
@@ -29,14 +29,47 @@ pub fn handle_handshake(mut conn: &TcpStream) -> Result<(), SocketError> {
     }
 
     let key = headers.get("Sec-WebSocket-Key").unwrap();
-    let guid = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
-
-    let hash = sha1(format!("{key}{guid}").as_bytes());
-    let resp = encode(&hash);
+    let resp = accept_key(key);
 
     let response = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {resp}\r\n\r\n");
     conn.write_all(response.as_bytes()).unwrap();
     conn.flush().unwrap();
 
     Ok(())
-}
\ No newline at end of file
+}
+
+fn accept_key(key: &str) -> String {
+    let guid = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+    let hash = sha1(format!("{key}{guid}").as_bytes());
+    encode(&hash)
+}
+
+/// Client side of the handshake, sends upgrade request and validates server's accept key.
+pub fn request_handshake(mut conn: &TcpStream, host: &str, rand: &Rand) -> Result<(), SocketError> {
+    let mut nonce = rand.next_u64().to_be_bytes().to_vec();
+    nonce.extend_from_slice(&rand.next_u64().to_be_bytes());
+    let key = encode(&nonce);
+
+    let request = format!("GET / HTTP/1.1\r\nHost: {host}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n");
+    conn.write_all(request.as_bytes()).map_err(|_| SocketError::ConnectionClosed)?;
+    conn.flush().map_err(|_| SocketError::ConnectionClosed)?;
+
+    let buff = BufReader::new(&mut conn);
+    let lines: Vec<_> = buff.lines().map_while(Result::ok).take_while(|line| !line.is_empty()).collect();
+
+    match lines.first() {
+        Some(status) if status.contains(" 101 ") => {},
+        _ => return Err(SocketError::InvalidHandshake)
+    }
+
+    let expected = accept_key(&key);
+    let accepted = lines.iter().skip(1)
+        .filter_map(|line| line.split_once(": "))
+        .any(|(key, value)| key.eq_ignore_ascii_case("Sec-WebSocket-Accept") && value == expected);
+
+    if !accepted {
+        return Err(SocketError::InvalidHandshake)
+    }
+
+    Ok(())
+}
@@ -2,5 +2,9 @@ pub mod frame;
 pub mod errors;
 pub mod response;
 pub mod handshake;
+pub mod client;
+pub mod socket_client;
 pub mod server;
-pub use server::SocketServer;
\ No newline at end of file
+pub use server::SocketServer;
+pub use client::{Client, Clients};
+pub use socket_client::SocketClient;
\ No newline at end of file
@@ -77,4 +77,12 @@ impl Response {
         response.opcode = Opcode::Pong;
         response.build()
     }
+
+    pub fn close(code: u16, reason: &str) -> Self {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        let mut response = Response::builder().set_body(payload);
+        response.opcode = Opcode::ConnectionClosed;
+        response
+    }
 }
\ No newline at end of file
@@ -1,33 +1,65 @@
 use std::{
-    io::Write, net::{Shutdown, TcpListener, TcpStream}, sync::Mutex, thread
+    net::{Shutdown, TcpListener, TcpStream}, sync::{atomic::{AtomicU64, Ordering}, Arc}, thread
 };
 
 use utils::Rand;
-use crate::{errors::SocketError, frame::{DataFrame, Opcode, ReadDataFrame}, handshake::handle_handshake, response::Response};
+use crate::{client::{Client, Clients}, errors::SocketError, frame::{DataFrame, Opcode, ReadDataFrame}, handshake::handle_handshake, response::Response};
 
 
-pub struct SocketServer<T> where T: Send{
-    listener: TcpListener,
-    rand: Rand,
-    message_handler: fn(DataFrame, &mut T) -> Response,
+pub struct SocketServer<T> where T: Send + Sync {
+    address: String,
+    rand: Arc<Rand>,
+    next_client_id: AtomicU64,
+    clients: Clients,
+    message_handler: fn(DataFrame, &T, &Arc<Client>, &Clients) -> Option<Response>,
     error_handler: fn(SocketError),
-    internal_data: Mutex<T>
+    close_handler: Option<fn(&T, &Arc<Client>)>,
+    max_frame_size: usize,
+    internal_data: T
 }
 
-impl<T> SocketServer<T> where T: Send{
-    pub fn new(message_handler: fn(DataFrame, &mut T) -> Response, error_handler: fn(SocketError), internal_data: T) -> Self {
+impl<T> SocketServer<T> where T: Send + Sync {
+    pub fn new(message_handler: fn(DataFrame, &T, &Arc<Client>, &Clients) -> Option<Response>, error_handler: fn(SocketError), internal_data: T) -> Self {
         Self {
-            listener: TcpListener::bind("0.0.0.0:7878").unwrap(),
-            rand: Rand::new(),
+            address: "0.0.0.0:7878".to_string(),
+            rand: Arc::new(Rand::new()),
+            next_client_id: AtomicU64::new(1),
+            clients: Clients::default(),
             message_handler,
             error_handler,
-            internal_data: Mutex::new(internal_data)
+            close_handler: None,
+            max_frame_size: 64 * 1024 * 1024,
+            internal_data
         }
     }
 
+    /// Called once the connection is gone, whichever side closed it, to release state kept for the client.
+    pub fn set_close_handler(mut self, close_handler: fn(&T, &Arc<Client>)) -> Self {
+        self.close_handler = Some(close_handler);
+        self
+    }
+
+    /// Largest payload in bytes a client may send in one frame, bigger frames close the connection with 1009.
+    pub fn set_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    pub fn set_address(mut self, address: impl Into<String>) -> Self {
+        self.address = address.into();
+        self
+    }
+
+    /// Gives access to the shared data for work done outside of connection handlers.
+    /// Data is shared between connection threads as is, synchronizing access is up to its type.
+    pub fn data(&self) -> &T {
+        &self.internal_data
+    }
+
     pub fn run(&self) {
+        let listener = TcpListener::bind(&self.address).unwrap();
         thread::scope(|s| {
-            for stream in self.listener.incoming() {
+            for stream in listener.incoming() {
                 match stream {
                     Ok(conn) => {
                         match handle_handshake(&conn) {
@@ -38,7 +70,7 @@ impl<T> SocketServer<T> where T: Send{
                             }
                         }
                         s.spawn(move || {
-                            self.main_loop(conn);
+                            self.serve(conn);
                         });
                     },
                     Err(_e) => {
@@ -49,39 +81,69 @@ impl<T> SocketServer<T> where T: Send{
         })
     }
 
-    fn main_loop(&self, mut conn: TcpStream) {
+    fn serve(&self, conn: TcpStream) {
+        let id = self.next_client_id.fetch_add(1, Ordering::Relaxed);
+        let client = match Client::new(id, &conn, self.rand.clone()) {
+            Ok(client) => Arc::new(client),
+            Err(e) => {
+                (self.error_handler)(e);
+                return
+            }
+        };
+        self.clients.register(client.clone());
+        self.main_loop(conn, &client);
+        self.clients.unregister(id);
+        if let Some(close_handler) = self.close_handler {
+            close_handler(&self.internal_data, &client);
+        }
+    }
+
+    fn main_loop(&self, mut conn: TcpStream, client: &Arc<Client>) {
         loop {
-            let data = match conn.read_frame() {
+            let data = match conn.read_frame_limited(self.max_frame_size) {
                 Ok(data) => data,
+                Err(SocketError::ConnectionClosed) => return,
+                // rest of the frame is still in the stream, so the connection can't go on
+                Err(SocketError::FrameTooLarge) => {
+                    let _ = client.close(1009, "Frame too large");
+                    (self.error_handler)(SocketError::FrameTooLarge);
+                    return
+                },
                 Err(e) => {
                     (self.error_handler)(e);
                     continue;
                 }
             };
+            client.touch();
 
             if data.opcode == Opcode::Ping {
-                data.pong(&mut conn).unwrap();
+                if let Err(e) = client.write(&Response::pong(&data)) {
+                    (self.error_handler)(e);
+                    return
+                }
                 continue;
             }
 
             if data.opcode == Opcode::ConnectionClosed {
                 println!("Connection closed");
-                conn.shutdown(Shutdown::Both).unwrap();
+                let _ = conn.shutdown(Shutdown::Both);
                 return
             }
 
-            let response;
-            {
-                let mut lock = self.internal_data.lock().unwrap();
-                response = (self.message_handler)(data, &mut lock);
-            }
+            let response = (self.message_handler)(data, &self.internal_data, client, &self.clients);
 
-            let payload = response.set_mask(self.rand.get_mask()).build();
-            for byte in payload.iter() {
-                print!("{:x}", byte);
+            // handler may reply on its own, eg. when order of messages matters,
+            // or defer the reply to another thread, eg. when the command waits for data
+            let Some(response) = response else {
+                if !client.wait_for_reply(&conn) {
+                    return
+                }
+                continue
+            };
+            if let Err(e) = client.send(response) {
+                (self.error_handler)(e);
+                return
             }
-            conn.write_all(&payload).unwrap();
-            conn.flush().unwrap();
         }
     }
 }
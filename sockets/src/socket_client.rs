@@ -0,0 +1,49 @@
+use std::{io::Write as _, net::{Shutdown, TcpStream}};
+
+use utils::Rand;
+
+use crate::{errors::SocketError, frame::{DataFrame, Opcode, ReadDataFrame}, handshake::request_handshake, response::Response};
+
+/// Outgoing websocket connection, used when this process has to talk to another server.
+pub struct SocketClient {
+    stream: TcpStream,
+    rand: Rand
+}
+
+impl SocketClient {
+    pub fn connect(address: &str) -> Result<Self, SocketError> {
+        let stream = TcpStream::connect(address).map_err(|_| SocketError::ConnectionClosed)?;
+        let rand = Rand::new();
+        request_handshake(&stream, address, &rand)?;
+        Ok(Self {
+            stream,
+            rand
+        })
+    }
+
+    pub fn send(&mut self, response: Response) -> Result<(), SocketError> {
+        let payload = response.set_mask(self.rand.get_mask()).build();
+        self.stream.write_all(&payload).map_err(|_| SocketError::ConnectionClosed)?;
+        self.stream.flush().map_err(|_| SocketError::ConnectionClosed)
+    }
+
+    /// Reads next data frame, answering pings on the way.
+    pub fn read(&mut self) -> Result<DataFrame, SocketError> {
+        loop {
+            let frame = self.stream.read_frame()?;
+            match frame.opcode {
+                Opcode::Ping => {
+                    let mut pong = Response::builder().set_body(frame.payload);
+                    pong.opcode = Opcode::Pong;
+                    self.send(pong)?;
+                },
+                Opcode::Pong => {},
+                Opcode::ConnectionClosed => {
+                    let _ = self.stream.shutdown(Shutdown::Both);
+                    return Err(SocketError::ConnectionClosed)
+                },
+                _ => return Ok(frame)
+            }
+        }
+    }
+}